@@ -0,0 +1,132 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use rs_chip8::chip8::batch::BatchRunner;
+use rs_chip8::chip8::block_cache::Engine;
+use rs_chip8::chip8::chip8_mods::display::Damage;
+use rs_chip8::chip8::chip8_mods::memory::Layout;
+use rs_chip8::chip8::palette::Palette;
+use rs_chip8::chip8::phosphor::{Persistence, PhosphorFilter};
+use rs_chip8::chip8::rom::Rom;
+use rs_chip8::chip8::Chip8;
+
+// Arithmetic in a loop, without drawing:
+// 0x200 7001  ADD V0, 0x01
+// 0x202 8104  ADD V1, V0
+// 0x204 A300  LD I, 0x300
+// 0x206 3205  SE V2, 0x05
+// 0x208 1200  JP 0x200
+// 0x20A 1200  JP 0x200
+const ARITHMETIC_LOOP: [u8; 12] = [
+    0x70, 0x01, 0x81, 0x04, 0xA3, 0x00, 0x32, 0x05, 0x12, 0x00, 0x12, 0x00,
+];
+
+// Draws the font's 0 across the screen forever:
+// 0x200 A000  LD I, 0x000
+// 0x202 6000  LD V0, 0x00
+// 0x204 6100  LD V1, 0x00
+// 0x206 D015  DRW V0, V1, 5
+// 0x208 7008  ADD V0, 0x08
+// 0x20A 7101  ADD V1, 0x01
+// 0x20C 1206  JP 0x206
+const DRAW_LOOP: [u8; 14] = [
+    0xA0, 0x00, 0x60, 0x00, 0x61, 0x00, 0xD0, 0x15, 0x70, 0x08, 0x71, 0x01, 0x12, 0x06,
+];
+
+fn chip8(program: &[u8], engine: Engine) -> Chip8 {
+    let rom = Rom::from_bytes("bench", program);
+    let mut chip8 = Chip8::from_rom(&rom, Layout::Chip8).unwrap();
+    chip8.set_engine(engine);
+    chip8
+}
+
+const ENGINES: [(&str, Engine); 2] = [
+    ("interpreter", Engine::Interpreter),
+    ("cached", Engine::Cached),
+];
+
+fn step(c: &mut Criterion) {
+    let mut group = c.benchmark_group("step");
+    for (name, engine) in ENGINES {
+        let mut chip8 = chip8(&ARITHMETIC_LOOP, engine);
+        group.bench_function(name, |b| b.iter(|| chip8.step()));
+    }
+    group.finish();
+}
+
+fn draw(c: &mut Criterion) {
+    let mut group = c.benchmark_group("dxyn");
+    for (name, engine) in ENGINES {
+        let mut chip8 = chip8(&DRAW_LOOP, engine);
+        group.bench_function(name, |b| b.iter(|| chip8.step()));
+    }
+    group.finish();
+}
+
+fn frame(c: &mut Criterion) {
+    let mut group = c.benchmark_group("frame");
+    for instructions_per_frame in [10, 100, 1000] {
+        for (name, engine) in ENGINES {
+            let mut chip8 = chip8(&DRAW_LOOP, engine);
+            chip8.set_instructions_per_frame(instructions_per_frame);
+            group.bench_with_input(
+                BenchmarkId::new(name, instructions_per_frame),
+                &instructions_per_frame,
+                |b, _| b.iter(|| chip8.run_frame()),
+            );
+        }
+    }
+    group.finish();
+}
+
+fn save_state(c: &mut Criterion) {
+    let mut chip8 = chip8(&DRAW_LOOP, Engine::Interpreter);
+    chip8.run_frame().unwrap();
+    let state = chip8.save_state();
+    c.bench_function("save_state", |b| b.iter(|| black_box(chip8.save_state())));
+    c.bench_function("load_state", |b| {
+        b.iter(|| chip8.load_state(black_box(&state)).unwrap())
+    });
+}
+
+fn render(c: &mut Criterion) {
+    let mut chip8 = chip8(&DRAW_LOOP, Engine::Interpreter);
+    chip8.set_instructions_per_frame(100);
+    chip8.run_frame().unwrap();
+    let palette = Palette::default();
+    let mut group = c.benchmark_group("render");
+    for (name, persistence) in [
+        ("off", Persistence::Off),
+        ("blend", Persistence::Blend(4)),
+        ("decay", Persistence::Decay(4)),
+    ] {
+        let mut filter = PhosphorFilter::new(persistence);
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                // a full redraw every time, like a screen that always changes
+                let display = chip8.display();
+                filter.apply(display, Damage::all(display.width(), display.height()));
+                black_box(palette.render_rgba(filter.frame()))
+            })
+        });
+    }
+    group.finish();
+}
+
+fn batch(c: &mut Criterion) {
+    let mut group = c.benchmark_group("batch");
+    for instances in [16, 256, 4096] {
+        let mut runner = BatchRunner::new(instances, 0, || {
+            let mut chip8 = chip8(&DRAW_LOOP, Engine::Cached);
+            chip8.set_instructions_per_frame(100);
+            chip8
+        });
+        runner.reset().unwrap();
+        let actions = vec![0; instances];
+        group.bench_with_input(BenchmarkId::new("step", instances), &instances, |b, _| {
+            b.iter(|| black_box(runner.step(&actions).unwrap().1[0]))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, step, draw, frame, save_state, render, batch);
+criterion_main!(benches);
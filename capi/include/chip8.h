@@ -0,0 +1,97 @@
+/*
+ * C ABI of the rs_chip8 CHIP-8 emulator.
+ *
+ * Build the library with `cargo build --release` in capi/ and link against
+ * librs_chip8_c (shared) or librs_chip8_c.a (static).
+ *
+ * Functions returning int return CHIP8_OK or a negative CHIP8_ERROR_* code,
+ * unless documented otherwise. A machine is not thread-safe, but different
+ * machines can be used from different threads.
+ */
+#ifndef RS_CHIP8_H
+#define RS_CHIP8_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define CHIP8_ABI_VERSION 1
+
+#define CHIP8_OK 0
+/* a null pointer, an unknown quirks preset or a key above 0xF */
+#define CHIP8_ERROR_ARGUMENT (-1)
+/* no ROM is loaded, or the machine crashed */
+#define CHIP8_ERROR_NO_ROM (-2)
+/* the ROM doesn't fit into memory */
+#define CHIP8_ERROR_ROM (-3)
+/* not a save state, or one of a machine with a different memory or stack size */
+#define CHIP8_ERROR_STATE (-4)
+#define CHIP8_ERROR_BUFFER_TOO_SMALL (-5)
+/* the emulator itself failed. Load the ROM again to continue. */
+#define CHIP8_ERROR_CRASHED (-6)
+/* the program stopped the machine, e.g. with a stack overflow. It stays at the
+   instruction that faulted until a save state or the ROM is loaded again. */
+#define CHIP8_ERROR_FAULT (-7)
+
+typedef struct Machine chip8_t;
+
+/* CHIP8_ABI_VERSION of the library, to check against the header. */
+uint32_t chip8_abi_version(void);
+
+/* A machine without a program. Free it with chip8_destroy. */
+chip8_t *chip8_create(void);
+void chip8_destroy(chip8_t *machine);
+
+/* Settings are kept across chip8_load_rom. `preset` is one of "default", "vip",
+   "modern", "chip48", "schip" or "xochip". */
+int chip8_set_quirks(chip8_t *machine, const char *preset);
+/* Instructions per frame, 10 by default. 0 is CHIP8_ERROR_ARGUMENT. */
+int chip8_set_speed(chip8_t *machine, uint32_t instructions_per_frame);
+/* Makes the random numbers of CXNN repeat for the same seed. */
+int chip8_seed(chip8_t *machine, uint64_t seed);
+
+/* Resets the machine and loads a program at 0x200. */
+int chip8_load_rom(chip8_t *machine, const uint8_t *rom, size_t length);
+
+/* Executes single instructions, without ticking the timers. */
+int chip8_run_cycles(chip8_t *machine, uint32_t cycles);
+/* Runs whole frames: the instructions of a frame, then one tick of both timers.
+   Call it 60 times per second for real-time speed. */
+int chip8_run_frames(chip8_t *machine, uint32_t frames);
+
+/* Presses and releases keypad keys 0x0 to 0xF. */
+int chip8_key_down(chip8_t *machine, uint8_t key);
+int chip8_key_up(chip8_t *machine, uint8_t key);
+
+/* The display, row by row, one byte per pixel: 0 for unlit pixels, otherwise
+   the palette index. The pointer stays valid until the next call to this
+   function or chip8_destroy. NULL without a ROM. */
+const uint8_t *chip8_framebuffer(chip8_t *machine);
+/* Size of the display in pixels, 0 without a ROM. */
+uint32_t chip8_width(const chip8_t *machine);
+uint32_t chip8_height(const chip8_t *machine);
+
+/* 1 while the sound timer is above zero and the buzzer should sound, else 0. */
+int chip8_sound_active(const chip8_t *machine);
+
+/* Value of register VX, or a negative error code. */
+int chip8_register(const chip8_t *machine, uint8_t x);
+/* Address of the next instruction, 0 without a ROM. */
+uint32_t chip8_pc(const chip8_t *machine);
+
+/* Bytes chip8_save_state needs, 0 without a ROM. */
+size_t chip8_state_size(const chip8_t *machine);
+/* Writes a save state into `buffer`. `written`, if not NULL, receives its size,
+   also when the buffer is too small. */
+int chip8_save_state(chip8_t *machine, uint8_t *buffer, size_t capacity, size_t *written);
+/* Restores a save state of a machine with the same memory and stack size. */
+int chip8_load_state(chip8_t *machine, const uint8_t *state, size_t length);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* RS_CHIP8_H */
@@ -0,0 +1,348 @@
+//! The C ABI declared in `include/chip8.h`. Every function takes the handle from
+//! `chip8_create` and returns `CHIP8_OK` or a negative error code, unless it
+//! returns a value. A program that faults stops the machine with `CHIP8_ERROR_FAULT`.
+//! Panics of the emulator never cross the ABI: the machine is dropped and the call
+//! returns `CHIP8_ERROR_CRASHED`.
+
+use rs_chip8::chip8::chip8_mods::memory::Layout;
+use rs_chip8::chip8::quirks::Quirks;
+use rs_chip8::chip8::rom::Rom;
+use rs_chip8::chip8::Chip8;
+use std::ffi::{c_char, CStr};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+
+// Raised whenever a function changes in a way old callers would notice.
+const ABI_VERSION: u32 = 1;
+
+pub const CHIP8_OK: i32 = 0;
+pub const CHIP8_ERROR_ARGUMENT: i32 = -1;
+pub const CHIP8_ERROR_NO_ROM: i32 = -2;
+pub const CHIP8_ERROR_ROM: i32 = -3;
+pub const CHIP8_ERROR_STATE: i32 = -4;
+pub const CHIP8_ERROR_BUFFER_TOO_SMALL: i32 = -5;
+pub const CHIP8_ERROR_CRASHED: i32 = -6;
+pub const CHIP8_ERROR_FAULT: i32 = -7;
+
+/// What a `chip8_t *` points to.
+pub struct Machine {
+    // None until a ROM is loaded, and again after a crash
+    chip8: Option<Chip8>,
+    quirks: Quirks,
+    instructions_per_frame: u32,
+    seed: Option<u64>,
+    // keys held down, bit k for key k
+    keys: u16,
+    // one byte per pixel, refreshed by chip8_framebuffer
+    framebuffer: Vec<u8>,
+}
+
+// Runs `f` on the loaded machine and turns a panic into CHIP8_ERROR_CRASHED.
+unsafe fn with_chip8(machine: *mut Machine, f: impl FnOnce(&mut Chip8) -> i32) -> i32 {
+    let machine = match machine.as_mut() {
+        Some(machine) => machine,
+        None => return CHIP8_ERROR_ARGUMENT,
+    };
+    let chip8 = match machine.chip8.as_mut() {
+        Some(chip8) => chip8,
+        None => return CHIP8_ERROR_NO_ROM,
+    };
+    match panic::catch_unwind(AssertUnwindSafe(|| f(chip8))) {
+        Ok(status) => status,
+        Err(_) => {
+            machine.chip8 = None;
+            CHIP8_ERROR_CRASHED
+        }
+    }
+}
+
+unsafe fn chip8_ref<'a>(machine: *const Machine) -> Option<&'a Chip8> {
+    machine.as_ref().and_then(|machine| machine.chip8.as_ref())
+}
+
+#[no_mangle]
+pub extern "C" fn chip8_abi_version() -> u32 {
+    ABI_VERSION
+}
+
+#[no_mangle]
+pub extern "C" fn chip8_create() -> *mut Machine {
+    Box::into_raw(Box::new(Machine {
+        chip8: None,
+        quirks: Quirks::default(),
+        instructions_per_frame: 10,
+        seed: None,
+        keys: 0,
+        framebuffer: Vec::new(),
+    }))
+}
+
+/// # Safety
+/// `machine` has to come from `chip8_create` and must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_destroy(machine: *mut Machine) {
+    if !machine.is_null() {
+        drop(Box::from_raw(machine));
+    }
+}
+
+/// # Safety
+/// `machine` has to come from `chip8_create`, `preset` has to be a C string.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_set_quirks(machine: *mut Machine, preset: *const c_char) -> i32 {
+    let (machine, preset) = match (machine.as_mut(), preset.is_null()) {
+        (Some(machine), false) => (machine, CStr::from_ptr(preset)),
+        _ => return CHIP8_ERROR_ARGUMENT,
+    };
+    let quirks = match preset.to_str().ok().and_then(Quirks::preset) {
+        Some(quirks) => quirks,
+        None => return CHIP8_ERROR_ARGUMENT,
+    };
+    machine.quirks = quirks;
+    if let Some(chip8) = &mut machine.chip8 {
+        chip8.set_quirks(quirks);
+    }
+    CHIP8_OK
+}
+
+/// # Safety
+/// `machine` has to come from `chip8_create`.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_set_speed(
+    machine: *mut Machine,
+    instructions_per_frame: u32,
+) -> i32 {
+    let machine = match machine.as_mut() {
+        Some(machine) => machine,
+        None => return CHIP8_ERROR_ARGUMENT,
+    };
+    if instructions_per_frame == 0 {
+        return CHIP8_ERROR_ARGUMENT;
+    }
+    machine.instructions_per_frame = instructions_per_frame;
+    if let Some(chip8) = &mut machine.chip8 {
+        chip8.set_instructions_per_frame(instructions_per_frame);
+    }
+    CHIP8_OK
+}
+
+/// # Safety
+/// `machine` has to come from `chip8_create`.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_seed(machine: *mut Machine, seed: u64) -> i32 {
+    let machine = match machine.as_mut() {
+        Some(machine) => machine,
+        None => return CHIP8_ERROR_ARGUMENT,
+    };
+    machine.seed = Some(seed);
+    if let Some(chip8) = &mut machine.chip8 {
+        chip8.seed_random(seed);
+    }
+    CHIP8_OK
+}
+
+/// # Safety
+/// `machine` has to come from `chip8_create`, `rom` has to point to `length` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_load_rom(
+    machine: *mut Machine,
+    rom: *const u8,
+    length: usize,
+) -> i32 {
+    let machine = match machine.as_mut() {
+        Some(machine) if !rom.is_null() => machine,
+        _ => return CHIP8_ERROR_ARGUMENT,
+    };
+    let rom = Rom::from_bytes("rom", slice::from_raw_parts(rom, length));
+    machine.chip8 = None;
+    machine.keys = 0;
+    let created = panic::catch_unwind(AssertUnwindSafe(|| {
+        let mut chip8 = Chip8::from_rom(&rom, Layout::Chip8).ok()?;
+        chip8.set_quirks(machine.quirks);
+        chip8.set_instructions_per_frame(machine.instructions_per_frame);
+        if let Some(seed) = machine.seed {
+            chip8.seed_random(seed);
+        }
+        Some(chip8)
+    }));
+    match created {
+        Ok(Some(chip8)) => {
+            machine.chip8 = Some(chip8);
+            CHIP8_OK
+        }
+        Ok(None) => CHIP8_ERROR_ROM,
+        Err(_) => CHIP8_ERROR_CRASHED,
+    }
+}
+
+/// # Safety
+/// `machine` has to come from `chip8_create`.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_run_cycles(machine: *mut Machine, cycles: u32) -> i32 {
+    with_chip8(machine, |chip8| {
+        for _ in 0..cycles {
+            if chip8.step().is_err() {
+                return CHIP8_ERROR_FAULT;
+            }
+        }
+        CHIP8_OK
+    })
+}
+
+/// # Safety
+/// `machine` has to come from `chip8_create`.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_run_frames(machine: *mut Machine, frames: u32) -> i32 {
+    with_chip8(machine, |chip8| {
+        for _ in 0..frames {
+            if chip8.run_frame().is_err() {
+                return CHIP8_ERROR_FAULT;
+            }
+        }
+        CHIP8_OK
+    })
+}
+
+unsafe fn set_key(machine: *mut Machine, key: u8, is_down: bool) -> i32 {
+    let keys = match machine.as_mut() {
+        Some(machine) if key < 16 => {
+            if is_down {
+                machine.keys |= 1 << key;
+            } else {
+                machine.keys &= !(1 << key);
+            }
+            machine.keys
+        }
+        _ => return CHIP8_ERROR_ARGUMENT,
+    };
+    with_chip8(machine, |chip8| {
+        chip8.set_keys(keys);
+        CHIP8_OK
+    })
+}
+
+/// # Safety
+/// `machine` has to come from `chip8_create`.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_key_down(machine: *mut Machine, key: u8) -> i32 {
+    set_key(machine, key, true)
+}
+
+/// # Safety
+/// `machine` has to come from `chip8_create`.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_key_up(machine: *mut Machine, key: u8) -> i32 {
+    set_key(machine, key, false)
+}
+
+/// # Safety
+/// `machine` has to come from `chip8_create`.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_framebuffer(machine: *mut Machine) -> *const u8 {
+    let machine = match machine.as_mut() {
+        Some(machine) => machine,
+        None => return ptr::null(),
+    };
+    let display = match &machine.chip8 {
+        Some(chip8) => chip8.display(),
+        None => return ptr::null(),
+    };
+    machine.framebuffer.clear();
+    for y in 0..display.height() {
+        machine
+            .framebuffer
+            .extend((0..display.width()).map(|x| display.pixel(x, y)));
+    }
+    machine.framebuffer.as_ptr()
+}
+
+/// # Safety
+/// `machine` has to come from `chip8_create`.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_width(machine: *const Machine) -> u32 {
+    chip8_ref(machine).map_or(0, |chip8| chip8.display().width() as u32)
+}
+
+/// # Safety
+/// `machine` has to come from `chip8_create`.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_height(machine: *const Machine) -> u32 {
+    chip8_ref(machine).map_or(0, |chip8| chip8.display().height() as u32)
+}
+
+/// # Safety
+/// `machine` has to come from `chip8_create`.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_sound_active(machine: *const Machine) -> i32 {
+    chip8_ref(machine).map_or(0, |chip8| chip8.sound_active() as i32)
+}
+
+/// # Safety
+/// `machine` has to come from `chip8_create`.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_register(machine: *const Machine, x: u8) -> i32 {
+    match chip8_ref(machine) {
+        Some(chip8) if x < 16 => chip8.register(x as usize) as i32,
+        Some(_) => CHIP8_ERROR_ARGUMENT,
+        None => CHIP8_ERROR_NO_ROM,
+    }
+}
+
+/// # Safety
+/// `machine` has to come from `chip8_create`.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_pc(machine: *const Machine) -> u32 {
+    chip8_ref(machine).map_or(0, |chip8| chip8.pc())
+}
+
+/// # Safety
+/// `machine` has to come from `chip8_create`.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_state_size(machine: *const Machine) -> usize {
+    chip8_ref(machine).map_or(0, |chip8| chip8.save_state().len())
+}
+
+/// # Safety
+/// `machine` has to come from `chip8_create`, `buffer` has to point to `capacity`
+/// writable bytes and `written` has to be null or point to a `size_t`.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_save_state(
+    machine: *mut Machine,
+    buffer: *mut u8,
+    capacity: usize,
+    written: *mut usize,
+) -> i32 {
+    if buffer.is_null() {
+        return CHIP8_ERROR_ARGUMENT;
+    }
+    with_chip8(machine, |chip8| {
+        let state = chip8.save_state();
+        if let Some(written) = written.as_mut() {
+            *written = state.len();
+        }
+        if state.len() > capacity {
+            return CHIP8_ERROR_BUFFER_TOO_SMALL;
+        }
+        ptr::copy_nonoverlapping(state.as_ptr(), buffer, state.len());
+        CHIP8_OK
+    })
+}
+
+/// # Safety
+/// `machine` has to come from `chip8_create`, `state` has to point to `length` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_load_state(
+    machine: *mut Machine,
+    state: *const u8,
+    length: usize,
+) -> i32 {
+    if state.is_null() {
+        return CHIP8_ERROR_ARGUMENT;
+    }
+    let state = slice::from_raw_parts(state, length);
+    with_chip8(machine, |chip8| match chip8.load_state(state) {
+        Ok(()) => CHIP8_OK,
+        Err(_) => CHIP8_ERROR_STATE,
+    })
+}
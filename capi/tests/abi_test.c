@@ -0,0 +1,121 @@
+/*
+ * Exercises the C ABI with a small program. Run with `make test` in capi/.
+ */
+#include <stdio.h>
+#include <stdlib.h>
+
+#include "chip8.h"
+
+static int failures = 0;
+
+#define CHECK(condition)                                                  \
+    do {                                                                  \
+        if (!(condition)) {                                               \
+            fprintf(stderr, "%s:%d: %s failed\n", __FILE__, __LINE__, #condition); \
+            failures++;                                                   \
+        }                                                                 \
+    } while (0)
+
+/*
+ * 0x200 6000  LD V0, 0x00
+ * 0x202 F029  LD F, V0      font sprite of 0
+ * 0x204 D005  DRW V0, V0, 5
+ * 0x206 6110  LD V1, 0x10
+ * 0x208 F118  LD ST, V1
+ * 0x20A F20A  LD V2, K      waits for a key
+ * 0x20C 120C  JP 0x20C
+ */
+static const uint8_t PROGRAM[] = {
+    0x60, 0x00, 0xF0, 0x29, 0xD0, 0x05, 0x61, 0x10,
+    0xF1, 0x18, 0xF2, 0x0A, 0x12, 0x0C,
+};
+
+static int lit_pixels(chip8_t *machine) {
+    const uint8_t *pixels = chip8_framebuffer(machine);
+    size_t size = (size_t)chip8_width(machine) * chip8_height(machine);
+    int lit = 0;
+    for (size_t i = 0; i < size; i++) {
+        lit += pixels[i] != 0;
+    }
+    return lit;
+}
+
+int main(void) {
+    CHECK(chip8_abi_version() == CHIP8_ABI_VERSION);
+
+    chip8_t *machine = chip8_create();
+    CHECK(machine != NULL);
+    CHECK(chip8_run_frames(machine, 1) == CHIP8_ERROR_NO_ROM);
+    CHECK(chip8_framebuffer(machine) == NULL);
+    CHECK(chip8_set_quirks(machine, "vip") == CHIP8_OK);
+    CHECK(chip8_set_quirks(machine, "no such preset") == CHIP8_ERROR_ARGUMENT);
+    CHECK(chip8_set_speed(machine, 0) == CHIP8_ERROR_ARGUMENT);
+    CHECK(chip8_set_speed(machine, 10) == CHIP8_OK);
+    CHECK(chip8_seed(machine, 42) == CHIP8_OK);
+
+    CHECK(chip8_load_rom(machine, PROGRAM, sizeof PROGRAM) == CHIP8_OK);
+    CHECK(chip8_width(machine) == 64);
+    CHECK(chip8_height(machine) == 32);
+    CHECK(chip8_sound_active(machine) == 0);
+
+    /* single instructions */
+    CHECK(chip8_run_cycles(machine, 3) == CHIP8_OK);
+    CHECK(chip8_pc(machine) == 0x206);
+    /* the 0 of the font is F0 90 90 90 F0 */
+    CHECK(lit_pixels(machine) == 14);
+    const uint8_t *pixels = chip8_framebuffer(machine);
+    CHECK(pixels[0] == 1 && pixels[3] == 1 && pixels[4] == 0);
+    CHECK(pixels[64] == 1 && pixels[65] == 0 && pixels[67] == 1);
+
+    /* the rest of the frame sets the sound timer and waits for a key */
+    CHECK(chip8_run_frames(machine, 1) == CHIP8_OK);
+    CHECK(chip8_sound_active(machine) == 1);
+    CHECK(chip8_pc(machine) == 0x20A);
+
+    /* save while waiting */
+    size_t size = chip8_state_size(machine);
+    CHECK(size > 0);
+    uint8_t *state = malloc(size);
+    size_t written = 0;
+    CHECK(chip8_save_state(machine, state, size - 1, &written) == CHIP8_ERROR_BUFFER_TOO_SMALL);
+    CHECK(written == size);
+    CHECK(chip8_save_state(machine, state, size, &written) == CHIP8_OK);
+
+    /* FX0A finishes once the key is released */
+    CHECK(chip8_key_down(machine, 0x10) == CHIP8_ERROR_ARGUMENT);
+    CHECK(chip8_key_down(machine, 0x5) == CHIP8_OK);
+    CHECK(chip8_run_frames(machine, 1) == CHIP8_OK);
+    CHECK(chip8_key_up(machine, 0x5) == CHIP8_OK);
+    CHECK(chip8_run_frames(machine, 1) == CHIP8_OK);
+    CHECK(chip8_register(machine, 2) == 5);
+    CHECK(chip8_pc(machine) == 0x20C);
+
+    /* the sound timer runs out after 16 frames */
+    CHECK(chip8_run_frames(machine, 16) == CHIP8_OK);
+    CHECK(chip8_sound_active(machine) == 0);
+
+    /* back to waiting for a key, with the sound still on */
+    CHECK(chip8_load_state(machine, state, size) == CHIP8_OK);
+    CHECK(chip8_pc(machine) == 0x20A);
+    CHECK(chip8_register(machine, 2) == 0);
+    CHECK(chip8_sound_active(machine) == 1);
+    CHECK(lit_pixels(machine) == 14);
+    CHECK(chip8_load_state(machine, state, size / 2) == CHIP8_ERROR_STATE);
+    free(state);
+
+    /* a return without a call stops the machine instead of crashing it */
+    static const uint8_t RETURN[] = {0x00, 0xEE};
+    CHECK(chip8_load_rom(machine, RETURN, sizeof RETURN) == CHIP8_OK);
+    CHECK(chip8_run_frames(machine, 1) == CHIP8_ERROR_FAULT);
+    CHECK(chip8_run_cycles(machine, 1) == CHIP8_ERROR_FAULT);
+    CHECK(chip8_pc(machine) == 0x200);
+
+    chip8_destroy(machine);
+
+    if (failures > 0) {
+        fprintf(stderr, "%d checks failed\n", failures);
+        return 1;
+    }
+    printf("All checks passed\n");
+    return 0;
+}
@@ -0,0 +1,712 @@
+//! A libretro core (https://docs.libretro.com). The frontend calls the `retro_*`
+//! functions from one thread; everything the core keeps between calls lives in `CORE`.
+//!
+//! The d-pad and A/B follow the ROM's keymap from the database, the keyboard uses
+//! the same 1234/QWER/ASDF/ZXCV layout as the window. While the sound timer runs,
+//! the core plays a square wave.
+
+use rs_chip8::chip8::chip8_mods::display::{self, Damage};
+use rs_chip8::chip8::chip8_mods::keypad::{Keymap, Keypad};
+use rs_chip8::chip8::chip8_mods::memory::Layout;
+use rs_chip8::chip8::database::{Database, RomInfo};
+use rs_chip8::chip8::palette::Palette;
+use rs_chip8::chip8::phosphor::Persistence;
+use rs_chip8::chip8::quirks::Quirks;
+use rs_chip8::chip8::rom::Rom;
+use rs_chip8::chip8::Chip8;
+use std::ffi::{c_char, c_uint, c_void, CStr, CString};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+use std::sync::Mutex;
+
+const API_VERSION: c_uint = 1;
+
+const ENVIRONMENT_SHUTDOWN: c_uint = 7;
+const ENVIRONMENT_SET_PIXEL_FORMAT: c_uint = 10;
+const ENVIRONMENT_GET_VARIABLE: c_uint = 15;
+const ENVIRONMENT_SET_VARIABLES: c_uint = 16;
+const ENVIRONMENT_GET_VARIABLE_UPDATE: c_uint = 17;
+const ENVIRONMENT_GET_LOG_INTERFACE: c_uint = 27;
+const PIXEL_FORMAT_XRGB8888: c_uint = 1;
+
+const LOG_WARN: c_uint = 2;
+const LOG_ERROR: c_uint = 3;
+
+const DEVICE_JOYPAD: c_uint = 1;
+const DEVICE_KEYBOARD: c_uint = 3;
+const JOYPAD_B: c_uint = 0;
+const JOYPAD_UP: c_uint = 4;
+const JOYPAD_DOWN: c_uint = 5;
+const JOYPAD_LEFT: c_uint = 6;
+const JOYPAD_RIGHT: c_uint = 7;
+const JOYPAD_A: c_uint = 8;
+
+// the keyboard keys of the hex keypad, whose libretro key codes are their ASCII codes
+const KEYBOARD_KEYS: &str = "1234qwerasdfzxcv";
+
+// Keys for ROMs the database doesn't know: the d-pad on W/A/S/D, A on E and B on Q.
+const FALLBACK_KEYMAP: Keymap = Keymap {
+    up: Some(0x5),
+    down: Some(0x8),
+    left: Some(0x7),
+    right: Some(0x9),
+    a: Some(0x6),
+    b: Some(0x4),
+};
+
+const FRAMES_PER_SECOND: f64 = 60.0;
+const SAMPLE_RATE: u32 = 44100;
+const SAMPLES_PER_FRAME: usize = SAMPLE_RATE as usize / FRAMES_PER_SECOND as usize;
+const TONE_HZ: f32 = 440.0;
+const TONE_VOLUME: i16 = 0x1000;
+const DEFAULT_INSTRUCTIONS_PER_FRAME: u32 = 10;
+
+const OPTION_QUIRKS: &CStr = c"rs_chip8_quirks";
+const OPTION_SPEED: &CStr = c"rs_chip8_speed";
+const OPTION_MEMORY: &CStr = c"rs_chip8_memory";
+const OPTION_PALETTE: &CStr = c"rs_chip8_palette";
+const OPTION_PERSISTENCE: &CStr = c"rs_chip8_persistence";
+
+// The first value is the default. "database" takes the setting from the ROM
+// database, or the emulator's default for unknown ROMs.
+const OPTIONS: [(&CStr, &CStr); 5] = [
+    (
+        OPTION_QUIRKS,
+        c"Quirks; database|default|vip|modern|chip48|schip|xochip",
+    ),
+    (
+        OPTION_SPEED,
+        c"Instructions per frame; database|10|15|20|30|50|100|200|500|1000",
+    ),
+    (
+        OPTION_MEMORY,
+        c"Memory (applies on reset); chip8|64k|eti660",
+    ),
+    (OPTION_PALETTE, c"Palette; database|default|amber|green|lcd"),
+    (
+        OPTION_PERSISTENCE,
+        c"Pixel persistence; off|blend:2|blend:3|decay:4|decay:8",
+    ),
+];
+
+type EnvironmentFn = unsafe extern "C" fn(command: c_uint, data: *mut c_void) -> bool;
+type VideoRefreshFn =
+    unsafe extern "C" fn(data: *const c_void, width: c_uint, height: c_uint, pitch: usize);
+type AudioSampleFn = unsafe extern "C" fn(left: i16, right: i16);
+type AudioSampleBatchFn = unsafe extern "C" fn(data: *const i16, frames: usize) -> usize;
+type InputPollFn = unsafe extern "C" fn();
+type InputStateFn =
+    unsafe extern "C" fn(port: c_uint, device: c_uint, index: c_uint, id: c_uint) -> i16;
+type LogFn = unsafe extern "C" fn(level: c_uint, fmt: *const c_char, ...);
+
+#[repr(C)]
+pub struct SystemInfo {
+    library_name: *const c_char,
+    library_version: *const c_char,
+    valid_extensions: *const c_char,
+    need_fullpath: bool,
+    block_extract: bool,
+}
+
+#[repr(C)]
+pub struct GameGeometry {
+    base_width: c_uint,
+    base_height: c_uint,
+    max_width: c_uint,
+    max_height: c_uint,
+    aspect_ratio: f32,
+}
+
+#[repr(C)]
+pub struct SystemTiming {
+    fps: f64,
+    sample_rate: f64,
+}
+
+#[repr(C)]
+pub struct SystemAvInfo {
+    geometry: GameGeometry,
+    timing: SystemTiming,
+}
+
+#[repr(C)]
+pub struct GameInfo {
+    path: *const c_char,
+    data: *const c_void,
+    size: usize,
+    meta: *const c_char,
+}
+
+#[repr(C)]
+struct Variable {
+    key: *const c_char,
+    value: *const c_char,
+}
+
+#[repr(C)]
+struct LogCallback {
+    log: Option<LogFn>,
+}
+
+// Messages go to the frontend's log, or to stderr if it doesn't have one.
+fn log(log: Option<LogFn>, level: c_uint, message: &str) {
+    match (log, CString::new(message)) {
+        (Some(log), Ok(message)) => unsafe { log(level, c"%s\n".as_ptr(), message.as_ptr()) },
+        _ => eprintln!("{}", message),
+    }
+}
+
+/// Core options as read from the frontend, `None` where they follow the database.
+#[derive(Clone, Copy, PartialEq)]
+struct Options {
+    quirks: Option<Quirks>,
+    instructions_per_frame: Option<u32>,
+    layout: Layout,
+    palette: Option<Palette>,
+    persistence: Persistence,
+}
+
+impl Options {
+    unsafe fn read(environment: EnvironmentFn) -> Self {
+        let get = |key: &CStr| variable(environment, key).filter(|value| value != "database");
+        Self {
+            quirks: get(OPTION_QUIRKS).and_then(|name| Quirks::preset(&name)),
+            instructions_per_frame: get(OPTION_SPEED).and_then(|speed| speed.parse().ok()),
+            layout: get(OPTION_MEMORY)
+                .and_then(|name| Layout::from_name(&name))
+                .unwrap_or(Layout::Chip8),
+            palette: get(OPTION_PALETTE).and_then(|name| Palette::preset(&name)),
+            persistence: get(OPTION_PERSISTENCE)
+                .and_then(|name| Persistence::from_name(&name))
+                .unwrap_or(Persistence::Off),
+        }
+    }
+}
+
+unsafe fn variable(environment: EnvironmentFn, key: &CStr) -> Option<String> {
+    let mut variable = Variable {
+        key: key.as_ptr(),
+        value: ptr::null(),
+    };
+    let found = environment(
+        ENVIRONMENT_GET_VARIABLE,
+        &mut variable as *mut Variable as *mut c_void,
+    );
+    if !found || variable.value.is_null() {
+        return None;
+    }
+    Some(
+        CStr::from_ptr(variable.value)
+            .to_string_lossy()
+            .into_owned(),
+    )
+}
+
+/// The loaded game.
+struct Game {
+    rom: Rom,
+    info: Option<RomInfo>,
+    options: Options,
+    chip8: Chip8,
+    // the filtered frame in RGBA and in the frontend's XRGB8888
+    rgba: Vec<u8>,
+    pixels: Vec<u32>,
+    // position within the current period of the tone, between 0 and 1
+    tone_phase: f32,
+    samples: Vec<i16>,
+}
+
+impl Game {
+    fn new(rom: Rom, options: Options) -> Result<Self, String> {
+        let info = Database::bundled().lookup(&rom.bytes).cloned();
+        let chip8 = create(&rom, info.as_ref(), options)?;
+        Ok(Self {
+            rom,
+            info,
+            options,
+            chip8,
+            rgba: Vec::new(),
+            pixels: Vec::new(),
+            tone_phase: 0.0,
+            samples: Vec::with_capacity(SAMPLES_PER_FRAME * 2),
+        })
+    }
+
+    fn reset(&mut self) -> Result<(), String> {
+        self.chip8 = create(&self.rom, self.info.as_ref(), self.options)?;
+        self.rgba.clear();
+        Ok(())
+    }
+
+    // Settings other than the memory layout change on the running machine.
+    fn set_options(&mut self, options: Options) {
+        if options.persistence != self.options.persistence {
+            self.chip8.set_persistence(options.persistence);
+        }
+        self.options = options;
+        configure(&mut self.chip8, self.info.as_ref(), options);
+        self.rgba.clear();
+    }
+
+    fn keymap(&self) -> Keymap {
+        match &self.info {
+            Some(info) if info.keymap != Keymap::default() => info.keymap,
+            _ => FALLBACK_KEYMAP,
+        }
+    }
+
+    // Renders what changed since the last frame into `pixels`.
+    fn render(&mut self) {
+        let damage = self.chip8.take_damage();
+        let frame = self.chip8.filtered_frame();
+        let damage = if self.rgba.len() != frame.pixels() * 4 {
+            self.rgba = vec![0; frame.pixels() * 4];
+            self.pixels = vec![0; frame.pixels()];
+            Damage::all(frame.columns, frame.rows)
+        } else {
+            damage
+        };
+        self.chip8
+            .palette()
+            .render_rgba_rows(frame, damage.rows(), &mut self.rgba);
+        for row in damage.rows() {
+            let texels = &self.rgba[row * frame.columns * 4..(row + 1) * frame.columns * 4];
+            let pixels = &mut self.pixels[row * frame.columns..(row + 1) * frame.columns];
+            for (pixel, texel) in pixels.iter_mut().zip(texels.chunks_exact(4)) {
+                *pixel = (texel[0] as u32) << 16 | (texel[1] as u32) << 8 | texel[2] as u32;
+            }
+        }
+    }
+
+    // One frame of interleaved stereo samples, silent unless the sound timer runs.
+    fn mix(&mut self) {
+        self.samples.clear();
+        let is_active = self.chip8.sound_active();
+        let step = TONE_HZ / SAMPLE_RATE as f32;
+        for _ in 0..SAMPLES_PER_FRAME {
+            let sample = match (is_active, self.tone_phase < 0.5) {
+                (false, _) => 0,
+                (true, true) => TONE_VOLUME,
+                (true, false) => -TONE_VOLUME,
+            };
+            self.samples.push(sample);
+            self.samples.push(sample);
+            self.tone_phase = (self.tone_phase + step).fract();
+        }
+    }
+}
+
+fn create(rom: &Rom, info: Option<&RomInfo>, options: Options) -> Result<Chip8, String> {
+    let mut chip8 = Chip8::from_rom(rom, options.layout).map_err(|err| err.to_string())?;
+    chip8.set_persistence(options.persistence);
+    configure(&mut chip8, info, options);
+    Ok(chip8)
+}
+
+// Settings resolve like on the command line: options over database over defaults.
+fn configure(chip8: &mut Chip8, info: Option<&RomInfo>, options: Options) {
+    let quirks = options
+        .quirks
+        .or_else(|| info.and_then(|info| info.quirks))
+        .unwrap_or_default();
+    chip8.set_quirks(quirks);
+    let instructions_per_frame = options
+        .instructions_per_frame
+        .or_else(|| info.and_then(|info| info.tickrate))
+        .unwrap_or(DEFAULT_INSTRUCTIONS_PER_FRAME);
+    chip8.set_instructions_per_frame(instructions_per_frame);
+    let palette = options.palette.unwrap_or_else(|| {
+        let mut palette = Palette::default();
+        if let Some(info) = info {
+            for (color, info_color) in palette.colors.iter_mut().zip(info.colors.iter()) {
+                if let Some(info_color) = info_color {
+                    *color = *info_color;
+                }
+            }
+        }
+        palette
+    });
+    chip8.set_palette(palette);
+}
+
+struct Core {
+    environment: Option<EnvironmentFn>,
+    log: Option<LogFn>,
+    video_refresh: Option<VideoRefreshFn>,
+    audio_sample_batch: Option<AudioSampleBatchFn>,
+    input_poll: Option<InputPollFn>,
+    input_state: Option<InputStateFn>,
+    game: Option<Game>,
+}
+
+static CORE: Mutex<Core> = Mutex::new(Core {
+    environment: None,
+    log: None,
+    video_refresh: None,
+    audio_sample_batch: None,
+    input_poll: None,
+    input_state: None,
+    game: None,
+});
+
+fn core() -> std::sync::MutexGuard<'static, Core> {
+    CORE.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+#[no_mangle]
+pub extern "C" fn retro_api_version() -> c_uint {
+    API_VERSION
+}
+
+#[no_mangle]
+pub extern "C" fn retro_init() {}
+
+#[no_mangle]
+pub extern "C" fn retro_deinit() {
+    core().game = None;
+}
+
+/// # Safety
+/// `info` has to point to a `retro_system_info`.
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_info(info: *mut SystemInfo) {
+    *info = SystemInfo {
+        library_name: c"rs_chip8".as_ptr(),
+        library_version: concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const c_char,
+        valid_extensions: c"ch8|c8|sc8|xo8|rom".as_ptr(),
+        need_fullpath: false,
+        block_extract: false,
+    };
+}
+
+/// # Safety
+/// `info` has to point to a `retro_system_av_info`.
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_av_info(info: *mut SystemAvInfo) {
+    let (width, height) = match &core().game {
+        Some(game) => (game.chip8.display().width(), game.chip8.display().height()),
+        None => (display::LORES_WIDTH, display::LORES_HEIGHT),
+    };
+    *info = SystemAvInfo {
+        geometry: GameGeometry {
+            base_width: width as c_uint,
+            base_height: height as c_uint,
+            max_width: display::HIRES_WIDTH as c_uint,
+            max_height: display::HIRES_HEIGHT as c_uint,
+            aspect_ratio: 2.0,
+        },
+        timing: SystemTiming {
+            fps: FRAMES_PER_SECOND,
+            sample_rate: SAMPLE_RATE as f64,
+        },
+    };
+}
+
+/// # Safety
+/// `environment` has to be a libretro environment callback.
+#[no_mangle]
+pub unsafe extern "C" fn retro_set_environment(environment: EnvironmentFn) {
+    let mut callback = LogCallback { log: None };
+    let has_log = environment(
+        ENVIRONMENT_GET_LOG_INTERFACE,
+        &mut callback as *mut LogCallback as *mut c_void,
+    );
+    let mut core = core();
+    core.environment = Some(environment);
+    core.log = if has_log { callback.log } else { None };
+    drop(core);
+    let mut variables: Vec<Variable> = OPTIONS
+        .iter()
+        .map(|(key, value)| Variable {
+            key: key.as_ptr(),
+            value: value.as_ptr(),
+        })
+        .collect();
+    variables.push(Variable {
+        key: ptr::null(),
+        value: ptr::null(),
+    });
+    environment(
+        ENVIRONMENT_SET_VARIABLES,
+        variables.as_mut_ptr() as *mut c_void,
+    );
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_video_refresh(video_refresh: VideoRefreshFn) {
+    core().video_refresh = Some(video_refresh);
+}
+
+// Samples are sent a frame at a time through the batch callback.
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample(_audio_sample: AudioSampleFn) {}
+
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample_batch(audio_sample_batch: AudioSampleBatchFn) {
+    core().audio_sample_batch = Some(audio_sample_batch);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_poll(input_poll: InputPollFn) {
+    core().input_poll = Some(input_poll);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_state(input_state: InputStateFn) {
+    core().input_state = Some(input_state);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_controller_port_device(_port: c_uint, _device: c_uint) {}
+
+#[no_mangle]
+pub extern "C" fn retro_reset() {
+    let mut core = core();
+    // picks up a changed memory layout
+    let options = core
+        .environment
+        .map(|environment| unsafe { Options::read(environment) });
+    if let Some(game) = &mut core.game {
+        if let Some(options) = options {
+            game.options = options;
+        }
+        if let Err(err) = game.reset() {
+            log(
+                core.log,
+                LOG_ERROR,
+                &format!("Could not reset the game: {}", err),
+            );
+        }
+    }
+}
+
+/// # Safety
+/// The callbacks have to be set.
+#[no_mangle]
+pub unsafe extern "C" fn retro_run() {
+    let mut core = core();
+    let core = &mut *core;
+    let (environment, video_refresh, audio_sample_batch, input_poll, input_state) = match (
+        core.environment,
+        core.video_refresh,
+        core.audio_sample_batch,
+        core.input_poll,
+        core.input_state,
+    ) {
+        (Some(a), Some(b), Some(c), Some(d), Some(e)) => (a, b, c, d, e),
+        _ => return,
+    };
+    let game = match &mut core.game {
+        Some(game) => game,
+        None => return,
+    };
+
+    let mut is_updated = false;
+    environment(
+        ENVIRONMENT_GET_VARIABLE_UPDATE,
+        &mut is_updated as *mut bool as *mut c_void,
+    );
+    if is_updated {
+        game.set_options(Options::read(environment));
+    }
+
+    input_poll();
+    let keymap = game.keymap();
+    let mut keys = 0u16;
+    for (button, key) in [
+        (JOYPAD_UP, keymap.up),
+        (JOYPAD_DOWN, keymap.down),
+        (JOYPAD_LEFT, keymap.left),
+        (JOYPAD_RIGHT, keymap.right),
+        (JOYPAD_A, keymap.a),
+        (JOYPAD_B, keymap.b),
+    ] {
+        if let Some(key) = key {
+            if input_state(0, DEVICE_JOYPAD, 0, button) != 0 {
+                keys |= 1 << key;
+            }
+        }
+    }
+    for c in KEYBOARD_KEYS.chars() {
+        if input_state(0, DEVICE_KEYBOARD, 0, c as c_uint) != 0 {
+            keys |= 1 << Keypad::key_for_char(c).unwrap();
+        }
+    }
+
+    // a panic can't unwind into the frontend, so a crashed game ends the session
+    let was_running = game.chip8.fault().is_none();
+    let ran = panic::catch_unwind(AssertUnwindSafe(|| {
+        game.chip8.set_keys(keys);
+        let ran = game.chip8.run_frame();
+        game.render();
+        game.mix();
+        ran
+    }));
+    match ran {
+        Err(_) => {
+            log(
+                core.log,
+                LOG_ERROR,
+                "The emulator crashed, closing the game.",
+            );
+            core.game = None;
+            environment(ENVIRONMENT_SHUTDOWN, ptr::null_mut());
+            return;
+        }
+        // a faulted game keeps its last frame, so it can be reset or rewound
+        Ok(Err(fault)) if was_running => {
+            log(core.log, LOG_WARN, &format!("The game stopped: {}", fault));
+        }
+        Ok(_) => (),
+    }
+
+    let frame = game.chip8.filtered_frame();
+    video_refresh(
+        game.pixels.as_ptr() as *const c_void,
+        frame.columns as c_uint,
+        frame.rows as c_uint,
+        frame.columns * 4,
+    );
+    audio_sample_batch(game.samples.as_ptr(), SAMPLES_PER_FRAME);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_serialize_size() -> usize {
+    core()
+        .game
+        .as_ref()
+        .map_or(0, |game| game.chip8.save_state().len())
+}
+
+/// # Safety
+/// `data` has to point to `size` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn retro_serialize(data: *mut c_void, size: usize) -> bool {
+    let state = match &core().game {
+        Some(game) => game.chip8.save_state(),
+        None => return false,
+    };
+    if data.is_null() || state.len() > size {
+        return false;
+    }
+    ptr::copy_nonoverlapping(state.as_ptr(), data as *mut u8, state.len());
+    true
+}
+
+/// # Safety
+/// `data` has to point to `size` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn retro_unserialize(data: *const c_void, size: usize) -> bool {
+    if data.is_null() {
+        return false;
+    }
+    let state = slice::from_raw_parts(data as *const u8, size);
+    let mut core = core();
+    let core = &mut *core;
+    match &mut core.game {
+        Some(game) => match game.chip8.load_state(state) {
+            Ok(()) => true,
+            Err(err) => {
+                log(
+                    core.log,
+                    LOG_ERROR,
+                    &format!("Could not load the save state: {}", err),
+                );
+                false
+            }
+        },
+        None => false,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_cheat_reset() {}
+
+#[no_mangle]
+pub extern "C" fn retro_cheat_set(_index: c_uint, _enabled: bool, _code: *const c_char) {}
+
+/// # Safety
+/// `game` has to be null or point to a `retro_game_info`.
+#[no_mangle]
+pub unsafe extern "C" fn retro_load_game(game: *const GameInfo) -> bool {
+    let mut core = core();
+    let environment = match (core.environment, game.as_ref()) {
+        (Some(environment), Some(_)) => environment,
+        _ => return false,
+    };
+    let game = &*game;
+    let name = if game.path.is_null() {
+        "rom".to_string()
+    } else {
+        CStr::from_ptr(game.path).to_string_lossy().into_owned()
+    };
+    let rom = if game.data.is_null() {
+        match Rom::load(&name) {
+            Ok(rom) => rom,
+            Err(err) => {
+                log(core.log, LOG_ERROR, &err.to_string());
+                return false;
+            }
+        }
+    } else {
+        Rom::from_bytes(
+            &name,
+            slice::from_raw_parts(game.data as *const u8, game.size),
+        )
+    };
+
+    let mut format = PIXEL_FORMAT_XRGB8888;
+    if !environment(
+        ENVIRONMENT_SET_PIXEL_FORMAT,
+        &mut format as *mut c_uint as *mut c_void,
+    ) {
+        log(
+            core.log,
+            LOG_ERROR,
+            "The frontend does not support XRGB8888.",
+        );
+        return false;
+    }
+
+    match Game::new(rom, Options::read(environment)) {
+        Ok(game) => {
+            core.game = Some(game);
+            true
+        }
+        Err(err) => {
+            log(core.log, LOG_ERROR, &err.to_string());
+            false
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_load_game_special(
+    _game_type: c_uint,
+    _info: *const GameInfo,
+    _num_info: usize,
+) -> bool {
+    false
+}
+
+#[no_mangle]
+pub extern "C" fn retro_unload_game() {
+    core().game = None;
+}
+
+// NTSC, for the 60 Hz timers.
+#[no_mangle]
+pub extern "C" fn retro_get_region() -> c_uint {
+    0
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_memory_data(_id: c_uint) -> *mut c_void {
+    ptr::null_mut()
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_memory_size(_id: c_uint) -> usize {
+    0
+}
@@ -0,0 +1,337 @@
+/*
+ * A minimal libretro frontend that loads the core like RetroArch would and
+ * checks video, audio, input, core options, save states and logging without
+ * a window.
+ * Run with `make test` in libretro/, or `frontend <core.so>`.
+ */
+#include <dlfcn.h>
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+/* the parts of libretro.h the core uses */
+#define RETRO_ENVIRONMENT_SHUTDOWN 7
+#define RETRO_ENVIRONMENT_SET_PIXEL_FORMAT 10
+#define RETRO_ENVIRONMENT_GET_VARIABLE 15
+#define RETRO_ENVIRONMENT_SET_VARIABLES 16
+#define RETRO_ENVIRONMENT_GET_VARIABLE_UPDATE 17
+#define RETRO_ENVIRONMENT_GET_LOG_INTERFACE 27
+#define RETRO_LOG_ERROR 3
+#define RETRO_PIXEL_FORMAT_XRGB8888 1
+#define RETRO_DEVICE_JOYPAD 1
+#define RETRO_DEVICE_KEYBOARD 3
+#define RETRO_DEVICE_ID_JOYPAD_UP 4
+
+struct retro_system_info {
+    const char *library_name;
+    const char *library_version;
+    const char *valid_extensions;
+    bool need_fullpath;
+    bool block_extract;
+};
+
+struct retro_system_av_info {
+    struct {
+        unsigned base_width, base_height, max_width, max_height;
+        float aspect_ratio;
+    } geometry;
+    struct {
+        double fps, sample_rate;
+    } timing;
+};
+
+struct retro_game_info {
+    const char *path;
+    const void *data;
+    size_t size;
+    const char *meta;
+};
+
+struct retro_variable {
+    const char *key;
+    const char *value;
+};
+
+struct retro_log_callback {
+    void (*log)(unsigned level, const char *fmt, ...);
+};
+
+typedef bool (*environment_t)(unsigned, void *);
+typedef void (*video_refresh_t)(const void *, unsigned, unsigned, size_t);
+typedef size_t (*audio_sample_batch_t)(const int16_t *, size_t);
+typedef void (*input_poll_t)(void);
+typedef int16_t (*input_state_t)(unsigned, unsigned, unsigned, unsigned);
+
+static struct {
+    unsigned (*api_version)(void);
+    void (*init)(void);
+    void (*deinit)(void);
+    void (*get_system_info)(struct retro_system_info *);
+    void (*get_system_av_info)(struct retro_system_av_info *);
+    void (*set_environment)(environment_t);
+    void (*set_video_refresh)(video_refresh_t);
+    void (*set_audio_sample_batch)(audio_sample_batch_t);
+    void (*set_input_poll)(input_poll_t);
+    void (*set_input_state)(input_state_t);
+    bool (*load_game)(const struct retro_game_info *);
+    void (*unload_game)(void);
+    void (*run)(void);
+    void (*reset)(void);
+    size_t (*serialize_size)(void);
+    bool (*serialize)(void *, size_t);
+    bool (*unserialize)(const void *, size_t);
+} core;
+
+static int failures = 0;
+
+#define CHECK(condition)                                                           \
+    do {                                                                           \
+        if (!(condition)) {                                                        \
+            fprintf(stderr, "%s:%d: %s failed\n", __FILE__, __LINE__, #condition); \
+            failures++;                                                            \
+        }                                                                          \
+    } while (0)
+
+/* what the frontend knows */
+static int variables_set = 0;
+static const char *palette = "database";
+static bool variables_updated = false;
+static unsigned pixel_format = 0;
+static uint32_t frame[128 * 64];
+static unsigned frame_width = 0, frame_height = 0;
+static size_t audio_frames = 0, loud_samples = 0;
+static int16_t joypad_up = 0;
+static unsigned keyboard_key = 0;
+static int logged_errors = 0;
+static char last_message[256];
+
+static void log_message(unsigned level, const char *fmt, ...) {
+    va_list args;
+    va_start(args, fmt);
+    vsnprintf(last_message, sizeof last_message, fmt, args);
+    va_end(args);
+    logged_errors += level == RETRO_LOG_ERROR;
+}
+
+static bool environment(unsigned command, void *data) {
+    switch (command) {
+    case RETRO_ENVIRONMENT_SET_VARIABLES: {
+        const struct retro_variable *variable = data;
+        for (; variable->key != NULL; variable++) {
+            variables_set++;
+        }
+        return true;
+    }
+    case RETRO_ENVIRONMENT_GET_VARIABLE: {
+        struct retro_variable *variable = data;
+        if (strcmp(variable->key, "rs_chip8_palette") == 0) {
+            variable->value = palette;
+            return true;
+        }
+        /* the other options keep their first value */
+        return false;
+    }
+    case RETRO_ENVIRONMENT_GET_VARIABLE_UPDATE:
+        *(bool *)data = variables_updated;
+        variables_updated = false;
+        return true;
+    case RETRO_ENVIRONMENT_GET_LOG_INTERFACE:
+        ((struct retro_log_callback *)data)->log = log_message;
+        return true;
+    case RETRO_ENVIRONMENT_SET_PIXEL_FORMAT:
+        pixel_format = *(const unsigned *)data;
+        return pixel_format == RETRO_PIXEL_FORMAT_XRGB8888;
+    default:
+        return false;
+    }
+}
+
+static void video_refresh(const void *data, unsigned width, unsigned height, size_t pitch) {
+    frame_width = width;
+    frame_height = height;
+    for (unsigned y = 0; y < height; y++) {
+        memcpy(&frame[y * width], (const uint8_t *)data + y * pitch, width * 4);
+    }
+}
+
+static size_t audio_sample_batch(const int16_t *data, size_t frames) {
+    audio_frames += frames;
+    for (size_t i = 0; i < frames * 2; i++) {
+        loud_samples += data[i] != 0;
+    }
+    return frames;
+}
+
+static void input_poll(void) {}
+
+static int16_t input_state(unsigned port, unsigned device, unsigned index, unsigned id) {
+    (void)index;
+    if (port != 0) {
+        return 0;
+    }
+    if (device == RETRO_DEVICE_JOYPAD && id == RETRO_DEVICE_ID_JOYPAD_UP) {
+        return joypad_up;
+    }
+    if (device == RETRO_DEVICE_KEYBOARD && id == keyboard_key) {
+        return 1;
+    }
+    return 0;
+}
+
+static void run_frames(int frames) {
+    for (int i = 0; i < frames; i++) {
+        core.run();
+    }
+}
+
+static uint32_t pixel(unsigned x, unsigned y) {
+    return frame[y * frame_width + x];
+}
+
+/*
+ * 0x200 6000  LD V0, 0x00
+ * 0x202 F029  LD F, V0      font sprite of 0
+ * 0x204 D005  DRW V0, V0, 5
+ * 0x206 6110  LD V1, 0x10
+ * 0x208 F118  LD ST, V1
+ * 0x20A F20A  LD V2, K      waits for a key
+ * 0x20C F229  LD F, V2      and draws it next to the 0
+ * 0x20E 6308  LD V3, 0x08
+ * 0x210 D305  DRW V3, V0, 5
+ * 0x212 1212  JP 0x212
+ */
+static const uint8_t PROGRAM[] = {
+    0x60, 0x00, 0xF0, 0x29, 0xD0, 0x05, 0x61, 0x10, 0xF1, 0x18,
+    0xF2, 0x0A, 0xF2, 0x29, 0x63, 0x08, 0xD3, 0x05, 0x12, 0x12,
+};
+
+#define LOAD(name)                                                   \
+    do {                                                             \
+        *(void **)&core.name = dlsym(library, "retro_" #name);       \
+        if (core.name == NULL) {                                     \
+            fprintf(stderr, "The core has no retro_%s.\n", #name);   \
+            return 1;                                                \
+        }                                                            \
+    } while (0)
+
+int main(int argc, char **argv) {
+    if (argc != 2) {
+        fprintf(stderr, "Usage: %s <core.so>\n", argv[0]);
+        return 1;
+    }
+    void *library = dlopen(argv[1], RTLD_NOW);
+    if (library == NULL) {
+        fprintf(stderr, "%s\n", dlerror());
+        return 1;
+    }
+    LOAD(api_version);
+    LOAD(init);
+    LOAD(deinit);
+    LOAD(get_system_info);
+    LOAD(get_system_av_info);
+    LOAD(set_environment);
+    LOAD(set_video_refresh);
+    LOAD(set_audio_sample_batch);
+    LOAD(set_input_poll);
+    LOAD(set_input_state);
+    LOAD(load_game);
+    LOAD(unload_game);
+    LOAD(run);
+    LOAD(reset);
+    LOAD(serialize_size);
+    LOAD(serialize);
+    LOAD(unserialize);
+
+    CHECK(core.api_version() == 1);
+    struct retro_system_info info;
+    core.get_system_info(&info);
+    CHECK(strcmp(info.library_name, "rs_chip8") == 0);
+    CHECK(strstr(info.valid_extensions, "ch8") != NULL);
+
+    core.set_environment(environment);
+    CHECK(variables_set == 5);
+    core.set_video_refresh(video_refresh);
+    core.set_audio_sample_batch(audio_sample_batch);
+    core.set_input_poll(input_poll);
+    core.set_input_state(input_state);
+    core.init();
+
+    struct retro_game_info game = {"test.ch8", PROGRAM, sizeof PROGRAM, NULL};
+    CHECK(core.load_game(&game));
+    CHECK(pixel_format == RETRO_PIXEL_FORMAT_XRGB8888);
+    struct retro_system_av_info av;
+    core.get_system_av_info(&av);
+    CHECK(av.geometry.base_width == 64 && av.geometry.base_height == 32);
+    CHECK(av.timing.fps == 60.0 && av.timing.sample_rate == 44100.0);
+
+    /* the first frame draws the 0 and starts the buzzer */
+    run_frames(1);
+    CHECK(frame_width == 64 && frame_height == 32);
+    const uint32_t white = 0xFFFFFF;
+    const uint32_t background = pixel(63, 31);
+    CHECK(pixel(0, 0) == white && pixel(1, 1) == background && pixel(3, 1) == white);
+    CHECK(audio_frames == 735);
+    CHECK(loud_samples > 0);
+
+    /* the palette option changes the colours of the running game */
+    palette = "amber";
+    variables_updated = true;
+    run_frames(1);
+    CHECK(pixel(0, 0) == 0xFFB000);
+
+    size_t size = core.serialize_size();
+    CHECK(size > 0);
+    uint8_t *state = malloc(size);
+    CHECK(core.serialize(state, size));
+    CHECK(!core.serialize(state, size - 1));
+
+    /* without a database entry, up is key 5 */
+    joypad_up = 1;
+    run_frames(1);
+    joypad_up = 0;
+    run_frames(2);
+    /* 5 is F0 80 F0 10 F0 */
+    CHECK(pixel(8, 0) == 0xFFB000 && pixel(8, 3) != 0xFFB000 && pixel(11, 3) == 0xFFB000);
+
+    /* the buzzer stops with the sound timer */
+    run_frames(16);
+    loud_samples = 0;
+    run_frames(1);
+    CHECK(loud_samples == 0);
+
+    /* back to waiting for a key, then E on the keyboard, which is key 6 */
+    CHECK(core.unserialize(state, size));
+    CHECK(!core.unserialize(state, size / 2));
+    /* the core reports through the frontend's log */
+    CHECK(logged_errors == 1);
+    CHECK(strstr(last_message, "save state") != NULL);
+    run_frames(1);
+    CHECK(pixel(8, 0) != 0xFFB000);
+    keyboard_key = 'e';
+    run_frames(1);
+    keyboard_key = 0;
+    run_frames(2);
+    /* 6 is F0 80 F0 90 F0 */
+    CHECK(pixel(8, 0) == 0xFFB000 && pixel(8, 3) == 0xFFB000);
+    free(state);
+
+    /* a reset starts the program again */
+    core.reset();
+    run_frames(1);
+    CHECK(pixel(0, 0) == 0xFFB000 && pixel(8, 0) != 0xFFB000);
+
+    core.unload_game();
+    CHECK(core.serialize_size() == 0);
+    core.deinit();
+    dlclose(library);
+
+    if (failures > 0) {
+        fprintf(stderr, "%d checks failed\n", failures);
+        return 1;
+    }
+    printf("All checks passed\n");
+    return 0;
+}
@@ -0,0 +1,51 @@
+from os import PathLike
+from typing import Optional, Tuple, Union
+
+import numpy as np
+import numpy.typing as npt
+
+Rom = Union[str, PathLike, bytes]
+
+class Chip8:
+    pc: int
+    index: int
+    def __init__(
+        self,
+        rom: Rom,
+        quirks: str = "default",
+        speed: int = 10,
+        memory: str = "chip8",
+        engine: str = "interpreter",
+        seed: Optional[int] = None,
+    ) -> None: ...
+    def step(self) -> None: ...
+    def run_frame(self, frames: int = 1) -> None: ...
+    def set_keys(self, keys: int) -> None: ...
+    def framebuffer(self) -> npt.NDArray[np.uint8]: ...
+    def frame_changed(self) -> bool: ...
+    def save_state(self) -> bytes: ...
+    def load_state(self, state: bytes) -> None: ...
+    def register(self, x: int) -> int: ...
+    def peek(self, address: int) -> int: ...
+
+class Batch:
+    observation_shape: Tuple[int, int, int]
+    def __init__(
+        self,
+        rom: Rom,
+        count: int,
+        seed: int = 0,
+        spec: Optional[str] = None,
+        frames_per_step: int = 1,
+        max_frames: Optional[int] = None,
+        quirks: str = "default",
+        speed: int = 10,
+        memory: str = "chip8",
+        engine: str = "interpreter",
+        threads: Optional[int] = None,
+    ) -> None: ...
+    def __len__(self) -> int: ...
+    def reset(self) -> npt.NDArray[np.uint8]: ...
+    def step(
+        self, actions: npt.NDArray[np.uint16]
+    ) -> Tuple[npt.NDArray[np.uint8], npt.NDArray[np.float32], npt.NDArray[np.bool_]]: ...
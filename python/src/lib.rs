@@ -0,0 +1,294 @@
+// pyo3 0.22's #[pymethods] expansion converts PyResult return values into
+// themselves, which clippy flags on every method returning one.
+#![allow(clippy::useless_conversion)]
+
+use numpy::ndarray::{Array2, Array4};
+use numpy::{IntoPyArray, PyArray1, PyArray2, PyArray4, PyReadonlyArray1};
+use pyo3::exceptions::{PyRuntimeError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+use rs_chip8::chip8::batch::BatchRunner;
+use rs_chip8::chip8::block_cache::Engine;
+use rs_chip8::chip8::chip8_mods::memory::Layout;
+use rs_chip8::chip8::quirks::{self, Quirks};
+use rs_chip8::chip8::reward::RewardSpec;
+use rs_chip8::chip8::rom::Rom;
+use rs_chip8::chip8::save_state::StateError;
+use rs_chip8::chip8::Chip8;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+// What every machine is created with.
+struct Settings {
+    rom: Rom,
+    quirks: Quirks,
+    instructions_per_frame: u32,
+    layout: Layout,
+    engine: Engine,
+}
+
+impl Settings {
+    fn parse(
+        rom: &Bound<'_, PyAny>,
+        quirks: &str,
+        speed: u32,
+        memory: &str,
+        engine: &str,
+    ) -> PyResult<Self> {
+        // bytes hold the program itself, anything else is a path
+        let rom = match rom.downcast::<PyBytes>() {
+            Ok(bytes) => Rom::from_bytes("bytes", bytes.as_bytes()),
+            Err(_) => {
+                let path: PathBuf = rom.extract()?;
+                Rom::load(&path.to_string_lossy()).map_err(value_error)?
+            }
+        };
+        let settings = Self {
+            rom,
+            quirks: Quirks::preset(quirks).ok_or_else(|| {
+                PyValueError::new_err(format!(
+                    "quirks has to be one of {}",
+                    quirks::PRESET_NAMES.join(", ")
+                ))
+            })?,
+            instructions_per_frame: speed,
+            layout: Layout::from_name(memory).ok_or_else(|| {
+                PyValueError::new_err("memory has to be one of chip8, 64k, eti660")
+            })?,
+            engine: Engine::from_name(engine)
+                .ok_or_else(|| PyValueError::new_err("engine has to be interpreter or cached"))?,
+        };
+        settings.create().map_err(value_error)?;
+        Ok(settings)
+    }
+
+    fn create(&self) -> Result<Chip8, rs_chip8::chip8::rom::RomError> {
+        let mut chip8 = Chip8::from_rom(&self.rom, self.layout)?;
+        chip8.set_quirks(self.quirks);
+        chip8.set_instructions_per_frame(self.instructions_per_frame);
+        chip8.set_engine(self.engine);
+        Ok(chip8)
+    }
+}
+
+fn value_error(err: impl std::fmt::Display) -> PyErr {
+    PyValueError::new_err(err.to_string())
+}
+
+/// A CHIP-8 machine running one program.
+#[pyclass(name = "Chip8", module = "rs_chip8")]
+struct PyChip8 {
+    chip8: Chip8,
+}
+
+#[pymethods]
+impl PyChip8 {
+    /// `rom` is a path (archives work like on the command line) or the program as bytes.
+    #[new]
+    #[pyo3(signature = (rom, quirks = "default", speed = 10, memory = "chip8", engine = "interpreter", seed = None))]
+    fn new(
+        rom: &Bound<'_, PyAny>,
+        quirks: &str,
+        speed: u32,
+        memory: &str,
+        engine: &str,
+        seed: Option<u64>,
+    ) -> PyResult<Self> {
+        let settings = Settings::parse(rom, quirks, speed, memory, engine)?;
+        let mut chip8 = settings.create().map_err(value_error)?;
+        if let Some(seed) = seed {
+            chip8.seed_random(seed);
+        }
+        Ok(Self { chip8 })
+    }
+
+    /// Executes one instruction, without ticking the timers. Raises `RuntimeError`
+    /// once the program faulted, e.g. with a stack overflow.
+    fn step(&mut self) -> PyResult<()> {
+        self.chip8
+            .step()
+            .map_err(|fault| PyRuntimeError::new_err(fault.to_string()))
+    }
+
+    /// Runs whole frames: `speed` instructions and a timer tick each. Raises
+    /// `RuntimeError` once the program faulted.
+    #[pyo3(signature = (frames = 1))]
+    fn run_frame(&mut self, py: Python<'_>, frames: u32) -> PyResult<()> {
+        let chip8 = &mut self.chip8;
+        py.allow_threads(|| (0..frames).try_for_each(|_| chip8.run_frame()))
+            .map_err(|fault| PyRuntimeError::new_err(fault.to_string()))
+    }
+
+    /// Holds down the keypad keys in `keys`, bit `k` for key `k`, and releases the others.
+    fn set_keys(&mut self, keys: u16) {
+        self.chip8.set_keys(keys);
+    }
+
+    /// The display as a `height` x `width` array of palette indices, 0 for unlit pixels.
+    fn framebuffer<'py>(&self, py: Python<'py>) -> Bound<'py, PyArray2<u8>> {
+        let display = self.chip8.display();
+        let (width, height) = (display.width(), display.height());
+        Array2::from_shape_fn((height, width), |(y, x)| display.pixel(x, y)).into_pyarray_bound(py)
+    }
+
+    /// Whether the last frame looked different from the one before.
+    fn frame_changed(&self) -> bool {
+        self.chip8.frame_changed()
+    }
+
+    fn save_state<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new_bound(py, &self.chip8.save_state())
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> PyResult<()> {
+        self.chip8.load_state(state).map_err(value_error)
+    }
+
+    fn register(&self, x: usize) -> PyResult<u8> {
+        if x >= 16 {
+            return Err(PyValueError::new_err("register has to be between 0 and 15"));
+        }
+        Ok(self.chip8.register(x))
+    }
+
+    fn peek(&self, address: usize) -> PyResult<u8> {
+        if address >= self.chip8.memory_size() {
+            return Err(PyValueError::new_err("address is outside of memory"));
+        }
+        Ok(self.chip8.peek(address))
+    }
+
+    #[getter]
+    fn pc(&self) -> u32 {
+        self.chip8.pc()
+    }
+
+    #[getter]
+    fn index(&self) -> u16 {
+        self.chip8.index()
+    }
+}
+
+/// Many machines running the same program in parallel, as a vectorised environment.
+#[pyclass(name = "Batch", module = "rs_chip8")]
+struct PyBatch {
+    runner: BatchRunner,
+}
+
+#[pymethods]
+impl PyBatch {
+    /// `spec` is the path of a reward spec in TOML.
+    #[new]
+    #[pyo3(signature = (rom, count, seed = 0, spec = None, frames_per_step = 1, max_frames = None, quirks = "default", speed = 10, memory = "chip8", engine = "interpreter", threads = None))]
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        rom: &Bound<'_, PyAny>,
+        count: usize,
+        seed: u64,
+        spec: Option<&str>,
+        frames_per_step: u32,
+        max_frames: Option<u64>,
+        quirks: &str,
+        speed: u32,
+        memory: &str,
+        engine: &str,
+        threads: Option<usize>,
+    ) -> PyResult<Self> {
+        if count == 0 {
+            return Err(PyValueError::new_err("count has to be at least 1"));
+        }
+        let settings = Settings::parse(rom, quirks, speed, memory, engine)?;
+        let mut runner = BatchRunner::new(count, seed, || {
+            settings.create().expect("The ROM was already loaded once.")
+        });
+        if let Some(spec) = spec {
+            let text = std::fs::read_to_string(spec).map_err(value_error)?;
+            let spec = RewardSpec::from_toml(&text).map_err(value_error)?;
+            runner.set_task(Arc::new(spec));
+        }
+        runner.set_frames_per_step(frames_per_step);
+        runner.set_max_frames(max_frames);
+        if let Some(threads) = threads {
+            runner.set_threads(threads);
+        }
+        Ok(Self { runner })
+    }
+
+    fn __len__(&self) -> usize {
+        self.runner.len()
+    }
+
+    /// Starts a new episode everywhere and returns the observations.
+    fn reset<'py>(&mut self, py: Python<'py>) -> PyResult<Bound<'py, PyArray4<u8>>> {
+        let runner = &mut self.runner;
+        let observations = py
+            .allow_threads(|| runner.reset().map(|observations| observations.to_vec()))
+            .map_err(value_error)?;
+        Ok(self.observations(py, observations))
+    }
+
+    /// Runs one step with one action (a key mask) per machine and returns the
+    /// observations, rewards and which episodes ended.
+    #[allow(clippy::type_complexity)]
+    fn step<'py>(
+        &mut self,
+        py: Python<'py>,
+        actions: PyReadonlyArray1<'py, u16>,
+    ) -> PyResult<(
+        Bound<'py, PyArray4<u8>>,
+        Bound<'py, PyArray1<f32>>,
+        Bound<'py, PyArray1<bool>>,
+    )> {
+        let actions = actions.as_slice()?.to_vec();
+        if actions.len() != self.runner.len() {
+            return Err(PyValueError::new_err(format!(
+                "expected {} actions, got {}",
+                self.runner.len(),
+                actions.len()
+            )));
+        }
+        let runner = &mut self.runner;
+        let (observations, rewards, done) = py
+            .allow_threads(|| {
+                let (observations, rewards, done) = runner.step(&actions)?;
+                Ok::<_, StateError>((observations.to_vec(), rewards.to_vec(), done.to_vec()))
+            })
+            .map_err(value_error)?;
+        Ok((
+            self.observations(py, observations),
+            rewards.into_pyarray_bound(py),
+            done.into_pyarray_bound(py),
+        ))
+    }
+
+    /// Shape of one observation: planes, rows and bytes per row. Every byte packs
+    /// eight pixels, the leftmost in the highest bit.
+    #[getter]
+    fn observation_shape(&self) -> (usize, usize, usize) {
+        self.runner.observation_shape()
+    }
+}
+
+impl PyBatch {
+    fn observations<'py>(
+        &self,
+        py: Python<'py>,
+        observations: Vec<u8>,
+    ) -> Bound<'py, PyArray4<u8>> {
+        let (planes, rows, bytes_per_row) = self.runner.observation_shape();
+        Array4::from_shape_vec(
+            (self.runner.len(), planes, rows, bytes_per_row),
+            observations,
+        )
+        .expect("Observations have the size of their shape.")
+        .into_pyarray_bound(py)
+    }
+}
+
+#[pymodule]
+#[pyo3(name = "rs_chip8")]
+fn rs_chip8_py(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyChip8>()?;
+    m.add_class::<PyBatch>()?;
+    Ok(())
+}
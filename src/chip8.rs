@@ -1,16 +1,48 @@
-mod chip8_mods;
+pub mod batch;
+pub mod block_cache;
+pub mod chip8_mods;
+pub mod control_flow;
+pub mod coverage;
+pub mod database;
+pub mod difftest;
+pub mod fault;
+pub mod instruction;
+pub mod palette;
+pub mod phosphor;
+pub mod profile;
+pub mod profiler;
+pub mod quirks;
+pub mod recorder;
+#[cfg(feature = "window")]
+pub mod renderer;
+pub mod reward;
+pub mod rom;
+pub mod save_state;
+pub mod trace;
+#[cfg(feature = "terminal")]
+pub mod tui;
+#[cfg(feature = "window")]
+pub mod window;
 
 use chip8_mods::*;
-use ggez::graphics::{self, Color};
-use ggez::{event, timer};
-use ggez::{Context, GameResult};
 use glam::*;
-use rand::Rng;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha12Rng;
 use std::{
     thread::{self, current},
     time,
 };
 
+/// What a headless run did, for the caller to report.
+pub struct HeadlessReport {
+    pub instructions: u64,
+    pub elapsed: time::Duration,
+    /// The fault that stopped the run early, if any.
+    pub fault: Option<fault::Fault>,
+    /// `None` for the interpreter.
+    pub block_cache: Option<block_cache::CacheCounts>,
+}
+
 pub struct Chip8 {
     memory: memory::Memory,
     display: display::Display,
@@ -20,105 +52,570 @@ pub struct Chip8 {
     delay_timer: delay_timer::DelayTimer,
     sound_timer: sound_timer::SoundTimer,
     variable_registers: [variable_register::VariableRegister; 16],
+    keypad: keypad::Keypad,
+    keymap: keypad::Keymap,
+    profile: Option<profile::RomProfile>,
     current_instruction: u16,
     current_function: fn(&mut Chip8),
+    recorder: Option<recorder::Recorder>,
+    palette: palette::Palette,
+    phosphor: phosphor::PhosphorFilter,
+    // what changed in the filtered frame since a frontend last drew it
+    damage: display::Damage,
+    #[cfg(feature = "window")]
+    renderer: renderer::Renderer,
+    quirks: quirks::Quirks,
+    instructions_per_frame: u32,
+    // set by DXYN with the vblank quirk, ends the current frame early
+    waiting_for_vblank: bool,
+    // instructions executed so far
+    cycle: u64,
+    // CXNN draws from its own generator, so seeded runs repeat
+    rng: ChaCha12Rng,
+    tracer: Option<trace::Tracer>,
+    profiler: Option<profiler::Profiler>,
+    // coverage and where to write its reports
+    coverage: Option<(coverage::Coverage, String)>,
+    // decoded blocks, when running with the cached engine
+    block_cache: Option<block_cache::BlockCache>,
+    // set when the program did something the machine can't, stops it
+    fault: Option<fault::Fault>,
 }
 
+const DEFAULT_INSTRUCTIONS_PER_FRAME: u32 = 10;
+const RECORDING_SCALE: usize = 4;
+
 impl Chip8 {
-    pub fn run(path: &str) {
+    pub fn from_rom(rom: &rom::Rom, layout: memory::Layout) -> Result<Self, rom::RomError> {
+        rom.check_fits(layout)?;
         let mut slf = Self {
-            memory: memory::Memory::new(),
+            memory: memory::Memory::with_layout(layout),
             display: display::Display::new(),
-            pc: program_counter::ProgramCounter::new(),
+            pc: program_counter::ProgramCounter::new(
+                layout.program_start() as u32,
+                layout.size() as u32,
+            ),
             i: index_register::IndexRegister::new(),
-            stack: stack::Stack::new(),
+            stack: stack::Stack::schip(),
             delay_timer: delay_timer::DelayTimer::new(),
             sound_timer: sound_timer::SoundTimer::new(),
             variable_registers: [variable_register::VariableRegister::new(); 16],
+            keypad: keypad::Keypad::new(),
+            keymap: keypad::Keymap::default(),
+            profile: None,
             current_instruction: 0,
             current_function: { |this| () },
+            recorder: None,
+            palette: palette::Palette::default(),
+            phosphor: phosphor::PhosphorFilter::new(phosphor::Persistence::Off),
+            damage: display::Damage::default(),
+            #[cfg(feature = "window")]
+            renderer: renderer::Renderer::new(renderer::Scaling::Fit, false),
+            quirks: quirks::Quirks::default(),
+            instructions_per_frame: DEFAULT_INSTRUCTIONS_PER_FRAME,
+            waiting_for_vblank: false,
+            cycle: 0,
+            rng: ChaCha12Rng::from_entropy(),
+            tracer: None,
+            profiler: None,
+            coverage: None,
+            block_cache: None,
+            fault: None,
+        };
+        slf.memory.load_program(&rom.bytes);
+        Ok(slf)
+    }
+
+    pub fn set_quirks(&mut self, quirks: quirks::Quirks) {
+        self.quirks = quirks;
+    }
+
+    pub fn set_instructions_per_frame(&mut self, instructions_per_frame: u32) {
+        self.instructions_per_frame = instructions_per_frame.max(1);
+    }
+
+    // Applies what the ROM database knows about the loaded ROM.
+    pub fn apply_rom_info(&mut self, info: &database::RomInfo) {
+        if let Some(quirks) = info.quirks {
+            self.quirks = quirks;
+        }
+        if let Some(tickrate) = info.tickrate {
+            self.set_instructions_per_frame(tickrate);
+        }
+        for (color, info_color) in self.palette.colors.iter_mut().zip(info.colors.iter()) {
+            if let Some(info_color) = info_color {
+                *color = *info_color;
+            }
+        }
+        self.keymap = info.keymap;
+    }
+
+    // Applies the settings of a profile, keeping the current ones where it has none.
+    pub fn apply_profile(&mut self, settings: &profile::Profile) {
+        settings.quirks.apply(&mut self.quirks);
+        if let Some(instructions_per_frame) = settings.instructions_per_frame {
+            self.set_instructions_per_frame(instructions_per_frame);
+        }
+        if let Some(palette) = settings.palette {
+            self.palette = palette;
+        }
+        if let Some(keymap) = settings.keymap {
+            self.keymap = keymap;
+        }
+    }
+
+    // Settings changed while running are saved into this profile.
+    pub fn set_profile(&mut self, profile: profile::RomProfile) {
+        self.profile = Some(profile);
+    }
+
+    // Applies the quirks, speed and colours stored in an Octo cartridge.
+    pub fn apply_octo_options(&mut self, options: &rom::OctoOptions) {
+        options.apply_quirks(&mut self.quirks);
+        options.apply_palette(&mut self.palette);
+        if let Some(tickrate) = options.tickrate {
+            self.set_instructions_per_frame(tickrate);
+        }
+    }
+
+    pub fn set_palette(&mut self, palette: palette::Palette) {
+        self.palette = palette;
+    }
+
+    pub fn set_persistence(&mut self, persistence: phosphor::Persistence) {
+        self.phosphor = phosphor::PhosphorFilter::new(persistence);
+    }
+
+    // Makes writes into the given memory region stop the emulator.
+    pub fn protect_memory(&mut self, kind: memory::RegionKind) {
+        self.memory.set_write_protection(kind, true);
+    }
+
+    pub fn set_stack(&mut self, stack: stack::Stack) {
+        self.stack = stack;
+    }
+
+    pub fn set_engine(&mut self, engine: block_cache::Engine) {
+        self.block_cache = match engine {
+            block_cache::Engine::Interpreter => None,
+            block_cache::Engine::Cached => Some(block_cache::BlockCache::new(self.memory.size())),
+        };
+    }
+
+    pub fn display(&self) -> &display::Display {
+        &self.display
+    }
+
+    /// The display after the phosphor filter, as frontends should show it.
+    pub fn filtered_frame(&self) -> &phosphor::FilteredFrame {
+        self.phosphor.frame()
+    }
+
+    pub fn palette(&self) -> palette::Palette {
+        self.palette
+    }
+
+    pub fn keymap(&self) -> keypad::Keymap {
+        self.keymap
+    }
+
+    /// Holds down the keypad keys in `keys`, bit `k` for key `k`, and releases the others.
+    pub fn set_keys(&mut self, keys: u16) {
+        for key in 0..16 {
+            let is_held = (keys >> key) & 1 == 1;
+            if is_held != self.keypad.is_pressed(key) {
+                if is_held {
+                    self.keypad.press(key);
+                } else {
+                    self.keypad.release(key);
+                }
+            }
+        }
+    }
+
+    // The buzzer sounds while the sound timer is above zero, and a stopped machine is silent.
+    pub fn sound_active(&self) -> bool {
+        self.fault.is_none() && self.sound_timer.is_active()
+    }
+
+    pub fn register(&self, x: usize) -> u8 {
+        self.variable_registers[x & 0xF].get()
+    }
+
+    pub fn pc(&self) -> u32 {
+        self.pc.get_point_value()
+    }
+
+    pub fn index(&self) -> u16 {
+        self.i.get()
+    }
+
+    // Reads memory without calling hooks.
+    pub fn peek(&self, address: usize) -> u8 {
+        self.memory.peek(address)
+    }
+
+    pub fn memory_size(&self) -> usize {
+        self.memory.size()
+    }
+
+    // Makes the random numbers of CXNN repeat for the same seed.
+    pub fn seed_random(&mut self, seed: u64) {
+        self.rng = ChaCha12Rng::seed_from_u64(seed);
+    }
+
+    /// Whether the last frame looks different from the one before, so frontends can skip it.
+    pub fn frame_changed(&self) -> bool {
+        !self.phosphor.damage().is_empty()
+    }
+
+    /// What changed in the filtered frame since the last call, for frontends that
+    /// don't draw every emulated frame.
+    pub fn take_damage(&mut self) -> display::Damage {
+        std::mem::take(&mut self.damage)
+    }
+
+    // Runs the emulator without opening a window, e.g. to produce recordings for docs.
+    pub fn run_headless(mut self, frames: u32) -> HeadlessReport {
+        let started = time::Instant::now();
+        let mut fault = None;
+        for _ in 0..frames {
+            if let Err(err) = self.run_frame() {
+                fault = Some(err);
+                break;
+            }
+        }
+        let report = HeadlessReport {
+            instructions: self.cycle,
+            elapsed: started.elapsed(),
+            fault,
+            block_cache: self.block_cache.as_ref().map(|cache| cache.counts()),
         };
-        slf.memory.read_program(path);
-        let cb = ggez::ContextBuilder::new("CHIP8_rust", "FleeXo");
-        let (ctx, event_loop) = cb.build().unwrap();
-        graphics::set_window_title(&ctx, "CHIP8 Emulator in Rust");
-        event::run(ctx, event_loop, slf);
+        self.shut_down();
+        report
+    }
+
+    /// Runs the instructions of a frame, then ticks both timers. Stops at a fault and
+    /// returns it, also for every later frame.
+    pub fn run_frame(&mut self) -> Result<(), fault::Fault> {
+        self.run_frame_checked(|_| true);
+        self.fault.map_or(Ok(()), Err)
+    }
+
+    /// Executes one instruction, without ticking the timers or ending the frame.
+    pub fn step(&mut self) -> Result<(), fault::Fault> {
+        if self.fault.is_none() {
+            let cached = self.uses_block_cache();
+            self.step_checked(cached, &mut |_| true);
+        }
+        self.fault.map_or(Ok(()), Err)
+    }
+
+    /// Why the machine stopped, if it did.
+    pub fn fault(&self) -> Option<fault::Fault> {
+        self.fault
+    }
+
+    // Stops the machine at the instruction being executed.
+    fn raise(&mut self, kind: fault::FaultKind) {
+        let address = self.pc.previous();
+        self.pc.set_point_value(address);
+        self.fault = Some(fault::Fault {
+            address: address as u16,
+            kind,
+        });
+    }
+
+    // Memory hooks have to see every fetch, so they need the interpreter.
+    fn uses_block_cache(&self) -> bool {
+        self.block_cache.is_some() && !self.memory.has_hooks()
+    }
+
+    // Executes one instruction unless `check` returns false for the state after its fetch.
+    fn step_checked(&mut self, cached: bool, check: &mut impl FnMut(&Self) -> bool) -> bool {
+        if cached {
+            self.fetch_cached();
+        } else {
+            self.fetch();
+        }
+        if !check(self) {
+            return false;
+        }
+        if self.tracer.is_some() {
+            self.trace();
+        }
+        if let Some(profiler) = &mut self.profiler {
+            profiler.record(self.pc.previous() as u16, self.current_instruction);
+        }
+        self.cycle += 1;
+        if !cached {
+            self.decode();
+        }
+        self.execute();
+        true
+    }
+
+    // Runs a frame, asking `check` about the state after every fetch. The frame
+    // stops before executing an instruction `check` returns false for.
+    // A fault ends the frame early, and a faulted machine doesn't run frames.
+    fn run_frame_checked(&mut self, mut check: impl FnMut(&Self) -> bool) -> bool {
+        if self.fault.is_some() {
+            return false;
+        }
+        self.waiting_for_vblank = false;
+        let cached = self.uses_block_cache();
+        for _ in 0..self.instructions_per_frame {
+            if !self.step_checked(cached, &mut check) {
+                return false;
+            }
+            if self.waiting_for_vblank || self.fault.is_some() {
+                break;
+            }
+        }
+        if let Some(profiler) = &mut self.profiler {
+            profiler.end_frame();
+        }
+        self.delay_timer.tick();
+        self.sound_timer.tick();
+        let damage = self.display.take_damage();
+        self.phosphor.apply(&self.display, damage);
+        self.damage = self.damage.union(self.phosphor.damage());
+        let changed = self.frame_changed();
+        if let Some(recorder) = &mut self.recorder {
+            if let Err(err) = recorder.capture(self.phosphor.frame(), changed) {
+                println!("Could not record frame: {}", err);
+                self.recorder = None;
+            }
+        }
+        true
+    }
+
+    pub fn start_recording(&mut self, path: &str) {
+        let mut recorder = recorder::Recorder::new(path, RECORDING_SCALE, self.palette);
+        match recorder.start() {
+            Ok(()) => self.recorder = Some(recorder),
+            Err(err) => println!("Could not start recording to {}: {}", path, err),
+        }
+    }
+
+    // Profiles until the emulator stops, then prints a report and optionally writes folded stacks.
+    pub fn start_profiling(&mut self, folded_path: Option<&str>) {
+        self.profiler = Some(profiler::Profiler::new(folded_path));
+    }
+
+    // Records which bytes of `rom` run as code and which are read as data. The
+    // reports are written to `<prefix>.lst` and `<prefix>.info` when the emulator stops.
+    pub fn start_coverage(&mut self, rom: &rom::Rom, prefix: &str) {
+        let coverage = coverage::Coverage::new(
+            self.memory.size(),
+            &rom.name,
+            self.memory.layout().program_start(),
+            rom.bytes.len(),
+        );
+        self.memory.add_hook(coverage.hook());
+        self.coverage = Some((coverage, prefix.to_string()));
+    }
+
+    // Finishes recordings, traces, profiles and coverage when the emulator stops.
+    fn shut_down(&mut self) {
+        self.stop_recording();
+        self.stop_tracing();
+        if let Some(profiler) = self.profiler.take() {
+            profiler.finish();
+        }
+        if let Some((coverage, prefix)) = self.coverage.take() {
+            coverage.print_summary();
+            match coverage.write_reports(&prefix, &self.memory) {
+                Ok(()) => println!("Wrote coverage to {}.lst and {}.info", prefix, prefix),
+                Err(err) => println!("Could not write coverage to {}: {}", prefix, err),
+            }
+        }
+    }
+
+    pub fn start_tracing(&mut self, path: &str, filter: trace::TraceFilter) {
+        match trace::Tracer::new(path, filter) {
+            Ok(tracer) => self.tracer = Some(tracer),
+            Err(err) => println!("Could not start tracing to {}: {}", path, err),
+        }
+    }
+
+    fn stop_tracing(&mut self) {
+        if let Some(mut tracer) = self.tracer.take() {
+            if let Err(err) = tracer.flush() {
+                println!("Could not finish trace: {}", err);
+            }
+        }
+    }
+
+    // The fetched instruction and the state it runs on.
+    fn trace_line(&self) -> trace::TraceLine {
+        trace::TraceLine {
+            cycle: self.cycle,
+            pc: self.pc.previous() as u16,
+            opcode: self.current_instruction,
+            registers: self.variable_registers.map(|register| register.get()),
+            i: self.i.get(),
+            sp: self.stack.get_pointer(),
+            delay_timer: self.delay_timer.get_value(),
+            sound_timer: self.sound_timer.get_value(),
+            framebuffer: self.display.hash(),
+        }
+    }
+
+    // Writes the fetched instruction and the state it runs on to the trace.
+    fn trace(&mut self) {
+        let pc = self.pc.previous() as u16;
+        let instruction = instruction::Instruction::decode(self.current_instruction);
+        if !self.tracer.as_ref().unwrap().filter.matches(pc, &instruction) {
+            return;
+        }
+        let line = self.trace_line();
+        if let Err(err) = self.tracer.as_mut().unwrap().record(&line, &instruction) {
+            println!("Could not write trace: {}", err);
+            self.tracer = None;
+        }
+    }
+
+    fn stop_recording(&mut self) {
+        if let Some(mut recorder) = self.recorder.take() {
+            if let Err(err) = recorder.stop() {
+                println!("Could not finish recording {}: {}", recorder.path().display(), err);
+            }
+        }
+    }
+
+    fn stack_call(&mut self, return_address: u16) -> Result<(), stack::StackError> {
+        self.stack.call(return_address)?;
+        let index = self.stack.get_pointer() - 1;
+        if let Some(address) = self.stack.ram_address_of(index) {
+            self.memory.poke(address, (return_address >> 8) as u8);
+            self.memory.poke(address + 1, return_address as u8);
+            if let Some(block_cache) = &mut self.block_cache {
+                block_cache.invalidate(address);
+                block_cache.invalidate(address + 1);
+            }
+        }
+        Ok(())
+    }
+
+    fn stack_ret(&mut self) -> Result<u16, stack::StackError> {
+        let return_address = self.stack.ret()?;
+        // a mapped stack lives in RAM, so whatever the program left there wins
+        match self.stack.ram_address_of(self.stack.get_pointer()) {
+            Some(address) => {
+                Ok(((self.memory.peek(address) as u16) << 8) | self.memory.peek(address + 1) as u16)
+            }
+            None => Ok(return_address),
+        }
+    }
+
+    fn advance_index_after_load_store(&mut self, x: usize) {
+        if self.quirks.memory_leave_i_unchanged {
+            return;
+        }
+        let advance = if self.quirks.memory_increment_by_x { x } else { x + 1 };
+        self.i.set(self.i.get().wrapping_add(advance as u16));
+    }
+
+    // Writes for the program, returns false after stopping the machine with a fault.
+    fn write_memory(&mut self, address: usize, value: u8) -> bool {
+        if let Err(fault) = self.memory.write_byte(address, value) {
+            self.raise(fault::FaultKind::Memory(fault));
+            return false;
+        }
+        if let Some(block_cache) = &mut self.block_cache {
+            block_cache.invalidate(self.memory.wrap(address));
+        }
+        true
     }
 
     fn fetch(&mut self) {
         self.current_instruction = self.memory.get_instruction(self.pc.get_point_value());
         self.pc.set_point_value(self.pc.get_point_value() + 2);
     }
+    // Fetch and decode in one, from the block cache.
+    fn fetch_cached(&mut self) {
+        let pc = self.pc.get_point_value();
+        let block_cache = self.block_cache.as_mut().unwrap();
+        let instruction = block_cache.next(pc as u16, &self.memory);
+        self.current_instruction = instruction.opcode;
+        self.current_function = instruction.handler;
+        self.pc.set_point_value(pc + 2);
+    }
     fn decode(&mut self) {
-        let nibble = self.current_instruction & 0xF000;
-        println!("Calling instruction: {:#04x}", self.current_instruction);
-        match nibble {
+        self.current_function = Self::handler(self.current_instruction);
+    }
+
+    // The function executing an opcode. It only depends on the opcode, so
+    // engines can look it up once and cache it.
+    fn handler(opcode: u16) -> fn(&mut Chip8) {
+        // opcodes the decoder doesn't know do nothing, like in the analysis
+        if let instruction::Instruction::Unknown(_) = instruction::Instruction::decode(opcode) {
+            return |_| ();
+        }
+        match opcode & 0xF000 {
             0x0000 => {
-                self.current_function = |this| {
-                    let last_bit = ((*this).current_instruction & 0x000F);
-                    match last_bit {
+                |this| {
+                    match (*this).current_instruction {
                         // 00E0 Clear Screen
-                        0 => {
+                        0x00E0 => {
                             (*this).display.clear();
                         }
                         // 00EE Subroutines
-                        _ => {
-                            (*this).stack.set_pointer((*this).stack.get_pointer() - 1);
-                            (*this)
-                                .pc
-                                .set_point_value((*this).stack.get_value_at_pointer() as u32);
-                        }
+                        0x00EE => match (*this).stack_ret() {
+                            Ok(address) => (*this).pc.set_point_value(address as u32),
+                            Err(err) => (*this).raise(fault::FaultKind::Stack(err)),
+                        },
+                        // 0NNN runs machine code on the original hardware, which we can't
+                        _ => (),
                     }
                 }
             }
             0x1000 => {
                 // 1NNN Jump to NNN
-                self.current_function = |this| {
+                |this| {
                     let address = ((*this).current_instruction & 0x0FFF) as u32;
                     (*this).pc.set_point_value(address);
                 }
             }
             0x2000 => {
                 // 2NNN Subroutines
-                self.current_function = |this| {
-                    let NNN = ((*this).current_instruction & 0x0FFF) as u8;
+                |this| {
+                    let NNN = (*this).current_instruction & 0x0FFF;
 
-                    (*this).stack.push((*this).pc.get_point_value() as u16);
-                    (*this).stack.set_pointer((*this).stack.get_pointer() + 1);
-                    (*this).pc.set_point_value(NNN as u32);
-                };
+                    match (*this).stack_call((*this).pc.get_point_value() as u16) {
+                        Ok(()) => (*this).pc.set_point_value(NNN as u32),
+                        Err(err) => (*this).raise(fault::FaultKind::Stack(err)),
+                    }
+                }
             }
             0x3000 => {
                 // 3XNN Skip
-                self.current_function = |this| {
+                |this| {
                     let X = ((*this).current_instruction & 0x0F00) >> 8;
                     let current_vx = (*this).variable_registers[X as usize].get();
                     let NN = ((*this).current_instruction & 0x00FF) as u8;
                     if current_vx == NN {
                         //do the skip
                         (*this).pc.set_point_value((*this).pc.get_point_value() + 2);
-                    } else {
-                        println!("Did not skip, because VX != NN");
                     }
-                };
+                }
             }
             0x4000 => {
                 // 4XNN Skip
-                self.current_function = |this| {
+                |this| {
                     let X = ((*this).current_instruction & 0x0F00) >> 8;
                     let current_vx = (*this).variable_registers[X as usize].get();
                     let NN = ((*this).current_instruction & 0x00FF) as u8;
                     if current_vx != NN {
                         //do the skip
                         (*this).pc.set_point_value((*this).pc.get_point_value() + 2);
-                    } else {
-                        println!("Did not skip, because VX == NN");
                     }
-                };
+                }
             }
             0x5000 => {
                 // 5XY0 Skip
-                self.current_function = |this| {
+                |this| {
                     let X = ((*this).current_instruction & 0x0F00) >> 8;
                     let current_vx = (*this).variable_registers[X as usize].get();
                     let Y = ((*this).current_instruction & 0x00F0) >> 4;
@@ -126,22 +623,20 @@ impl Chip8 {
                     if current_vx == current_vy {
                         //do the skip
                         (*this).pc.set_point_value((*this).pc.get_point_value() + 2);
-                    } else {
-                        println!("Did not skip, because VX != VY");
                     }
-                };
+                }
             }
             0x6000 => {
                 // 6XNN // set register VX to NN
-                self.current_function = |this| {
+                |this| {
                     let NN = ((*this).current_instruction & 0x00FF) as u8;
                     let X = ((*this).current_instruction & 0x0F00) >> 8;
                     (*this).variable_registers[X as usize].set(NN);
-                };
+                }
             }
             0x7000 => {
                 // 7XNN (add value to register VX)
-                self.current_function = |this| {
+                |this| {
                     let NN = ((*this).current_instruction & 0x00FF) as u8;
                     let X = ((*this).current_instruction & 0x0F00) >> 8;
                     let current_vx = (*this).variable_registers[X as usize].get();
@@ -150,7 +645,7 @@ impl Chip8 {
                 }
             }
             0x8000 => {
-                self.current_function = |this| {
+                |this| {
                 let function_index = ((*this).current_instruction & 0x000F) as u8;
 
                 let X = (((*this).current_instruction & 0x0F00) >> 8) as usize;
@@ -165,80 +660,77 @@ impl Chip8 {
                 1 => {
                     let bin_or_val = (*this).variable_registers[X].get() | (*this).variable_registers[Y].get();
                     (*this).variable_registers[X].set(bin_or_val);
+                    if (*this).quirks.logic {
+                        (*this).variable_registers[15].set(0);
+                    }
                 }
                 // 8XY2 Binary And
                 2 => {
                     let bin_and_val = (*this).variable_registers[X].get() & (*this).variable_registers[Y].get();
                     (*this).variable_registers[X].set(bin_and_val);
+                    if (*this).quirks.logic {
+                        (*this).variable_registers[15].set(0);
+                    }
                 }
                 // 8XY3 logical XOR
                 3 => {
                     let bin_xor_val = (*this).variable_registers[X].get() ^ (*this).variable_registers[Y].get();
                     (*this).variable_registers[X].set(bin_xor_val);
+                    if (*this).quirks.logic {
+                        (*this).variable_registers[15].set(0);
+                    }
                 }
-                // 8XY4 Add
+                // 8XY4 Add, here and below VF is written last, so the flag wins over the result for X = F
                 4 => {
                     let addition_result = (*this).variable_registers[X].get() as u16 + (*this).variable_registers[Y].get() as u16;
+                    (*this).variable_registers[X].set(addition_result as u8);
                     if addition_result > 255 {
                         (*this).variable_registers[15].set(1);
-                        println!("Add resulted in an overflow.");
                     } else {
                         (*this).variable_registers[15].set(0);
-                        println!("Add did not result in an overflow.");
                     }
-                    (*this).variable_registers[X].set(addition_result as u8);
                 }
                 // 8XY5 Subtract
                 5 => {
                     let minuend = (*this).variable_registers[X].get();
                     let subtrahend = (*this).variable_registers[Y].get();
-                    
-                    if(minuend > subtrahend) {
+                    (*this).variable_registers[X].set(minuend.wrapping_sub(subtrahend));
+                    if minuend >= subtrahend {
                         (*this).variable_registers[15].set(1);
-                        println!("Subtract resulted in an overflow.");
                     } else {
                         (*this).variable_registers[15].set(0);
-                        println!("Subtract did not result in an overflow.");
                     }
-
-                    let subtract_result = minuend as i16 - subtrahend as i16;
-                    (*this).variable_registers[X].set(subtract_result as u8);
                 }
                 // 8XY6 Shift [Ambigious]
                 6 => {
-                    (*this).variable_registers[15].set((*this).variable_registers[X].get() & 1);
-                    (*this).variable_registers[X].set((*this).variable_registers[X].get() >> 1);
+                    let value = if (*this).quirks.shift { (*this).variable_registers[X].get() } else { (*this).variable_registers[Y].get() };
+                    (*this).variable_registers[X].set(value >> 1);
+                    (*this).variable_registers[15].set(value & 1);
                 }
                 // 8XY7 Subtract
                 7 => {
                     let minuend = (*this).variable_registers[Y].get();
                     let subtrahend = (*this).variable_registers[X].get();
-                    
-                    if(minuend > subtrahend) {
+                    (*this).variable_registers[X].set(minuend.wrapping_sub(subtrahend));
+                    if minuend >= subtrahend {
                         (*this).variable_registers[15].set(1);
-                        println!("Subtract resulted in an overflow.");
                     } else {
                         (*this).variable_registers[15].set(0);
-                        println!("Subtract did not result in an overflow.");
                     }
-
-                    let subtract_result = minuend as i16 - subtrahend as i16;
-                    (*this).variable_registers[X].set(subtract_result as u8);
                 }
                 // 8XYE Shift [Ambigious]
                 0xE => {
-                    (*this).variable_registers[15].set(((*this).variable_registers[X].get() & 0b10000000) >> 7);
-                    (*this).variable_registers[X].set((*this).variable_registers[X].get() << 1);
-                }
-                _ => {
-                    println!("Did not find matching function inside this OP Code.");
+                    let value = if (*this).quirks.shift { (*this).variable_registers[X].get() } else { (*this).variable_registers[Y].get() };
+                    (*this).variable_registers[X].set(value << 1);
+                    (*this).variable_registers[15].set((value & 0b10000000) >> 7);
                 }
+                _ => (),
             }
                 }
             }
             0x9000 => {
                 // 9XY0 Skip
-                self.current_function = |this| {
+                |this| {
                     let X = ((*this).current_instruction & 0x0F00) >> 8;
                     let current_vx = (*this).variable_registers[X as usize].get();
                     let Y = ((*this).current_instruction & 0x00F0) >> 4;
@@ -246,152 +738,282 @@ impl Chip8 {
                     if current_vx != current_vy {
                         //do the skip
                         (*this).pc.set_point_value((*this).pc.get_point_value() + 2);
-                    } else {
-                        println!("Did not skip, because VX == VY");
                     }
-                };
+                }
             }
             0xA000 => {
                 // ANNN (set index register I)
-                self.current_function = |this| {
+                |this| {
                     let NN = ((*this).current_instruction & 0x0FFF) as u16;
                     this.i.set(NN);
                 }
             }
             0xB000 => {
                 // BNNN Jump with offset [Ambigious]
-                self.current_function = |this| {
+                |this| {
                 let NNN = ((*this).current_instruction & 0x0FFF) as u32;
-                (*this).pc.set_point_value(((*this).variable_registers[0].get() as u32)+NNN);
+                // with the jump quirk this is BXNN, using VX as offset
+                let offset_register = if (*this).quirks.jump { (NNN >> 8) as usize } else { 0 };
+                (*this).pc.set_point_value(((*this).variable_registers[offset_register].get() as u32)+NNN);
                 }
             }
             0xC000 => {
                 // CXNN Random
-                self.current_function = |this| {
+                |this| {
                     let X = ((*this).current_instruction & 0x0F00) >> 8;
                     let NN = ((*this).current_instruction & 0x00FF) as u8;
-                    let mut rand_number = rand::thread_rng();
-                    (*this).variable_registers[X as usize].set(rand_number.gen::<u8>() & NN);
+                    let random = (*this).rng.gen::<u8>();
+                    (*this).variable_registers[X as usize].set(random & NN);
                 }
             }
             0xD000 => {
                 //DXYN display/draw
-                self.current_function = |this| {
+                |this| {
                     // get coordinates
                     let X = ((*this).current_instruction & 0x0F00) >> 8;
-                    let mut current_vx = (*this).variable_registers[X as usize].get() % 64;
+                    let current_vx = (*this).variable_registers[X as usize].get() as usize;
                     let Y = ((*this).current_instruction & 0x00F0) >> 4;
-                    let mut current_vy = (*this).variable_registers[Y as usize].get() % 32;
-
-                    // set VF to 0
-                    (*this).variable_registers[15].set(0);
-
-                    // To the stuff with N ...
-                    let N = ((*this).current_instruction & 0x000F);
-                    let I = (*this).i.get();
-                    for nth in 0..N {
-                        let nth_byte = this.memory.get_byte(I as usize, nth as usize);
-                        current_vx = (*this).variable_registers[X as usize].get() % 64;
-                        for i in 0..8 {
-                            let is_set = (nth_byte >> (7 - i) & 1);
-
-                            if is_set == 1 {
-                                if (*this)
-                                    .display
-                                    .is_pixel_on(current_vx as usize, current_vy as usize)
-                                {
-                                    (*this)
-                                        .display
-                                        .un_set_pixel(current_vx as usize, current_vy as usize);
-                                    // set VF to 1
-                                    (*this).variable_registers[15].set(1);
-                                } else {
-                                    (*this)
-                                        .display
-                                        .set_pixel(current_vx as usize, current_vy as usize);
-                                }
-                            }
-                            if (*this).display.is_right_edge(current_vx as usize) {
-                                break;
-                            }
-                            current_vx += 1;
-                        }
+                    let current_vy = (*this).variable_registers[Y as usize].get() as usize;
 
-                        current_vy += 1;
-                        if (*this).display.is_bottom_edge(current_vy as usize) {
-                            break;
-                        }
+                    // read N sprite rows starting at I
+                    let N = ((*this).current_instruction & 0x000F) as usize;
+                    let I = (*this).i.get() as usize;
+                    let mut sprite = [0u8; 15];
+                    for (nth, row) in sprite.iter_mut().take(N).enumerate() {
+                        *row = (*this).memory.get_byte(I, nth);
+                    }
+
+                    // VF is 1 if a lit pixel was turned off
+                    let wrap = (*this).quirks.wrap;
+                    let collision = (*this).display.draw_sprite(0, current_vx, current_vy, &sprite[..N], wrap);
+                    (*this).variable_registers[15].set(collision as u8);
+
+                    if (*this).quirks.vblank {
+                        (*this).waiting_for_vblank = true;
                     }
                 }
             }
             0xE000 => {
-                // EX9E Skip if key
-                // EXA1 Skip if key
+                |this| {
+                    let X = ((*this).current_instruction & 0x0F00) >> 8;
+                    let key = (*this).variable_registers[X as usize].get();
+                    let is_pressed = (*this).keypad.is_pressed(key);
+                    match (*this).current_instruction & 0x00FF {
+                        // EX9E Skip if key, EXA1 Skip if not key
+                        0x9E if is_pressed => {
+                            (*this).pc.set_point_value((*this).pc.get_point_value() + 2);
+                        }
+                        0xA1 if !is_pressed => {
+                            (*this).pc.set_point_value((*this).pc.get_point_value() + 2);
+                        }
+                        _ => (),
+                    }
+                }
             }
             0xF000 => {
-                // FX07 sets VX to the current value of the delay timer
-                // FX15 sets the delay timer to the value in VX
-                // FX18 sets the sound timer to the value in VX
-                // FX1E: Add to index
-                // FX0A: Get key
-                // FX29: Font character
-                // FX33: Binary-coded decimal conversion
-                // FX55 and FX65: Store and load memory [Ambigious]
-            }
-            _ => {
-                println!("Did not found Nibble of OPCODE.");
+                |this| {
+                    let X = (((*this).current_instruction & 0x0F00) >> 8) as usize;
+                    let current_vx = (*this).variable_registers[X].get();
+                    let I = (*this).i.get() as usize;
+                    match (*this).current_instruction & 0x00FF {
+                        // FX07 sets VX to the current value of the delay timer
+                        0x07 => {
+                            (*this).variable_registers[X].set((*this).delay_timer.get_value());
+                        }
+                        // FX15 sets the delay timer to the value in VX
+                        0x15 => {
+                            (*this).delay_timer.set_value(current_vx);
+                        }
+                        // FX18 sets the sound timer to the value in VX
+                        0x18 => {
+                            (*this).sound_timer.set_value(current_vx);
+                        }
+                        // FX1E: Add to index
+                        0x1E => {
+                            (*this).i.set((*this).i.get().wrapping_add(current_vx as u16));
+                        }
+                        // FX0A: Get key
+                        0x0A => match (*this).keypad.take_released() {
+                            Some(key) => (*this).variable_registers[X].set(key),
+                            // wait by executing this instruction again
+                            None => (*this).pc.set_point_value((*this).pc.previous()),
+                        },
+                        // FX29: Font character, the font is stored at 0x000 with 5 bytes per character
+                        0x29 => {
+                            (*this).i.set((current_vx & 0xF) as u16 * 5);
+                        }
+                        // FX33: Binary-coded decimal conversion
+                        0x33 => {
+                            let digits = [current_vx / 100, current_vx / 10 % 10, current_vx % 10];
+                            for (offset, digit) in digits.iter().enumerate() {
+                                if !(*this).write_memory(I + offset, *digit) {
+                                    return;
+                                }
+                            }
+                        }
+                        // FX55 and FX65: Store and load memory [Ambigious]
+                        0x55 => {
+                            for register in 0..=X {
+                                let value = (*this).variable_registers[register].get();
+                                if !(*this).write_memory(I + register, value) {
+                                    return;
+                                }
+                            }
+                            (*this).advance_index_after_load_store(X);
+                        }
+                        0x65 => {
+                            for register in 0..=X {
+                                let value = (*this).memory.get_byte(I, register);
+                                (*this).variable_registers[register].set(value);
+                            }
+                            (*this).advance_index_after_load_store(X);
+                        }
+                        _ => (),
+                    }
+                }
             }
+            _ => |_| (),
         }
     }
     fn execute(&mut self) {
         (self.current_function)(self);
     }
+}
 
-    fn draw_display_pixels(&mut self, ctx: &mut Context) {
-        let pixel_width = 800.0 / (self.display.get_pixels()[0].len() as f32);
-        let pixel_height = 600.0 / (self.display.get_pixels().len() as f32);
-
-        for (y_i, YArr) in self.display.get_pixels().iter().enumerate() {
-            for (x_i, pixel_val) in YArr.iter().enumerate() {
-                let rect_coords: graphics::Rect = graphics::Rect::new(
-                    x_i as f32 * pixel_width,
-                    y_i as f32 * pixel_height,
-                    pixel_width,
-                    pixel_height,
-                );
-                if *pixel_val == true {
-                    let mut color = graphics::Color::WHITE;
-
-                    let rect = graphics::Mesh::new_rectangle(
-                        ctx,
-                        graphics::DrawMode::fill(),
-                        rect_coords,
-                        color,
-                    )
-                    .unwrap();
-                    graphics::draw(ctx, &rect, graphics::DrawParam::default());
-                }
-            }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chip8(program: &[u8]) -> Chip8 {
+        let rom = rom::Rom::from_bytes("test.ch8", program);
+        Chip8::from_rom(&rom, memory::Layout::Chip8).unwrap()
+    }
+
+    #[test]
+    fn calls_and_returns() {
+        // 2206 CALL 206, 1204 JP 204, 00EE RET
+        let mut chip8 = chip8(&[0x22, 0x06, 0x00, 0x00, 0x12, 0x04, 0x00, 0xEE]);
+        chip8.step().unwrap();
+        assert_eq!(chip8.pc(), 0x206);
+        chip8.step().unwrap();
+        assert_eq!(chip8.pc(), 0x202);
+    }
+
+    #[test]
+    fn stack_overflow_faults() {
+        // 2200 calls itself until the stack is full
+        let mut chip8 = chip8(&[0x22, 0x00]);
+        for _ in 0..stack::SCHIP_DEPTH {
+            chip8.step().unwrap();
         }
+        let fault = chip8.step().unwrap_err();
+        assert_eq!(fault.kind, fault::FaultKind::Stack(stack::StackError::Overflow));
+        assert_eq!(fault.address, 0x200);
+        assert_eq!(chip8.pc(), 0x200);
+        assert_eq!(chip8.fault(), Some(fault));
+        // a faulted machine stays where it stopped
+        assert_eq!(chip8.run_frame(), Err(fault));
+        assert_eq!(chip8.pc(), 0x200);
     }
-}
 
-impl event::EventHandler<ggez::GameError> for Chip8 {
-    fn update(&mut self, _ctx: &mut Context) -> GameResult {
-        const DESIRED_FPS: u32 = 1;
-        while timer::check_update_time(_ctx, DESIRED_FPS) {
-            self.fetch();
-            self.decode();
-            self.execute();
+    #[test]
+    fn stack_underflow_faults() {
+        // 6105 LD V1, 05, 00EE RET without a call
+        let mut chip8 = chip8(&[0x61, 0x05, 0x00, 0xEE, 0x61, 0x07]);
+        let fault = chip8.run_frame().unwrap_err();
+        assert_eq!(fault.kind, fault::FaultKind::Stack(stack::StackError::Underflow));
+        assert_eq!(fault.address, 0x202);
+        assert_eq!(chip8.register(1), 0x05);
+        assert!(!chip8.sound_active());
+    }
+
+    #[test]
+    fn loading_a_state_clears_the_fault() {
+        let mut chip8 = chip8(&[0x00, 0xEE]);
+        let state = chip8.save_state();
+        assert!(chip8.run_frame().is_err());
+        chip8.load_state(&state).unwrap();
+        assert_eq!(chip8.fault(), None);
+    }
+    #[test]
+    fn protected_writes_fault() {
+        // A000 LD I, 000, F055 LD [I], V0
+        let mut chip8 = chip8(&[0xA0, 0x00, 0xF0, 0x55]);
+        chip8.protect_memory(memory::RegionKind::Interpreter);
+        let fault = chip8.run_frame().unwrap_err();
+        assert_eq!(fault.address, 0x202);
+        assert!(matches!(fault.kind, fault::FaultKind::Memory(_)));
+        assert_eq!(chip8.peek(0), 0xF0);
+        // I only moves on after a store that worked
+        assert_eq!(chip8.index(), 0);
+    }
+
+    #[test]
+    fn memory_accesses_wrap_around() {
+        // AFFF LD I, FFF, 6112 LD V1, 12 (so V0 = 0), F133 LD B, V1 at FFF, 000 and 001
+        let mut chip8 = chip8(&[0xAF, 0xFF, 0x61, 0x12, 0xF1, 0x33]);
+        for _ in 0..3 {
+            chip8.step().unwrap();
         }
-        Ok(())
+        assert_eq!([chip8.peek(0xFFF), chip8.peek(0x000), chip8.peek(0x001)], [0, 1, 8]);
     }
 
-    fn draw(&mut self, ctx: &mut Context) -> GameResult {
-        graphics::clear(ctx, [0.1, 0.2, 0.3, 1.0].into());
+    #[test]
+    fn program_counter_wraps_around() {
+        // 1FFE JP FFE, where 6107 LD V1, 07 is, then 0000 at 000
+        let mut chip8 = chip8(&[0x1F, 0xFE]);
+        chip8.memory.poke(0xFFE, 0x61);
+        chip8.memory.poke(0xFFF, 0x07);
+        chip8.step().unwrap();
+        chip8.step().unwrap();
+        assert_eq!(chip8.register(1), 0x07);
+        assert_eq!(chip8.pc(), 0x000);
+    }
 
-        self.draw_display_pixels(ctx);
-        graphics::present(ctx)?;
-        Ok(())
+    // Loads `vx` and `vy` into X and Y, runs 8XY`n` and returns VX and VF.
+    fn alu(x: u8, y: u8, n: u8, vx: u8, vy: u8) -> (u8, u8) {
+        let mut chip8 = chip8(&[0x60 | x, vx, 0x60 | y, vy, 0x80 | x, y << 4 | n]);
+        for _ in 0..3 {
+            chip8.step().unwrap();
+        }
+        (chip8.register(x as usize), chip8.register(15))
+    }
+
+    #[test]
+    fn subtract_sets_no_borrow() {
+        // 8XY5 is VX - VY
+        assert_eq!(alu(1, 2, 5, 5, 3), (2, 1));
+        assert_eq!(alu(1, 2, 5, 3, 3), (0, 1));
+        assert_eq!(alu(1, 2, 5, 3, 5), (0xFE, 0));
+        // 8XY7 is VY - VX
+        assert_eq!(alu(1, 2, 7, 3, 5), (2, 1));
+        assert_eq!(alu(1, 2, 7, 3, 3), (0, 1));
+        assert_eq!(alu(1, 2, 7, 5, 3), (0xFE, 0));
+    }
+
+    #[test]
+    fn subtract_with_vf_as_operand() {
+        // VF as Y is read before it gets the flag
+        assert_eq!(alu(1, 0xF, 5, 5, 3), (2, 1));
+        assert_eq!(alu(1, 0xF, 7, 5, 3), (0xFE, 0));
+        // VF as X ends up with the flag, not the result
+        let (_, flag) = alu(0xF, 2, 5, 5, 3);
+        assert_eq!(flag, 1);
+        let (_, flag) = alu(0xF, 2, 5, 3, 3);
+        assert_eq!(flag, 1);
+        let (_, flag) = alu(0xF, 2, 5, 3, 5);
+        assert_eq!(flag, 0);
+        let (_, flag) = alu(0xF, 2, 7, 3, 5);
+        assert_eq!(flag, 1);
+        let (_, flag) = alu(0xF, 2, 7, 5, 3);
+        assert_eq!(flag, 0);
+    }
+
+    #[test]
+    fn flags_win_over_results() {
+        // 8FY4 with a carry, 8FY6 and 8FYE shifting a 1 out of 0x81 with either quirk
+        assert_eq!(alu(0xF, 2, 4, 0xFF, 0x02).1, 1);
+        assert_eq!(alu(0xF, 2, 6, 0x81, 0x81).1, 1);
+        assert_eq!(alu(0xF, 2, 0xE, 0x81, 0x81).1, 1);
     }
 }
@@ -0,0 +1,422 @@
+use super::chip8_mods::keypad::Keypad;
+use super::save_state::StateError;
+use super::Chip8;
+use rayon::prelude::*;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::thread;
+
+/// What an environment rewards and when an episode ends, judged from the machine
+/// state. `reward::RewardSpec` reads both from a TOML description.
+pub trait Task: Send + Sync {
+    /// A score that grows as the agent does well, `frames` into the episode. The
+    /// reward of a step is how much the score changed during it.
+    fn score(&self, chip8: &Chip8, frames: u64) -> f32;
+
+    /// Checked after every frame, so a step ends early when the episode does.
+    fn is_done(&self, chip8: &Chip8) -> bool;
+}
+
+struct Instance {
+    chip8: Chip8,
+    // episodes started so far
+    episode: u64,
+    frames: u64,
+    score: f32,
+    done: bool,
+}
+
+/// Runs many independent instances of one program in lockstep, spread over threads,
+/// e.g. as a vectorised reinforcement learning environment.
+///
+/// An action is the set of keypad keys held down during a step, as a bit mask with
+/// bit `k` for key `k`. Observations are the packed framebuffers of all instances,
+/// one after the other, in the layout of `Display::rows`: plane after plane, row by
+/// row, `width / 8` bytes per row with the leftmost pixel in the highest bit.
+pub struct BatchRunner {
+    instances: Vec<Instance>,
+    // every episode starts from this save state
+    initial_state: Vec<u8>,
+    seed: u64,
+    task: Option<Arc<dyn Task>>,
+    frames_per_step: u32,
+    max_frames: Option<u64>,
+    // started once, steps run their instances on it
+    pool: rayon::ThreadPool,
+    observations: Vec<u8>,
+    rewards: Vec<f32>,
+    done: Vec<bool>,
+}
+
+impl BatchRunner {
+    /// Creates `count` instances with `create`, which has to set up the same program
+    /// and settings every time. Instance `n` draws its random numbers from a generator
+    /// seeded with `seed`, `n` and the number of the episode.
+    pub fn new(count: usize, seed: u64, mut create: impl FnMut() -> Chip8) -> Self {
+        assert!(count > 0, "A batch needs at least one instance.");
+        let instances: Vec<Instance> = (0..count)
+            .map(|_| Instance {
+                chip8: create(),
+                episode: 0,
+                frames: 0,
+                score: 0.0,
+                done: false,
+            })
+            .collect();
+        let initial_state = instances[0].chip8.save_state();
+        let threads = thread::available_parallelism()
+            .map(|threads| threads.get())
+            .unwrap_or(1);
+        let mut runner = Self {
+            instances,
+            initial_state,
+            seed,
+            task: None,
+            frames_per_step: 1,
+            max_frames: None,
+            pool: thread_pool(threads),
+            observations: Vec::new(),
+            rewards: vec![0.0; count],
+            done: vec![false; count],
+        };
+        runner.observations = vec![0; count * runner.observation_size()];
+        runner
+    }
+
+    pub fn set_task(&mut self, task: Arc<dyn Task>) {
+        self.task = Some(task);
+    }
+
+    // Frames emulated per step with the same action, like frame skipping in ALE.
+    pub fn set_frames_per_step(&mut self, frames: u32) {
+        self.frames_per_step = frames.max(1);
+    }
+
+    // Ends episodes after this many frames even if the task doesn't.
+    pub fn set_max_frames(&mut self, frames: Option<u64>) {
+        self.max_frames = frames;
+    }
+
+    pub fn set_threads(&mut self, threads: usize) {
+        self.pool = thread_pool(threads.max(1));
+    }
+
+    pub fn len(&self) -> usize {
+        self.instances.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.instances.is_empty()
+    }
+
+    /// Planes, rows and bytes per row of one observation.
+    pub fn observation_shape(&self) -> (usize, usize, usize) {
+        let display = self.instances[0].chip8.display();
+        (display.planes(), display.height(), display.width() / 8)
+    }
+
+    pub fn observation_size(&self) -> usize {
+        let (planes, rows, bytes_per_row) = self.observation_shape();
+        planes * rows * bytes_per_row
+    }
+
+    pub fn instance(&self, index: usize) -> &Chip8 {
+        &self.instances[index].chip8
+    }
+
+    /// Starts a new episode on every instance and returns the observations. Has to
+    /// be called before the first step. Fails if the instances were set up differently.
+    pub fn reset(&mut self) -> Result<&[u8], StateError> {
+        let (seed, initial_state) = (self.seed, &self.initial_state);
+        let size = self.observation_size();
+        for (index, (instance, observation)) in self
+            .instances
+            .iter_mut()
+            .zip(self.observations.chunks_exact_mut(size))
+            .enumerate()
+        {
+            reset(instance, index, seed, initial_state, self.task.as_deref())?;
+            observe(&instance.chip8, observation);
+        }
+        self.rewards.iter_mut().for_each(|reward| *reward = 0.0);
+        self.done.iter_mut().for_each(|done| *done = false);
+        Ok(&self.observations)
+    }
+
+    /// Runs one step with one action per instance and returns the observations, the
+    /// rewards and which episodes ended. An instance whose episode ended starts a new
+    /// one on its next step instead, ignoring that step's action and returning the
+    /// first observation of the new episode with a reward of 0. An instance that
+    /// panics ends its episode.
+    #[allow(clippy::type_complexity)]
+    pub fn step(&mut self, actions: &[u16]) -> Result<(&[u8], &[f32], &[bool]), StateError> {
+        assert_eq!(
+            actions.len(),
+            self.instances.len(),
+            "Expected one action per instance."
+        );
+        let size = self.observation_size();
+        let settings = StepSettings {
+            seed: self.seed,
+            initial_state: &self.initial_state,
+            task: self.task.as_deref(),
+            frames_per_step: self.frames_per_step,
+            max_frames: self.max_frames,
+        };
+        let (instances, observations, rewards, done) = (
+            &mut self.instances,
+            &mut self.observations,
+            &mut self.rewards,
+            &mut self.done,
+        );
+        self.pool.install(|| {
+            instances
+                .par_iter_mut()
+                .zip(actions.par_iter())
+                .zip(observations.par_chunks_mut(size))
+                .zip(rewards.par_iter_mut())
+                .zip(done.par_iter_mut())
+                .enumerate()
+                .try_for_each(
+                    |(index, ((((instance, action), observation), reward), done))| {
+                        (*reward, *done) = step(instance, index, *action, &settings)?;
+                        observe(&instance.chip8, observation);
+                        Ok(())
+                    },
+                )
+        })?;
+        Ok((&self.observations, &self.rewards, &self.done))
+    }
+}
+
+fn thread_pool(threads: usize) -> rayon::ThreadPool {
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()
+        .expect("Could not start the batch's threads.")
+}
+
+struct StepSettings<'a> {
+    seed: u64,
+    initial_state: &'a [u8],
+    task: Option<&'a dyn Task>,
+    frames_per_step: u32,
+    max_frames: Option<u64>,
+}
+
+fn step(
+    instance: &mut Instance,
+    index: usize,
+    action: u16,
+    settings: &StepSettings,
+) -> Result<(f32, bool), StateError> {
+    if instance.done {
+        reset(
+            instance,
+            index,
+            settings.seed,
+            settings.initial_state,
+            settings.task,
+        )?;
+        return Ok((0.0, false));
+    }
+    let result = panic::catch_unwind(AssertUnwindSafe(|| run(instance, action, settings)));
+    // the next step starts over from the initial state, whatever the panic left behind
+    Ok(result.unwrap_or_else(|_| {
+        instance.done = true;
+        (0.0, true)
+    }))
+}
+
+fn run(instance: &mut Instance, action: u16, settings: &StepSettings) -> (f32, bool) {
+    instance.chip8.set_keys(action);
+    for _ in 0..settings.frames_per_step {
+        let faulted = instance.chip8.run_frame().is_err();
+        instance.frames += 1;
+        // a faulted instance can't go on, so its episode ends
+        if faulted
+            || settings
+                .task
+                .is_some_and(|task| task.is_done(&instance.chip8))
+        {
+            instance.done = true;
+            break;
+        }
+    }
+
+    let mut reward = 0.0;
+    if let Some(task) = settings.task {
+        let score = task.score(&instance.chip8, instance.frames);
+        reward = score - instance.score;
+        instance.score = score;
+    }
+    if settings
+        .max_frames
+        .is_some_and(|max_frames| instance.frames >= max_frames)
+    {
+        instance.done = true;
+    }
+    (reward, instance.done)
+}
+
+fn reset(
+    instance: &mut Instance,
+    index: usize,
+    seed: u64,
+    initial_state: &[u8],
+    task: Option<&dyn Task>,
+) -> Result<(), StateError> {
+    instance.episode += 1;
+    let chip8 = &mut instance.chip8;
+    chip8.load_state(initial_state)?;
+    chip8.keypad = Keypad::new();
+    chip8.seed_random(
+        seed.wrapping_add(index as u64)
+            .wrapping_add(instance.episode << 32),
+    );
+    instance.frames = 0;
+    instance.score = task.map_or(0.0, |task| task.score(chip8, 0));
+    instance.done = false;
+    Ok(())
+}
+
+fn observe(chip8: &Chip8, observation: &mut [u8]) {
+    let display = chip8.display();
+    let bytes_per_row = display.width() / 8;
+    let rows = (0..display.planes()).flat_map(|plane| display.rows(plane).iter());
+    for (row, bytes) in rows.zip(observation.chunks_exact_mut(bytes_per_row)) {
+        bytes.copy_from_slice(&row.to_be_bytes()[16 - bytes_per_row..]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::chip8_mods::memory::Layout;
+    use crate::chip8::rom::Rom;
+
+    // Draws a random font character at a random place every frame:
+    // 0x200 00E0  CLS
+    // 0x202 C00F  RND V0, 0F
+    // 0x204 F029  LD F, V0
+    // 0x206 C13F  RND V1, 3F
+    // 0x208 C21F  RND V2, 1F
+    // 0x20A D125  DRW V1, V2, 5
+    // 0x20C 7301  ADD V3, 01
+    // 0x20E 1200  JP 200
+    const PROGRAM: [u8; 16] = [
+        0x00, 0xE0, 0xC0, 0x0F, 0xF0, 0x29, 0xC1, 0x3F, 0xC2, 0x1F, 0xD1, 0x25, 0x73, 0x01, 0x12,
+        0x00,
+    ];
+
+    fn chip8(layout: Layout) -> Chip8 {
+        let mut chip8 = Chip8::from_rom(&Rom::from_bytes("test.ch8", &PROGRAM), layout).unwrap();
+        chip8.set_instructions_per_frame(8);
+        chip8
+    }
+
+    // Scores the random character and ends when V3 passes `end`.
+    struct Counter {
+        end: u8,
+    }
+
+    impl Task for Counter {
+        fn score(&self, chip8: &Chip8, _frames: u64) -> f32 {
+            chip8.register(0) as f32
+        }
+
+        fn is_done(&self, chip8: &Chip8) -> bool {
+            chip8.register(3) >= self.end
+        }
+    }
+
+    // Observations and rewards of the first `steps` steps.
+    fn run(seed: u64, threads: usize, steps: usize) -> Vec<(Vec<u8>, Vec<f32>, Vec<bool>)> {
+        let mut runner = BatchRunner::new(6, seed, || chip8(Layout::Chip8));
+        runner.set_threads(threads);
+        runner.set_task(Arc::new(Counter { end: 5 }));
+        runner.reset().unwrap();
+        (0..steps)
+            .map(|step| {
+                let actions: Vec<u16> = (0..6).map(|index| 1 << ((index + step) % 16)).collect();
+                let (observations, rewards, done) = runner.step(&actions).unwrap();
+                (observations.to_vec(), rewards.to_vec(), done.to_vec())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn repeats_for_the_same_seed() {
+        let first = run(42, 1, 12);
+        assert_eq!(run(42, 1, 12), first);
+        assert_eq!(run(42, 4, 12), first);
+        assert_ne!(run(43, 4, 12), first);
+        // instances draw different numbers
+        let (observations, _, _) = &first[0];
+        let size = observations.len() / 6;
+        assert_ne!(observations[..size], observations[size..2 * size]);
+        // and every episode ends on the fifth step, then starts over
+        let done: Vec<bool> = first.iter().map(|(_, _, done)| done[0]).collect();
+        assert_eq!(
+            done,
+            [false, false, false, false, true, false, false, false, false, false, true, false]
+        );
+    }
+
+    #[test]
+    fn observes_packed_framebuffers() {
+        let mut runner = BatchRunner::new(2, 0, || chip8(Layout::Chip8));
+        assert_eq!(runner.observation_shape(), (1, 32, 8));
+        assert_eq!(runner.observation_size(), 256);
+        let observations = runner.reset().unwrap();
+        assert_eq!(observations.len(), 2 * 256);
+        assert!(observations.iter().all(|byte| *byte == 0));
+        let observations = runner.step(&[0, 0]).unwrap().0.to_vec();
+        let display = runner.instance(1).display();
+        let y = (0..32).find(|y| display.rows(0)[*y] != 0).unwrap();
+        assert_eq!(
+            observations[256 + y * 8..256 + (y + 1) * 8],
+            display.rows(0)[y].to_be_bytes()[8..]
+        );
+    }
+
+    // Panics when asked whether a step ended.
+    struct Broken;
+
+    impl Task for Broken {
+        fn score(&self, _chip8: &Chip8, _frames: u64) -> f32 {
+            0.0
+        }
+
+        fn is_done(&self, _chip8: &Chip8) -> bool {
+            panic!("broken task");
+        }
+    }
+
+    #[test]
+    fn panics_end_the_episode() {
+        let mut runner = BatchRunner::new(3, 0, || chip8(Layout::Chip8));
+        runner.set_task(Arc::new(Broken));
+        runner.reset().unwrap();
+        let (_, rewards, done) = runner.step(&[0; 3]).unwrap();
+        assert_eq!(done, [true; 3]);
+        assert_eq!(rewards, [0.0; 3]);
+        // the next step starts a new episode
+        let (_, _, done) = runner.step(&[0; 3]).unwrap();
+        assert_eq!(done, [false; 3]);
+    }
+
+    #[test]
+    fn instances_have_to_match() {
+        let mut created = 0;
+        let mut runner = BatchRunner::new(2, 0, || {
+            created += 1;
+            chip8(if created == 1 {
+                Layout::Chip8
+            } else {
+                Layout::Extended
+            })
+        });
+        assert_eq!(runner.reset(), Err(StateError::Mismatch("memory size")));
+    }
+}
@@ -0,0 +1,206 @@
+use super::chip8_mods::memory::Memory;
+use super::control_flow;
+use super::instruction::Instruction;
+use super::Chip8;
+
+// Blocks are cut after this many instructions even without a jump.
+const MAX_BLOCK_LENGTH: usize = 64;
+
+/// How the emulator gets from an address to the function executing it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Engine {
+    // fetches and decodes every instruction again
+    Interpreter,
+    // decodes basic blocks once and runs them from a cache
+    Cached,
+}
+
+impl Engine {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "interpreter" => Some(Self::Interpreter),
+            "cached" => Some(Self::Cached),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct CachedInstruction {
+    pub opcode: u16,
+    pub handler: fn(&mut Chip8),
+}
+
+/// How often the cache was used, for reports.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CacheCounts {
+    pub hits: u64,
+    pub misses: u64,
+    pub invalidations: u64,
+}
+
+/// Decoded instructions indexed by their address. A miss decodes the whole basic
+/// block starting there, and a write throws away the instructions it overlaps.
+pub struct BlockCache {
+    instructions: Vec<Option<CachedInstruction>>,
+    pub hits: u64,
+    pub misses: u64,
+    pub invalidations: u64,
+}
+
+impl BlockCache {
+    pub fn new(memory_size: usize) -> Self {
+        Self {
+            instructions: vec![None; memory_size],
+            hits: 0,
+            misses: 0,
+            invalidations: 0,
+        }
+    }
+
+    pub fn counts(&self) -> CacheCounts {
+        CacheCounts {
+            hits: self.hits,
+            misses: self.misses,
+            invalidations: self.invalidations,
+        }
+    }
+
+    /// The instruction at `pc`, decoding the block starting there if it isn't cached.
+    pub fn next(&mut self, pc: u16, memory: &Memory) -> CachedInstruction {
+        if let Some(instruction) = self.instructions[pc as usize] {
+            self.hits += 1;
+            return instruction;
+        }
+        self.misses += 1;
+        self.decode_block(pc as usize, memory);
+        self.instructions[pc as usize].unwrap()
+    }
+
+    // Called for every write into memory.
+    pub fn invalidate(&mut self, address: usize) {
+        let size = self.instructions.len();
+        // the instruction starting at the address and the one starting a byte before
+        for start in [address % size, (address + size - 1) % size] {
+            if self.instructions[start].take().is_some() {
+                self.invalidations += 1;
+            }
+        }
+    }
+
+    // Memory wraps around, so a block ends at a jump, after MAX_BLOCK_LENGTH
+    // instructions or at the end of memory, whichever comes first.
+    fn decode_block(&mut self, start: usize, memory: &Memory) {
+        let mut address = start;
+        for _ in 0..MAX_BLOCK_LENGTH {
+            let opcode = ((memory.peek(address) as u16) << 8) | memory.peek(address + 1) as u16;
+            self.instructions[address] = Some(CachedInstruction {
+                opcode,
+                handler: Chip8::handler(opcode),
+            });
+            address += 2;
+            if control_flow::ends_block(&Instruction::decode(opcode)) || address >= memory.size() {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::chip8_mods::memory::Layout;
+    use crate::chip8::rom::Rom;
+
+    // Draws random font characters while rewriting the ADD at 0x21C, then jumps
+    // through the end of memory:
+    // 0x200 6000  LD V0, 00
+    // 0x202 A21D  LD I, 21D
+    // 0x204 F055  LD [I], V0      the 00 of ADD V1, 00 becomes V0
+    // 0x206 221C  CALL 21C
+    // 0x208 7001  ADD V0, 01
+    // 0x20A C30F  RND V3, 0F
+    // 0x20C F329  LD F, V3
+    // 0x20E D125  DRW V1, V2, 5
+    // 0x210 7205  ADD V2, 05
+    // 0x212 3020  SE V0, 20
+    // 0x214 1202  JP 202
+    // 0x216 1FFE  JP FFE          FFE holds 6A0A LD VA, 0A, then 000 holds 1218
+    // 0x218 1218  JP 218
+    // 0x21A 0000
+    // 0x21C 7100  ADD V1, 00
+    // 0x21E 00EE  RET
+    const PROGRAM: [u8; 32] = [
+        0x60, 0x00, 0xA2, 0x1D, 0xF0, 0x55, 0x22, 0x1C, 0x70, 0x01, 0xC3, 0x0F, 0xF3, 0x29, 0xD1,
+        0x25, 0x72, 0x05, 0x30, 0x20, 0x12, 0x02, 0x1F, 0xFE, 0x12, 0x18, 0x00, 0x00, 0x71, 0x00,
+        0x00, 0xEE,
+    ];
+
+    fn run(engine: Engine) -> Chip8 {
+        let rom = Rom::from_bytes("test.ch8", &PROGRAM);
+        let mut chip8 = Chip8::from_rom(&rom, Layout::Chip8).unwrap();
+        chip8.set_engine(engine);
+        chip8.seed_random(7);
+        for (address, byte) in [(0xFFE, 0x6A), (0xFFF, 0x0A), (0x000, 0x12), (0x001, 0x18)] {
+            chip8.memory.poke(address, byte);
+        }
+        for _ in 0..60 {
+            chip8.run_frame().unwrap();
+        }
+        chip8
+    }
+
+    #[test]
+    fn engines_agree() {
+        let interpreter = run(Engine::Interpreter);
+        let cached = run(Engine::Cached);
+        for x in 0..16 {
+            assert_eq!(interpreter.register(x), cached.register(x), "V{:X}", x);
+        }
+        assert_eq!(interpreter.index(), cached.index());
+        assert_eq!(interpreter.pc(), cached.pc());
+        assert_eq!(interpreter.display().hash(), cached.display().hash());
+        // the program got to the end, with its code rewritten along the way
+        assert_eq!(cached.pc(), 0x218);
+        assert_eq!(cached.register(0xA), 0x0A);
+        assert_eq!(cached.peek(0x21D), 0x1F);
+        let cache = cached.block_cache.as_ref().unwrap();
+        assert!(cache.invalidations >= 0x1F);
+        assert!(cache.hits > cache.misses);
+    }
+
+    #[test]
+    fn writes_throw_away_overlapping_instructions() {
+        let mut memory = Memory::new();
+        for (address, byte) in [0x60, 0x01, 0x61, 0x02, 0x12, 0x00].iter().enumerate() {
+            memory.poke(0x200 + address, *byte);
+        }
+        let mut cache = BlockCache::new(memory.size());
+        assert_eq!(cache.next(0x200, &memory).opcode, 0x6001);
+        // decoded with the block
+        assert_eq!(cache.next(0x202, &memory).opcode, 0x6102);
+        assert_eq!(cache.next(0x204, &memory).opcode, 0x1200);
+        assert_eq!((cache.hits, cache.misses), (2, 1));
+
+        memory.poke(0x203, 0x05);
+        cache.invalidate(0x203);
+        assert_eq!(cache.invalidations, 1);
+        assert_eq!(cache.next(0x202, &memory).opcode, 0x6105);
+        assert_eq!(cache.misses, 2);
+        // untouched instructions stay cached
+        assert_eq!(cache.next(0x200, &memory).opcode, 0x6001);
+        assert_eq!(cache.misses, 2);
+    }
+
+    #[test]
+    fn blocks_wrap_around_memory() {
+        let mut memory = Memory::new();
+        memory.poke(0xFFF, 0x12);
+        memory.poke(0x000, 0x34);
+        let mut cache = BlockCache::new(memory.size());
+        assert_eq!(cache.next(0xFFF, &memory).opcode, 0x1234);
+        memory.poke(0x000, 0x00);
+        cache.invalidate(0x000);
+        assert_eq!(cache.next(0xFFF, &memory).opcode, 0x1200);
+    }
+}
@@ -5,4 +5,5 @@ pub mod index_register;
 pub mod stack;
 pub mod delay_timer;
 pub mod sound_timer;
-pub mod variable_register;
\ No newline at end of file
+pub mod variable_register;
+pub mod keypad;
\ No newline at end of file
@@ -4,18 +4,19 @@ pub struct DelayTimer {
 
 impl DelayTimer {
     pub fn new() -> Self {
-        Self{ value: 255}
+        Self{ value: 0}
     }
 
+    // called at 60 Hz
     pub fn tick(&mut self) {
-        if(self.value>=0) {
-            self.value-=1;
-        } else {
-            println!("Delay Timer reached 0");
-        }
+        self.value = self.value.saturating_sub(1);
+    }
+
+    pub fn set_value(&mut self, value: u8) {
+        self.value = value;
     }
 
     pub fn get_value(&self) -> u8 {
         self.value
     }
-}
\ No newline at end of file
+}
@@ -1,43 +1,462 @@
+// CHIP-8 resolution, and the high resolution of SCHIP and XO-CHIP.
+pub const LORES_WIDTH: usize = 64;
+pub const LORES_HEIGHT: usize = 32;
+pub const HIRES_WIDTH: usize = 128;
+pub const HIRES_HEIGHT: usize = 64;
+// XO-CHIP draws on two bit planes.
+pub const MAX_PLANES: usize = 2;
+
+/// A rectangle of pixels.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Region {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+/// The rows and columns that changed, as one bit per row and column (bit `x` is
+/// column `x`). A pixel may have changed if both its row and its column did.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Damage {
+    rows: u64,
+    columns: u128,
+}
+
+impl Damage {
+    /// Every pixel of a display of the given size.
+    pub fn all(width: usize, height: usize) -> Self {
+        Self {
+            rows: low_bits(height) as u64,
+            columns: low_bits(width),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows == 0
+    }
+
+    pub fn contains_row(&self, y: usize) -> bool {
+        y < 64 && (self.rows >> y) & 1 == 1
+    }
+
+    /// Changed rows, top to bottom.
+    pub fn rows(&self) -> impl Iterator<Item = usize> {
+        let rows = self.rows;
+        (0..64).filter(move |y| (rows >> y) & 1 == 1)
+    }
+
+    /// The smallest region around every change.
+    pub fn bounds(&self) -> Option<Region> {
+        if self.is_empty() {
+            return None;
+        }
+        let x = self.columns.trailing_zeros() as usize;
+        let y = self.rows.trailing_zeros() as usize;
+        Some(Region {
+            x,
+            y,
+            width: 128 - self.columns.leading_zeros() as usize - x,
+            height: 64 - self.rows.leading_zeros() as usize - y,
+        })
+    }
+
+    pub fn union(self, other: Damage) -> Self {
+        Self {
+            rows: self.rows | other.rows,
+            columns: self.columns | other.columns,
+        }
+    }
+}
+
+fn low_bits(count: usize) -> u128 {
+    if count >= 128 {
+        u128::MAX
+    } else {
+        (1 << count) - 1
+    }
+}
+
+/// A bit-packed framebuffer with one `u128` per row and plane. Column `x` of a
+/// row is bit `width - 1 - x`, so the leftmost pixel is the highest bit in use.
 pub struct Display {
-    pixels: [[bool; 64]; 32],
+    width: usize,
+    height: usize,
+    // rows of every plane, plane after plane
+    rows: Vec<u128>,
+    planes: usize,
+    // changed since the last `take_damage`, in the bit order of the rows
+    damaged_rows: u64,
+    damaged_columns: u128,
 }
 
 impl Display {
     pub fn new() -> Self {
+        Self::with_size(LORES_WIDTH, LORES_HEIGHT, 1)
+    }
+
+    pub fn with_size(width: usize, height: usize, planes: usize) -> Self {
+        assert!(
+            width <= HIRES_WIDTH && width.is_multiple_of(8),
+            "Unsupported display width {}.",
+            width
+        );
+        assert!(
+            height <= HIRES_HEIGHT,
+            "Unsupported display height {}.",
+            height
+        );
+        assert!(
+            (1..=MAX_PLANES).contains(&planes),
+            "Unsupported plane count {}.",
+            planes
+        );
         Self {
-            pixels: [[false; 64]; 32],
+            width,
+            height,
+            rows: vec![0; height * planes],
+            planes,
+            damaged_rows: 0,
+            damaged_columns: 0,
         }
     }
 
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn planes(&self) -> usize {
+        self.planes
+    }
+
     pub fn clear(&mut self) {
-        for Y in self.pixels.iter_mut() {
-            for pixel in Y.iter_mut() {
-                *pixel = false;
+        let height = self.height;
+        for (index, row) in self.rows.iter_mut().enumerate() {
+            if *row != 0 {
+                self.damaged_rows |= 1 << (index % height);
+                self.damaged_columns |= *row;
+                *row = 0;
             }
         }
     }
 
+    /// Whether anything changed since the last `take_damage`.
+    pub fn is_changed(&self) -> bool {
+        self.damaged_rows != 0
+    }
+
+    /// What changed since the last call. The emulator takes it once per frame.
+    pub fn take_damage(&mut self) -> Damage {
+        let damage = Damage {
+            rows: self.damaged_rows,
+            columns: self.damaged_columns.reverse_bits() >> (128 - self.width),
+        };
+        self.damaged_rows = 0;
+        self.damaged_columns = 0;
+        damage
+    }
+
+    /// Rows of one plane, top to bottom.
+    pub fn rows(&self, plane: usize) -> &[u128] {
+        &self.rows[plane * self.height..(plane + 1) * self.height]
+    }
+
+    fn mask(&self) -> u128 {
+        low_bits(self.width)
+    }
+
+    /// The planes a pixel is lit on as bits, which is also its palette index.
+    pub fn pixel(&self, x: usize, y: usize) -> u8 {
+        let bit = self.width - 1 - x;
+        (0..self.planes).fold(0, |index, plane| {
+            index | ((((self.rows(plane)[y] >> bit) & 1) as u8) << plane)
+        })
+    }
+
     pub fn is_pixel_on(&self, x: usize, y: usize) -> bool {
-        self.pixels[y][x]
+        self.pixel(x, y) != 0
+    }
+
+    /// XORs an 8 pixel wide sprite onto a plane at (x, y) and returns whether
+    /// a lit pixel was turned off. Pixels past the edges wrap around or are clipped.
+    pub fn draw_sprite(
+        &mut self,
+        plane: usize,
+        x: usize,
+        y: usize,
+        sprite: &[u8],
+        wrap: bool,
+    ) -> bool {
+        let shift = self.width - 8;
+        let rows = sprite.iter().map(|row| (*row as u128) << shift);
+        self.blit(plane, x, y, rows, wrap)
+    }
+
+    /// Like `draw_sprite`, for the 16x16 sprites of SCHIP.
+    pub fn draw_wide_sprite(
+        &mut self,
+        plane: usize,
+        x: usize,
+        y: usize,
+        sprite: &[u16],
+        wrap: bool,
+    ) -> bool {
+        let shift = self.width - 16;
+        let rows = sprite.iter().map(|row| (*row as u128) << shift);
+        self.blit(plane, x, y, rows, wrap)
+    }
+
+    // `rows` are aligned to the left edge, so they only have to be shifted by x.
+    fn blit(
+        &mut self,
+        plane: usize,
+        x: usize,
+        y: usize,
+        rows: impl Iterator<Item = u128>,
+        wrap: bool,
+    ) -> bool {
+        let (width, height, mask) = (self.width, self.height, self.mask());
+        let x = x % width;
+        let y = y % height;
+        let target = &mut self.rows[plane * height..(plane + 1) * height];
+        let mut collision = false;
+        for (offset, row) in rows.enumerate() {
+            let mut line = y + offset;
+            if line >= height {
+                if !wrap {
+                    break;
+                }
+                line %= height;
+            }
+            let mut bits = row >> x;
+            if wrap && x > 0 {
+                bits |= (row << (width - x)) & mask;
+            }
+            collision |= target[line] & bits != 0;
+            target[line] ^= bits;
+            if bits != 0 {
+                self.damaged_rows |= 1 << line;
+                self.damaged_columns |= bits;
+            }
+        }
+        collision
+    }
+
+    // FNV-1a over one byte per pixel (its palette index), row by row. Simple
+    // enough to compute in other emulators, so their traces can be compared with ours.
+    pub fn hash(&self) -> u64 {
+        let mut hash: u64 = 0xcbf29ce484222325;
+        for y in 0..self.height {
+            for x in 0..self.width {
+                hash ^= self.pixel(x, y) as u64;
+                hash = hash.wrapping_mul(0x100000001b3);
+            }
+        }
+        hash
+    }
+
+    // Puts back rows taken from a display of the same size, e.g. from a save state.
+    pub fn restore(&mut self, plane: usize, rows: &[u128]) {
+        let (height, mask) = (self.height, self.mask());
+        let target = &mut self.rows[plane * height..(plane + 1) * height];
+        for (line, (target, row)) in target.iter_mut().zip(rows.iter()).enumerate() {
+            let changed = *target ^ (row & mask);
+            if changed != 0 {
+                self.damaged_rows |= 1 << line;
+                self.damaged_columns |= changed;
+                *target ^= changed;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The 0 of the font.
+    const ZERO: [u8; 5] = [0xF0, 0x90, 0x90, 0x90, 0xF0];
+
+    fn lit(display: &Display) -> Vec<(usize, usize)> {
+        (0..display.height())
+            .flat_map(|y| (0..display.width()).map(move |x| (x, y)))
+            .filter(|(x, y)| display.is_pixel_on(*x, *y))
+            .collect()
+    }
+
+    #[test]
+    fn draws_and_erases_sprites() {
+        let mut display = Display::new();
+        assert!(!display.draw_sprite(0, 10, 3, &ZERO, false));
+        assert_eq!(display.rows(0)[3], 0xF0 << (64 - 8 - 10));
+        assert_eq!(display.rows(0)[4], 0x90 << (64 - 8 - 10));
+        assert!(display.is_pixel_on(10, 3) && display.is_pixel_on(13, 4));
+        assert!(!display.is_pixel_on(11, 4) && !display.is_pixel_on(10, 8));
+        assert_eq!(lit(&display).len(), 14);
+        // drawing it again turns every pixel off
+        assert!(display.draw_sprite(0, 10, 3, &ZERO, false));
+        assert!(lit(&display).is_empty());
+    }
+
+    #[test]
+    fn collides_only_on_overlap() {
+        let mut display = Display::new();
+        display.draw_sprite(0, 0, 0, &[0xF0], false);
+        assert!(!display.draw_sprite(0, 4, 0, &[0xF0], false));
+        assert!(display.draw_sprite(0, 7, 0, &[0x80], false));
+        assert!(!display.is_pixel_on(7, 0));
+    }
+
+    #[test]
+    fn clips_or_wraps_at_the_edges() {
+        let mut clipped = Display::new();
+        clipped.draw_sprite(0, 60, 30, &ZERO, false);
+        assert_eq!(
+            lit(&clipped),
+            [(60, 30), (61, 30), (62, 30), (63, 30), (60, 31), (63, 31)]
+        );
+
+        let mut wrapped = Display::new();
+        wrapped.draw_sprite(0, 62, 30, &[0xFF, 0x81, 0x81], true);
+        assert!(wrapped.is_pixel_on(63, 30) && wrapped.is_pixel_on(0, 30));
+        assert!(wrapped.is_pixel_on(5, 30) && !wrapped.is_pixel_on(6, 30));
+        assert!(wrapped.is_pixel_on(62, 31) && wrapped.is_pixel_on(5, 31));
+        assert!(wrapped.is_pixel_on(62, 0) && wrapped.is_pixel_on(5, 0));
+        assert_eq!(lit(&wrapped).len(), 8 + 2 + 2);
+
+        // coordinates past the screen start over, with both quirks
+        let mut display = Display::new();
+        display.draw_sprite(0, 64 + 1, 32 + 2, &[0x80], false);
+        assert_eq!(lit(&display), [(1, 2)]);
     }
 
-    pub fn set_pixel(&mut self, x: usize, y: usize) {
-        self.pixels[y][x] = true;
+    #[test]
+    fn draws_wide_sprites_in_high_resolution() {
+        let mut display = Display::with_size(HIRES_WIDTH, HIRES_HEIGHT, 1);
+        let sprite = [0x8001; 16];
+        display.draw_wide_sprite(0, 120, 60, &sprite, false);
+        assert_eq!(lit(&display), [(120, 60), (120, 61), (120, 62), (120, 63)]);
+        display.draw_wide_sprite(0, 120, 0, &sprite, true);
+        // the right column of the sprite wraps to x = 7
+        assert!(display.is_pixel_on(7, 0) && display.is_pixel_on(7, 15));
+        assert!(!display.is_pixel_on(8, 0));
     }
 
-    pub fn un_set_pixel(&mut self, x: usize, y: usize) {
-        self.pixels[y][x] = false;
+    #[test]
+    fn planes_make_the_palette_index() {
+        let mut display = Display::with_size(LORES_WIDTH, LORES_HEIGHT, 2);
+        display.draw_sprite(0, 0, 0, &[0xC0], false);
+        display.draw_sprite(1, 1, 0, &[0xC0], false);
+        assert_eq!(
+            [0, 1, 2, 3].map(|x| display.pixel(x, 0)),
+            [0b01, 0b11, 0b10, 0b00]
+        );
+        // collisions are per plane
+        assert!(!display.draw_sprite(1, 0, 0, &[0x80], false));
+        display.clear();
+        assert!(lit(&display).is_empty());
+    }
+
+    #[test]
+    fn restores_rows() {
+        let mut display = Display::new();
+        display.draw_sprite(0, 8, 8, &ZERO, false);
+        let rows = display.rows(0).to_vec();
+        let hash = display.hash();
+        let mut restored = Display::new();
+        restored.restore(0, &rows);
+        assert_eq!(restored.rows(0), &rows[..]);
+        assert_eq!(restored.hash(), hash);
+        assert_ne!(Display::new().hash(), hash);
+    }
+
+    #[test]
+    fn tracks_changed_rows_and_columns() {
+        let mut display = Display::new();
+        assert!(!display.is_changed());
+        assert!(display.take_damage().is_empty());
+
+        display.draw_sprite(0, 10, 3, &ZERO, false);
+        display.draw_sprite(0, 40, 20, &[0x01], false);
+        assert!(display.is_changed());
+        let damage = display.take_damage();
+        assert_eq!(damage.rows().collect::<Vec<_>>(), [3, 4, 5, 6, 7, 20]);
+        assert!(damage.contains_row(20) && !damage.contains_row(8));
+        assert_eq!(
+            damage.bounds(),
+            Some(Region {
+                x: 10,
+                y: 3,
+                width: 48 - 10,
+                height: 18,
+            })
+        );
+        // taken once
+        assert!(!display.is_changed());
+        assert_eq!(display.take_damage(), Damage::default());
     }
 
-    pub fn is_right_edge(&self, x: usize) -> bool {
-        x >= 63
+    #[test]
+    fn damage_covers_wrapped_pixels() {
+        let mut display = Display::with_size(HIRES_WIDTH, HIRES_HEIGHT, 1);
+        display.draw_sprite(0, 124, 63, &[0xFF, 0xFF], true);
+        let damage = display.take_damage();
+        assert_eq!(damage.rows().collect::<Vec<_>>(), [0, 63]);
+        assert_eq!(
+            damage.bounds(),
+            Some(Region {
+                x: 0,
+                y: 0,
+                width: 128,
+                height: 64,
+            })
+        );
     }
 
-    pub fn is_bottom_edge(&self, y: usize) -> bool {
-        y >= 32
+    #[test]
+    fn only_visible_changes_count() {
+        let mut display = Display::new();
+        // an empty sprite row changes nothing
+        display.draw_sprite(0, 0, 0, &[0x00, 0x80], false);
+        assert_eq!(display.take_damage().rows().collect::<Vec<_>>(), [1]);
+        // clearing only damages lit rows
+        display.clear();
+        assert_eq!(display.take_damage().rows().collect::<Vec<_>>(), [1]);
+        display.clear();
+        assert!(!display.is_changed());
+        // restoring the same rows changes nothing
+        display.draw_sprite(0, 0, 5, &ZERO, false);
+        display.take_damage();
+        let rows = display.rows(0).to_vec();
+        display.restore(0, &rows);
+        assert!(!display.is_changed());
     }
 
-    pub fn get_pixels(&self) -> [[bool; 64]; 32] {
-        self.pixels
+    #[test]
+    fn damage_adds_up() {
+        let all = Damage::all(LORES_WIDTH, LORES_HEIGHT);
+        assert_eq!(all.rows().count(), 32);
+        assert_eq!(
+            all.bounds(),
+            Some(Region {
+                x: 0,
+                y: 0,
+                width: 64,
+                height: 32,
+            })
+        );
+        let mut display = Display::new();
+        display.draw_sprite(0, 0, 0, &[0x80], false);
+        let first = display.take_damage();
+        display.draw_sprite(0, 63, 31, &[0x80], false);
+        let second = display.take_damage();
+        let both = first.union(second);
+        assert_eq!(both.rows().collect::<Vec<_>>(), [0, 31]);
+        assert_eq!(both.bounds(), all.bounds());
+        assert_eq!(Damage::default().union(first), first);
     }
 }
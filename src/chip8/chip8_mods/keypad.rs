@@ -0,0 +1,218 @@
+// Physical keys for the hex keypad, using the usual 1234/QWER/ASDF/ZXCV layout:
+//  1 2 3 C      1 2 3 4
+//  4 5 6 D  ->  Q W E R
+//  7 8 9 E      A S D F
+//  A 0 B F      Z X C V
+const KEY_LAYOUT: [char; 16] = [
+    'x', '1', '2', '3', 'q', 'w', 'e', 'a', 's', 'd', 'z', 'c', '4', 'r', 'f', 'v',
+];
+
+#[derive(Default)]
+pub struct Keypad {
+    keys: [bool; 16],
+    // FX0A waits for a key to be pressed and released again
+    waiting_for_release: bool,
+    released: Option<u8>,
+}
+
+impl Keypad {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn press(&mut self, key: u8) {
+        self.keys[(key & 0xF) as usize] = true;
+    }
+
+    pub fn release(&mut self, key: u8) {
+        self.keys[(key & 0xF) as usize] = false;
+        if self.waiting_for_release {
+            self.released = Some(key & 0xF);
+        }
+    }
+
+    // Returns the key released since the last call, or starts waiting for one.
+    pub fn take_released(&mut self) -> Option<u8> {
+        let released = self.released.take();
+        self.waiting_for_release = released.is_none();
+        released
+    }
+
+    // Whether FX0A waits for a release and the key released meanwhile, for save states.
+    pub fn wait_state(&self) -> (bool, Option<u8>) {
+        (self.waiting_for_release, self.released)
+    }
+
+    pub fn restore_wait_state(&mut self, waiting_for_release: bool, released: Option<u8>) {
+        self.waiting_for_release = waiting_for_release;
+        self.released = released;
+    }
+
+    pub fn is_pressed(&self, key: u8) -> bool {
+        self.keys[(key & 0xF) as usize]
+    }
+
+    pub fn key_for_char(c: char) -> Option<u8> {
+        let c = c.to_ascii_lowercase();
+        KEY_LAYOUT.iter().position(|k| *k == c).map(|key| key as u8)
+    }
+}
+
+/// Keypad keys a game uses for its actions, so frontends can bind arrow keys and buttons to them.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Keymap {
+    pub up: Option<u8>,
+    pub down: Option<u8>,
+    pub left: Option<u8>,
+    pub right: Option<u8>,
+    pub a: Option<u8>,
+    pub b: Option<u8>,
+}
+
+impl Keymap {
+    /// Reads a chip-8-database `keys` object, e.g. `{"up": 5, "a": 6}`.
+    pub fn from_json(keys: &serde_json::Value) -> Self {
+        let key = |name: &str| {
+            keys.get(name)
+                .and_then(|key| key.as_u64())
+                .filter(|key| *key < 16)
+                .map(|key| key as u8)
+        };
+        Self {
+            up: key("up"),
+            down: key("down"),
+            left: key("left"),
+            right: key("right"),
+            a: key("a"),
+            b: key("b"),
+        }
+    }
+
+    pub fn to_json(self) -> serde_json::Value {
+        let mut keys = serde_json::Map::new();
+        for (name, key) in self.bindings() {
+            if let Some(key) = key {
+                keys.insert(name.to_string(), key.into());
+            }
+        }
+        serde_json::Value::Object(keys)
+    }
+
+    /// Parses a comma separated list of bindings, e.g. `up=5,down=8,a=6`.
+    pub fn from_bindings(list: &str) -> Option<Self> {
+        let mut keymap = Self::default();
+        for binding in list.split(',') {
+            let (name, key) = binding.split_once('=')?;
+            let key = u8::from_str_radix(key.trim(), 16).ok().filter(|key| *key < 16)?;
+            let slot = match name.trim() {
+                "up" => &mut keymap.up,
+                "down" => &mut keymap.down,
+                "left" => &mut keymap.left,
+                "right" => &mut keymap.right,
+                "a" => &mut keymap.a,
+                "b" => &mut keymap.b,
+                _ => return None,
+            };
+            *slot = Some(key);
+        }
+        Some(keymap)
+    }
+
+    fn bindings(&self) -> [(&'static str, Option<u8>); 6] {
+        [
+            ("up", self.up),
+            ("down", self.down),
+            ("left", self.left),
+            ("right", self.right),
+            ("a", self.a),
+            ("b", self.b),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::chip8_mods::memory::Layout;
+    use crate::chip8::rom::Rom;
+    use crate::chip8::Chip8;
+
+    #[test]
+    fn maps_characters_to_keys() {
+        assert_eq!(Keypad::key_for_char('1'), Some(0x1));
+        assert_eq!(Keypad::key_for_char('x'), Some(0x0));
+        assert_eq!(Keypad::key_for_char('V'), Some(0xF));
+        assert_eq!(Keypad::key_for_char('4'), Some(0xC));
+        assert_eq!(Keypad::key_for_char('g'), None);
+    }
+
+    #[test]
+    fn holds_keys() {
+        let mut keypad = Keypad::new();
+        keypad.press(0x1A);
+        assert!(keypad.is_pressed(0xA));
+        keypad.release(0xA);
+        assert!(!keypad.is_pressed(0xA));
+    }
+
+    #[test]
+    fn waits_for_a_release() {
+        let mut keypad = Keypad::new();
+        // a release before FX0A asks for one doesn't count
+        keypad.press(0x3);
+        keypad.release(0x3);
+        assert_eq!(keypad.take_released(), None);
+        keypad.press(0x5);
+        assert_eq!(keypad.take_released(), None);
+        keypad.release(0x5);
+        assert_eq!(keypad.wait_state(), (true, Some(0x5)));
+        assert_eq!(keypad.take_released(), Some(0x5));
+        assert_eq!(keypad.wait_state(), (false, None));
+    }
+
+    #[test]
+    fn fx0a_finishes_on_release() {
+        // 0x200 F30A  LD V3, K
+        // 0x202 1202  JP 0x202
+        let rom = Rom::from_bytes("test.ch8", &[0xF3, 0x0A, 0x12, 0x02]);
+        let mut chip8 = Chip8::from_rom(&rom, Layout::Chip8).unwrap();
+        chip8.step().unwrap();
+        assert_eq!(chip8.pc(), 0x200);
+        chip8.set_keys(1 << 0xB);
+        chip8.step().unwrap();
+        assert_eq!(chip8.pc(), 0x200);
+        chip8.set_keys(0);
+        chip8.step().unwrap();
+        assert_eq!(chip8.pc(), 0x202);
+        assert_eq!(chip8.register(3), 0xB);
+    }
+
+    #[test]
+    fn reads_keymaps() {
+        let keymap = Keymap::from_bindings("up=5, down=8,a=F").unwrap();
+        assert_eq!(
+            keymap,
+            Keymap {
+                up: Some(0x5),
+                down: Some(0x8),
+                a: Some(0xF),
+                ..Keymap::default()
+            }
+        );
+        assert_eq!(keymap.to_json(), serde_json::json!({"up": 5, "down": 8, "a": 15}));
+        assert_eq!(Keymap::from_json(&keymap.to_json()), keymap);
+
+        assert_eq!(Keymap::from_bindings("up=10"), None);
+        assert_eq!(Keymap::from_bindings("jump=1"), None);
+        assert_eq!(Keymap::from_bindings("up"), None);
+        // keys outside the keypad are ignored
+        let keys = serde_json::json!({"left": 16, "right": 9});
+        assert_eq!(
+            Keymap::from_json(&keys),
+            Keymap {
+                right: Some(0x9),
+                ..Keymap::default()
+            }
+        );
+    }
+}
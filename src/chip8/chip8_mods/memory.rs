@@ -1,16 +1,11 @@
-use std::fs;
-use std::path::Path; // 1.3.4
-use std::fs::File;
-use std::io::prelude::*;
+use std::cell::RefCell;
+use std::fmt;
 
-fn read_font() -> Option<[u8; 80]> {
-    let filenpath = Path::new("./font/font");
-    println!("In file {}", filenpath.display());
-
-    let mut contents =
-        fs::read_to_string(filenpath).expect("Something went wrong reading the file");
+// built in, so the emulator doesn't depend on the working directory
+const FONT: &str = include_str!("../../../font/font");
 
-    println!("With text:\n{}", contents);
+fn read_font() -> Option<[u8; 80]> {
+    let mut contents = FONT.to_string();
     contents.retain(|c| !c.is_whitespace());
     let split_content: Vec<&str> = contents.split(',').collect();
     if split_content.len() != 80 {
@@ -28,14 +23,154 @@ fn read_font() -> Option<[u8; 80]> {
     return Some(result_array);
 }
 
+/// Address space layouts of the supported machines.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Layout {
+    // 4 KB, programs start at 0x200
+    Chip8,
+    // 64 KB like XO-CHIP, programs start at 0x200
+    Extended,
+    // 4 KB, programs start at 0x600 like on the ETI-660
+    Eti660,
+}
+
+impl Layout {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "chip8" => Some(Layout::Chip8),
+            "64k" => Some(Layout::Extended),
+            "eti660" => Some(Layout::Eti660),
+            _ => None,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        match self {
+            Layout::Chip8 | Layout::Eti660 => 0x1000,
+            Layout::Extended => 0x10000,
+        }
+    }
+
+    pub fn program_start(&self) -> usize {
+        match self {
+            Layout::Chip8 | Layout::Extended => 0x200,
+            Layout::Eti660 => 0x600,
+        }
+    }
+
+    fn regions(&self) -> Vec<Region> {
+        let program_start = self.program_start();
+        let mut regions = vec![Region {
+            kind: RegionKind::Interpreter,
+            start: 0,
+            end: program_start,
+            write_protected: false,
+        }];
+        match self {
+            Layout::Chip8 => {
+                regions.push(Region {
+                    kind: RegionKind::Program,
+                    start: program_start,
+                    end: VIP_RESERVED_START,
+                    write_protected: false,
+                });
+                regions.push(Region {
+                    kind: RegionKind::VipReserved,
+                    start: VIP_RESERVED_START,
+                    end: self.size(),
+                    write_protected: false,
+                });
+            }
+            Layout::Extended | Layout::Eti660 => regions.push(Region {
+                kind: RegionKind::Program,
+                start: program_start,
+                end: self.size(),
+                write_protected: false,
+            }),
+        }
+        regions
+    }
+}
+
+// The COSMAC VIP interpreter used the top of its 4 KB for the stack (0xEA0),
+// its work area (0xED0) and the display buffer (0xF00).
+const VIP_RESERVED_START: usize = 0xEA0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RegionKind {
+    // font and interpreter code
+    Interpreter,
+    Program,
+    // stack, work area and display of the COSMAC VIP interpreter
+    VipReserved,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Region {
+    pub kind: RegionKind,
+    pub start: usize,
+    // exclusive
+    pub end: usize,
+    pub write_protected: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AccessKind {
+    // instruction fetch by the CPU
+    Fetch,
+    // data read, e.g. sprites or FX65
+    Read,
+    Write,
+}
+
+/// One memory access as seen by a hook. Hooks may change `value` to alter
+/// what is read or written, e.g. for cheats.
+#[derive(Debug, Clone, Copy)]
+pub struct Access {
+    pub kind: AccessKind,
+    pub address: usize,
+    pub value: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MemoryFault {
+    WriteProtected { address: usize, region: RegionKind },
+}
+
+impl fmt::Display for MemoryFault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MemoryFault::WriteProtected { address, region } => write!(
+                f,
+                "Write to protected {:?} memory at {:#05x}.",
+                region, address
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MemoryFault {}
+
+pub type HookId = usize;
+type Hook = Box<dyn FnMut(&mut Access) + Send>;
+
 pub struct Memory {
-    RAM: [u8; 4096],
+    RAM: Vec<u8>,
+    layout: Layout,
+    regions: Vec<Region>,
+    // reads only borrow the memory, so hooks live in a RefCell
+    hooks: RefCell<Vec<(HookId, Hook)>>,
+    next_hook_id: HookId,
 }
 
 impl Memory {
     pub fn new() -> Self {
+        Self::with_layout(Layout::Chip8)
+    }
+
+    pub fn with_layout(layout: Layout) -> Self {
         let font_bit_array = read_font();
-        let mut ram: [u8; 4096] = [0; 4096];
+        let mut ram: Vec<u8> = vec![0; layout.size()];
         if font_bit_array == None {
             panic!("Could not read font.");
         } else {
@@ -43,28 +178,224 @@ impl Memory {
                 ram[i] = *font_byte;
             }
         }
-        Self { RAM: ram }
+        Self {
+            RAM: ram,
+            layout,
+            regions: layout.regions(),
+            hooks: RefCell::new(Vec::new()),
+            next_hook_id: 0,
+        }
+    }
+
+    pub fn layout(&self) -> Layout {
+        self.layout
+    }
+
+    pub fn size(&self) -> usize {
+        self.RAM.len()
+    }
+
+    // Addresses past the end wrap around to the start, like the 12-bit addresses
+    // of the COSMAC VIP. Every layout has a power of two as its size.
+    pub fn wrap(&self, address: usize) -> usize {
+        address & (self.RAM.len() - 1)
+    }
+
+    pub fn regions(&self) -> &[Region] {
+        &self.regions
+    }
+
+    pub fn region_at(&self, address: usize) -> Option<&Region> {
+        self.regions
+            .iter()
+            .find(|region| region.start <= address && address < region.end)
+    }
+
+    pub fn set_write_protection(&mut self, kind: RegionKind, write_protected: bool) {
+        for region in self.regions.iter_mut().filter(|region| region.kind == kind) {
+            region.write_protected = write_protected;
+        }
+    }
+
+    /// Calls `hook` for every fetch, read and write until it is removed again.
+    pub fn add_hook(&mut self, hook: impl FnMut(&mut Access) + Send + 'static) -> HookId {
+        let id = self.next_hook_id;
+        self.next_hook_id += 1;
+        self.hooks.get_mut().push((id, Box::new(hook)));
+        id
+    }
+
+    pub fn remove_hook(&mut self, id: HookId) {
+        self.hooks.get_mut().retain(|(hook_id, _)| *hook_id != id);
+    }
+
+    pub fn has_hooks(&self) -> bool {
+        !self.hooks.borrow().is_empty()
+    }
+
+    fn notify(&self, kind: AccessKind, address: usize, value: u8) -> u8 {
+        let mut hooks = self.hooks.borrow_mut();
+        if hooks.is_empty() {
+            return value;
+        }
+        let mut access = Access {
+            kind,
+            address,
+            value,
+        };
+        for (_, hook) in hooks.iter_mut() {
+            hook(&mut access);
+        }
+        access.value
     }
 
     pub fn get_instruction(&self, pointer_value: u32) -> u16 {
-        let memory_point = self.RAM.get(pointer_value as usize).unwrap();
-        let memory_point2 = self.RAM.get((pointer_value + 1) as usize).unwrap();
+        let address = self.wrap(pointer_value as usize);
+        let next = self.wrap(address + 1);
+        let memory_point = self.notify(AccessKind::Fetch, address, self.RAM[address]);
+        let memory_point2 = self.notify(AccessKind::Fetch, next, self.RAM[next]);
 
-        return ((*memory_point as u16) << 8) | *memory_point2 as u16;
+        ((memory_point as u16) << 8) | memory_point2 as u16
     }
 
     pub fn get_byte(&self, from: usize, at: usize) -> u8 {
-        return *self.RAM.get(from + at).unwrap();
+        let address = self.wrap(from + at);
+        self.notify(AccessKind::Read, address, self.RAM[address])
     }
 
-    pub fn read_program(&mut self, path: &str) {
-        let filenpath = Path::new(path);
-        println!("In program {}", filenpath.display());
+    pub fn write_byte(&mut self, address: usize, value: u8) -> Result<(), MemoryFault> {
+        let address = self.wrap(address);
+        if let Some(region) = self.region_at(address).filter(|region| region.write_protected) {
+            return Err(MemoryFault::WriteProtected {
+                address,
+                region: region.kind,
+            });
+        }
+        let value = self.notify(AccessKind::Write, address, value);
+        self.RAM[address] = value;
+        Ok(())
+    }
+
+    // Reads without calling hooks, for the interpreter itself and debugging tools.
+    pub fn peek(&self, address: usize) -> u8 {
+        self.RAM[self.wrap(address)]
+    }
+
+    // Writes without calling hooks or checking protection, for the interpreter itself.
+    pub fn poke(&mut self, address: usize, value: u8) {
+        let address = self.wrap(address);
+        self.RAM[address] = value;
+    }
+
+    pub fn load_program(&mut self, program: &[u8]) {
+        let program_start = self.layout.program_start();
+        self.RAM[program_start..program_start + program.len()].copy_from_slice(program);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
 
-        let contents = fs::read(filenpath).expect("Something went wrong reading the program");
+    #[test]
+    fn layouts() {
+        assert_eq!(Layout::from_name("64k"), Some(Layout::Extended));
+        assert_eq!(Layout::from_name("128k"), None);
+        let memory = Memory::with_layout(Layout::Eti660);
+        assert_eq!(memory.size(), 0x1000);
+        assert_eq!(memory.region_at(0x5FF).unwrap().kind, RegionKind::Interpreter);
+        assert_eq!(memory.region_at(0x600).unwrap().kind, RegionKind::Program);
+        assert_eq!(memory.region_at(0xFFF).unwrap().kind, RegionKind::Program);
+    }
 
-        for (i, u8val) in contents.iter().enumerate() {
-            self.RAM[0x200 + i] = *u8val;
+    #[test]
+    fn regions_cover_all_memory() {
+        for layout in [Layout::Chip8, Layout::Extended, Layout::Eti660] {
+            let memory = Memory::with_layout(layout);
+            let regions = memory.regions();
+            assert_eq!(regions[0].start, 0);
+            assert_eq!(regions.last().unwrap().end, layout.size());
+            assert!(regions.windows(2).all(|pair| pair[0].end == pair[1].start));
         }
+        let memory = Memory::new();
+        assert_eq!(memory.region_at(0xEA0).unwrap().kind, RegionKind::VipReserved);
+        assert_eq!(memory.region_at(0xE9F).unwrap().kind, RegionKind::Program);
+    }
+
+    #[test]
+    fn starts_with_the_font() {
+        let memory = Memory::new();
+        // the 0 is F0 90 90 90 F0
+        let zero: Vec<u8> = (0..5).map(|address| memory.peek(address)).collect();
+        assert_eq!(zero, [0xF0, 0x90, 0x90, 0x90, 0xF0]);
+    }
+
+    #[test]
+    fn protected_writes_fault() {
+        let mut memory = Memory::new();
+        let font = memory.peek(0x010);
+        memory.set_write_protection(RegionKind::Interpreter, true);
+        assert_eq!(
+            memory.write_byte(0x010, 0xAA),
+            Err(MemoryFault::WriteProtected {
+                address: 0x010,
+                region: RegionKind::Interpreter,
+            })
+        );
+        assert_eq!(memory.peek(0x010), font);
+        assert_eq!(memory.write_byte(0x300, 0xAA), Ok(()));
+        // the interpreter itself may still write there
+        memory.poke(0x010, 0xAA);
+        assert_eq!(memory.peek(0x010), 0xAA);
+        memory.set_write_protection(RegionKind::Interpreter, false);
+        assert_eq!(memory.write_byte(0x010, 0xBB), Ok(()));
+    }
+
+    #[test]
+    fn addresses_wrap_around() {
+        let mut memory = Memory::new();
+        memory.write_byte(0x1000 + 0x300, 0x12).unwrap();
+        assert_eq!(memory.peek(0x300), 0x12);
+        memory.poke(0xFFF, 0x34);
+        memory.poke(0x000, 0x56);
+        assert_eq!(memory.get_instruction(0xFFF), 0x3456);
+        assert_eq!(memory.get_byte(0xFFF, 2), memory.peek(0x001));
+        // protection applies to the wrapped address
+        memory.set_write_protection(RegionKind::Interpreter, true);
+        assert!(memory.write_byte(0x1005, 0).is_err());
+    }
+
+    #[test]
+    fn hooks_see_and_change_accesses() {
+        let mut memory = Memory::new();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let log = seen.clone();
+        let id = memory.add_hook(move |access| {
+            log.lock().unwrap().push((access.kind, access.address));
+            if access.kind == AccessKind::Read {
+                access.value = 0x42;
+            }
+        });
+        assert!(memory.has_hooks());
+        memory.poke(0x200, 0x12);
+        memory.poke(0x201, 0x34);
+        assert_eq!(memory.get_instruction(0x200), 0x1234);
+        assert_eq!(memory.get_byte(0x300, 1), 0x42);
+        memory.write_byte(0x302, 7).unwrap();
+        // peek and poke don't call hooks
+        assert_eq!(memory.peek(0x301), 0);
+        assert_eq!(
+            *seen.lock().unwrap(),
+            [
+                (AccessKind::Fetch, 0x200),
+                (AccessKind::Fetch, 0x201),
+                (AccessKind::Read, 0x301),
+                (AccessKind::Write, 0x302),
+            ]
+        );
+        memory.remove_hook(id);
+        assert!(!memory.has_hooks());
+        assert_eq!(memory.get_byte(0x300, 1), 0);
     }
 }
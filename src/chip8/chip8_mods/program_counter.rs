@@ -1,12 +1,15 @@
-use crate::chip8::chip8_mods::memory;
-
 pub struct ProgramCounter {
     points_at: u32,
+    // addresses wrap around at the end of memory, whose size is a power of two
+    mask: u32,
 }
 
 impl ProgramCounter {
-    pub fn new() -> Self {
-        Self { points_at: 0x200 }
+    pub fn new(start: u32, memory_size: u32) -> Self {
+        Self {
+            points_at: start,
+            mask: memory_size - 1,
+        }
     }
 
     pub fn get_point_value(&self) -> u32 {
@@ -14,12 +17,11 @@ impl ProgramCounter {
     }
 
     pub fn set_point_value(&mut self, val: u32) {
-        const memory_size: u32 = 4096; // KB
-                                       // instruction is 2 bytes long
-        if val <= 4094 {
-            self.points_at = val;
-        } else {
-            panic!("INVALID PROGRAM COUNTER POINT VALUE!");
-        }
+        self.points_at = val & self.mask;
+    }
+
+    // Address of the instruction fetched last, two bytes back.
+    pub fn previous(&self) -> u32 {
+        self.points_at.wrapping_sub(2) & self.mask
     }
 }
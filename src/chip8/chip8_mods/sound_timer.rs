@@ -4,19 +4,24 @@ pub struct SoundTimer {
 
 impl SoundTimer {
     pub fn new() -> Self {
-        Self{ value: 255}
+        Self{ value: 0}
     }
 
+    // called at 60 Hz
     pub fn tick(&mut self) {
-        if(self.value>=0) {
-            self.value-=1;
-            println!("BEEEP!");
-        } else {
-            println!("Sound Timer reached 0");
-        }
+        self.value = self.value.saturating_sub(1);
+    }
+
+    pub fn set_value(&mut self, value: u8) {
+        self.value = value;
     }
 
     pub fn get_value(&self) -> u8 {
         self.value
     }
+
+    // the buzzer sounds as long as the timer is above zero
+    pub fn is_active(&self) -> bool {
+        self.value > 0
+    }
 }
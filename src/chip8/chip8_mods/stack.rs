@@ -1,36 +1,134 @@
-use std::collections::VecDeque;
+use std::fmt;
+
+// The COSMAC VIP interpreter reserved room for 12 return addresses, SCHIP for 16.
+pub const VIP_DEPTH: usize = 12;
+pub const SCHIP_DEPTH: usize = 16;
+// Where the COSMAC VIP interpreter kept its stack in RAM.
+pub const VIP_RAM_ADDRESS: u16 = 0xEA0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StackError {
+    Overflow,
+    Underflow,
+}
+
+impl fmt::Display for StackError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StackError::Overflow => write!(f, "STACK OVERFLOW ON CHIP8 STACK!"),
+            StackError::Underflow => write!(f, "STACK UNDERFLOW ON CHIP8 STACK!"),
+        }
+    }
+}
+
+impl std::error::Error for StackError {}
 
 pub struct Stack {
-    stack_queue: Vec<u16>,
-    pointer: usize
+    entries: Vec<u16>,
+    pointer: usize,
+    ram_address: Option<u16>,
 }
 
 impl Stack {
-    pub fn new() -> Self {
-        Self { stack_queue: Vec::new(),
-                pointer: 0}
+    pub fn new(depth: usize) -> Self {
+        Self {
+            entries: vec![0; depth],
+            pointer: 0,
+            ram_address: None,
+        }
     }
 
-    pub fn push(&mut self, val: u16) {
-        self.stack_queue.push(val);
-        if self.stack_queue.len() > 16 { 
-            println!("STACK OVERFLOW ON CHIP8 STACK!");
-        }
+    pub fn vip() -> Self {
+        Self::new(VIP_DEPTH).mapped_at(VIP_RAM_ADDRESS)
+    }
+
+    pub fn schip() -> Self {
+        Self::new(SCHIP_DEPTH)
     }
 
-    pub fn pop(&mut self) {
-        self.stack_queue.pop();
+    // Mirrors every entry into emulated RAM as big-endian words starting at `address`.
+    pub fn mapped_at(mut self, address: u16) -> Self {
+        self.ram_address = Some(address);
+        self
     }
 
-    pub fn set_pointer(&mut self, value: usize) {
-        self.pointer = value;
+    pub fn call(&mut self, return_address: u16) -> Result<(), StackError> {
+        if self.pointer == self.entries.len() {
+            return Err(StackError::Overflow);
+        }
+        self.entries[self.pointer] = return_address;
+        self.pointer += 1;
+        Ok(())
+    }
+
+    pub fn ret(&mut self) -> Result<u16, StackError> {
+        if self.pointer == 0 {
+            return Err(StackError::Underflow);
+        }
+        self.pointer -= 1;
+        Ok(self.entries[self.pointer])
     }
 
     pub fn get_pointer(&self) -> usize {
-        return self.pointer
+        self.pointer
     }
 
-    pub fn get_value_at_pointer(&self) -> u16 {
-        self.stack_queue[self.pointer]
+    // Every slot, including the ones above the pointer.
+    pub fn entries(&self) -> &[u16] {
+        &self.entries
     }
-}
\ No newline at end of file
+
+    // Puts back entries and pointer taken from a stack of the same depth.
+    pub fn restore(&mut self, entries: &[u16], pointer: usize) {
+        self.entries.copy_from_slice(entries);
+        self.pointer = pointer;
+    }
+
+    // Address of the entry at `index` in emulated RAM, if the stack is mapped.
+    pub fn ram_address_of(&self, index: usize) -> Option<usize> {
+        self.ram_address.map(|address| address as usize + index * 2)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn returns_in_reverse_order() {
+        let mut stack = Stack::new(2);
+        stack.call(0x202).unwrap();
+        stack.call(0x304).unwrap();
+        assert_eq!(stack.get_pointer(), 2);
+        assert_eq!(stack.ret(), Ok(0x304));
+        assert_eq!(stack.ret(), Ok(0x202));
+        assert_eq!(stack.get_pointer(), 0);
+    }
+
+    #[test]
+    fn overflows_past_its_depth() {
+        let mut stack = Stack::schip();
+        for i in 0..SCHIP_DEPTH {
+            assert_eq!(stack.call(i as u16), Ok(()));
+        }
+        assert_eq!(stack.call(0x200), Err(StackError::Overflow));
+        assert_eq!(stack.get_pointer(), SCHIP_DEPTH);
+        assert_eq!(stack.ret(), Ok(SCHIP_DEPTH as u16 - 1));
+    }
+
+    #[test]
+    fn underflows_when_empty() {
+        let mut stack = Stack::vip();
+        assert_eq!(stack.ret(), Err(StackError::Underflow));
+        assert_eq!(stack.get_pointer(), 0);
+    }
+
+    #[test]
+    fn vip_stack_is_mapped_into_ram() {
+        let stack = Stack::vip();
+        assert_eq!(stack.entries().len(), VIP_DEPTH);
+        assert_eq!(stack.ram_address_of(0), Some(0xEA0));
+        assert_eq!(stack.ram_address_of(3), Some(0xEA6));
+        assert_eq!(Stack::schip().ram_address_of(0), None);
+    }
+}
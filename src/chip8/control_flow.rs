@@ -0,0 +1,454 @@
+use super::instruction::Instruction;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EdgeKind {
+    // the next instruction
+    FallThrough,
+    // 1NNN
+    Jump,
+    // the instruction after the next one, taken by 3XNN, 4XNN, 5XY0, 9XY0, EX9E and EXA1
+    Skip,
+    // 2NNN; the block also falls through to the instruction after the call
+    Call,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Edge {
+    pub target: u16,
+    pub kind: EdgeKind,
+}
+
+/// Instructions that always run one after another.
+#[derive(Debug, Clone)]
+pub struct BasicBlock {
+    pub start: u16,
+    pub instructions: Vec<(u16, Instruction)>,
+    pub successors: Vec<Edge>,
+}
+
+impl BasicBlock {
+    // First address after the block.
+    pub fn end(&self) -> u16 {
+        self.instructions
+            .last()
+            .map_or(self.start, |(address, _)| address.wrapping_add(2))
+    }
+}
+
+/// An FX33 or FX55 that writes into reachable code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SelfModifyingWrite {
+    pub at: u16,
+    // first and last address written
+    pub target: (u16, u16),
+}
+
+/// Control-flow graph of a ROM, found by following every path from the entry
+/// point. Instructions are decoded with the emulator's decoder, so the graph
+/// and the emulator agree on what every opcode does.
+#[derive(Debug, Clone)]
+pub struct ControlFlowGraph {
+    pub entry: u16,
+    pub blocks: BTreeMap<u16, BasicBlock>,
+    // 2NNN targets
+    pub subroutines: BTreeSet<u16>,
+    // BNNN jumps, their target depends on V0 (or VX)
+    pub computed_jumps: Vec<u16>,
+    // jumps, calls and skips to addresses outside the program
+    pub external_targets: Vec<(u16, u16)>,
+    pub self_modifying_writes: Vec<SelfModifyingWrite>,
+    // runs of program bytes no path reaches, as start and end (exclusive);
+    // these are data or dead code
+    pub unreachable: Vec<(u16, u16)>,
+}
+
+impl ControlFlowGraph {
+    /// Builds the graph of `program` loaded at `start`, which is also the entry point.
+    pub fn build(program: &[u8], start: u16) -> Self {
+        let end = start as usize + program.len();
+        let in_program = |address: u16| address >= start && (address as usize) + 1 < end;
+        let fetch = |address: u16| {
+            let offset = (address - start) as usize;
+            Instruction::decode(((program[offset] as u16) << 8) | program[offset + 1] as u16)
+        };
+
+        // find every reachable instruction and where blocks have to start
+        let mut instructions: BTreeMap<u16, Instruction> = BTreeMap::new();
+        let mut leaders: BTreeSet<u16> = BTreeSet::new();
+        let mut subroutines = BTreeSet::new();
+        let mut computed_jumps = Vec::new();
+        let mut external_targets = Vec::new();
+        let mut pending = vec![start];
+        leaders.insert(start);
+        while let Some(address) = pending.pop() {
+            if instructions.contains_key(&address) {
+                continue;
+            }
+            if !in_program(address) {
+                continue;
+            }
+            let instruction = fetch(address);
+            instructions.insert(address, instruction);
+            for edge in successors(address, &instruction) {
+                if !in_program(edge.target) {
+                    external_targets.push((address, edge.target));
+                    continue;
+                }
+                if edge.kind != EdgeKind::FallThrough {
+                    leaders.insert(edge.target);
+                }
+                if edge.kind == EdgeKind::Call {
+                    subroutines.insert(edge.target);
+                }
+                pending.push(edge.target);
+            }
+            match instruction {
+                Instruction::JumpWithOffset(_) => computed_jumps.push(address),
+                // whatever follows a block end starts a new block
+                _ if ends_block(&instruction) => {
+                    leaders.insert(address.wrapping_add(2));
+                }
+                _ => (),
+            }
+        }
+
+        // cut the instructions into blocks
+        let mut blocks: BTreeMap<u16, BasicBlock> = BTreeMap::new();
+        let mut current: Option<BasicBlock> = None;
+        for (address, instruction) in instructions.iter() {
+            let continues = current
+                .as_ref()
+                .is_some_and(|block| block.end() == *address && !leaders.contains(address));
+            if !continues {
+                if let Some(block) = current.take() {
+                    finish_block(&mut blocks, block);
+                }
+                current = Some(BasicBlock {
+                    start: *address,
+                    instructions: Vec::new(),
+                    successors: Vec::new(),
+                });
+            }
+            let block = current.as_mut().unwrap();
+            block.instructions.push((*address, *instruction));
+            if ends_block(instruction) {
+                finish_block(&mut blocks, current.take().unwrap());
+            }
+        }
+        if let Some(block) = current.take() {
+            finish_block(&mut blocks, block);
+        }
+
+        let mut graph = Self {
+            entry: start,
+            blocks,
+            subroutines,
+            computed_jumps,
+            external_targets,
+            self_modifying_writes: Vec::new(),
+            unreachable: Vec::new(),
+        };
+        graph.self_modifying_writes = graph.find_self_modifying_writes(&instructions);
+        graph.unreachable = unreachable_ranges(&instructions, start, end);
+        graph
+    }
+
+    // Follows I through every block, as far as it is a constant, and reports
+    // FX33 and FX55 writes that land on reachable instructions.
+    fn find_self_modifying_writes(
+        &self,
+        instructions: &BTreeMap<u16, Instruction>,
+    ) -> Vec<SelfModifyingWrite> {
+        let is_code = |address: u16| {
+            instructions.contains_key(&address)
+                || (address > 0 && instructions.contains_key(&(address - 1)))
+        };
+        let mut writes = Vec::new();
+        for block in self.blocks.values() {
+            let mut i: Option<u16> = None;
+            for (address, instruction) in block.instructions.iter() {
+                let written = match *instruction {
+                    Instruction::SetIndex(nnn) => {
+                        i = Some(nnn);
+                        None
+                    }
+                    Instruction::AddToIndex(_) | Instruction::FontCharacter(_) => {
+                        i = None;
+                        None
+                    }
+                    Instruction::BinaryCodedDecimal(_) => i.map(|i| (i, i + 2)),
+                    Instruction::Store(x) => {
+                        let written = i.map(|i| (i, i + x as u16));
+                        // where I ends up depends on the load/store quirk
+                        i = None;
+                        written
+                    }
+                    Instruction::Load(_) => {
+                        i = None;
+                        None
+                    }
+                    _ => None,
+                };
+                if let Some((first, last)) = written {
+                    if (first..=last).any(is_code) {
+                        writes.push(SelfModifyingWrite {
+                            at: *address,
+                            target: (first, last),
+                        });
+                    }
+                }
+            }
+        }
+        writes
+    }
+
+    /// The graph in Graphviz DOT, one node per block.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::new();
+        writeln!(dot, "digraph rom {{").unwrap();
+        writeln!(dot, "    node [shape=box, fontname=monospace];").unwrap();
+        for block in self.blocks.values() {
+            let mut label = String::new();
+            if self.subroutines.contains(&block.start) {
+                write!(label, "sub_0x{:04X}:\\l", block.start).unwrap();
+            }
+            for (address, instruction) in block.instructions.iter() {
+                write!(label, "0x{:04X}  {}\\l", address, instruction).unwrap();
+            }
+            let modifies_code = self
+                .self_modifying_writes
+                .iter()
+                .any(|write| block.start <= write.at && write.at < block.end());
+            let style = if block.start == self.entry {
+                ", style=bold"
+            } else if self
+                .computed_jumps
+                .iter()
+                .any(|jump| block.start <= *jump && *jump < block.end())
+            {
+                ", color=red"
+            } else if modifies_code {
+                ", color=orange"
+            } else {
+                ""
+            };
+            writeln!(
+                dot,
+                "    b{:04X} [label=\"{}\"{}];",
+                block.start, label, style
+            )
+            .unwrap();
+            for edge in block.successors.iter() {
+                if !self.blocks.contains_key(&edge.target) {
+                    continue;
+                }
+                let style = match edge.kind {
+                    EdgeKind::FallThrough => "",
+                    EdgeKind::Jump => " [label=jump]",
+                    EdgeKind::Skip => " [label=skip, style=dashed]",
+                    EdgeKind::Call => " [label=call, style=bold]",
+                };
+                writeln!(
+                    dot,
+                    "    b{:04X} -> b{:04X}{};",
+                    block.start, edge.target, style
+                )
+                .unwrap();
+            }
+        }
+        writeln!(dot, "}}").unwrap();
+        dot
+    }
+
+    pub fn print_summary(&self) {
+        println!(
+            "{} blocks, {} subroutines",
+            self.blocks.len(),
+            self.subroutines.len()
+        );
+        for (start, end) in self.unreachable.iter() {
+            println!(
+                "Unreachable: 0x{:04X}-0x{:04X} ({} bytes)",
+                start,
+                end - 1,
+                end - start
+            );
+        }
+        for jump in self.computed_jumps.iter() {
+            println!("Computed jump at 0x{:04X} can't be followed", jump);
+        }
+        for (at, target) in self.external_targets.iter() {
+            println!("0x{:04X} leaves the program for 0x{:04X}", at, target);
+        }
+        for write in self.self_modifying_writes.iter() {
+            println!(
+                "Self-modifying write at 0x{:04X} to 0x{:04X}-0x{:04X}",
+                write.at, write.target.0, write.target.1
+            );
+        }
+    }
+}
+
+fn successors(address: u16, instruction: &Instruction) -> Vec<Edge> {
+    let edge = |target: u16, kind: EdgeKind| Edge { target, kind };
+    let next = address.wrapping_add(2);
+    match *instruction {
+        Instruction::Jump(nnn) => vec![edge(nnn, EdgeKind::Jump)],
+        Instruction::Call(nnn) => {
+            vec![edge(nnn, EdgeKind::Call), edge(next, EdgeKind::FallThrough)]
+        }
+        Instruction::Return | Instruction::JumpWithOffset(_) | Instruction::Unknown(_) => vec![],
+        Instruction::SkipIfEqual(..)
+        | Instruction::SkipIfNotEqual(..)
+        | Instruction::SkipIfRegistersEqual(..)
+        | Instruction::SkipIfRegistersNotEqual(..)
+        | Instruction::SkipIfKey(_)
+        | Instruction::SkipIfNotKey(_) => vec![
+            edge(next, EdgeKind::FallThrough),
+            edge(address.wrapping_add(4), EdgeKind::Skip),
+        ],
+        _ => vec![edge(next, EdgeKind::FallThrough)],
+    }
+}
+
+// Whether the instruction does anything but continue with the next one.
+pub fn ends_block(instruction: &Instruction) -> bool {
+    successors(0, instruction)
+        != [Edge {
+            target: 2,
+            kind: EdgeKind::FallThrough,
+        }]
+}
+
+fn finish_block(blocks: &mut BTreeMap<u16, BasicBlock>, mut block: BasicBlock) {
+    let (address, instruction) = *block.instructions.last().unwrap();
+    block.successors = successors(address, &instruction);
+    blocks.insert(block.start, block);
+}
+
+fn unreachable_ranges(
+    instructions: &BTreeMap<u16, Instruction>,
+    start: u16,
+    end: usize,
+) -> Vec<(u16, u16)> {
+    let mut reached = vec![false; end - start as usize];
+    for address in instructions.keys() {
+        let offset = (address - start) as usize;
+        reached[offset] = true;
+        if let Some(second) = reached.get_mut(offset + 1) {
+            *second = true;
+        }
+    }
+    let mut ranges = Vec::new();
+    let mut range_start: Option<usize> = None;
+    for (offset, reached) in reached.iter().chain(std::iter::once(&true)).enumerate() {
+        match (*reached, range_start) {
+            (false, None) => range_start = Some(offset),
+            (true, Some(first)) => {
+                ranges.push((start + first as u16, start + offset as u16));
+                range_start = None;
+            }
+            _ => (),
+        }
+    }
+    ranges
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 0x200 2208  CALL 208
+    // 0x202 3000  SE V0, 00
+    // 0x204 1200  JP 200
+    // 0x206 1206  JP 206
+    // 0x208 A204  LD I, 204
+    // 0x20A F055  LD [I], V0    overwrites the JP at 204
+    // 0x20C 00EE  RET
+    // 0x20E ABCD  data
+    const PROGRAM: [u8; 16] = [
+        0x22, 0x08, 0x30, 0x00, 0x12, 0x00, 0x12, 0x06, 0xA2, 0x04, 0xF0, 0x55, 0x00, 0xEE, 0xAB,
+        0xCD,
+    ];
+
+    fn edge(target: u16, kind: EdgeKind) -> Edge {
+        Edge { target, kind }
+    }
+
+    #[test]
+    fn splits_blocks_at_branches() {
+        let graph = ControlFlowGraph::build(&PROGRAM, 0x200);
+        assert_eq!(
+            graph.blocks.keys().copied().collect::<Vec<_>>(),
+            [0x200, 0x202, 0x204, 0x206, 0x208]
+        );
+        let successors = |start: u16| graph.blocks[&start].successors.clone();
+        assert_eq!(
+            successors(0x200),
+            [
+                edge(0x208, EdgeKind::Call),
+                edge(0x202, EdgeKind::FallThrough)
+            ]
+        );
+        assert_eq!(
+            successors(0x202),
+            [
+                edge(0x204, EdgeKind::FallThrough),
+                edge(0x206, EdgeKind::Skip)
+            ]
+        );
+        assert_eq!(successors(0x204), [edge(0x200, EdgeKind::Jump)]);
+        assert_eq!(successors(0x206), [edge(0x206, EdgeKind::Jump)]);
+        assert_eq!(successors(0x208), []);
+        assert_eq!(graph.blocks[&0x208].instructions.len(), 3);
+        assert_eq!(graph.blocks[&0x208].end(), 0x20E);
+        assert_eq!(graph.subroutines, BTreeSet::from([0x208]));
+    }
+
+    #[test]
+    fn finds_unreachable_bytes_and_self_modifying_writes() {
+        let graph = ControlFlowGraph::build(&PROGRAM, 0x200);
+        assert_eq!(graph.unreachable, [(0x20E, 0x210)]);
+        assert_eq!(
+            graph.self_modifying_writes,
+            [SelfModifyingWrite {
+                at: 0x20A,
+                target: (0x204, 0x204),
+            }]
+        );
+        assert!(graph.computed_jumps.is_empty());
+        assert!(graph.external_targets.is_empty());
+    }
+
+    #[test]
+    fn reports_what_it_cannot_follow() {
+        // 0x200 4000 SNE V0, 00, 0x202 B210 JP V0, 210, 0x204 1300 JP 300
+        let graph = ControlFlowGraph::build(&[0x40, 0x00, 0xB2, 0x10, 0x13, 0x00], 0x200);
+        assert_eq!(graph.computed_jumps, [0x202]);
+        assert_eq!(graph.external_targets, [(0x204, 0x300)]);
+        assert!(graph.unreachable.is_empty());
+    }
+
+    #[test]
+    fn writes_dot() {
+        let dot = ControlFlowGraph::build(&PROGRAM, 0x200).to_dot();
+        assert!(dot.starts_with("digraph rom {\n"));
+        assert!(dot.contains("    b0200 [label=\"0x0200  "));
+        assert!(dot.contains("sub_0x0208:\\l"));
+        assert!(dot.contains("    b0200 -> b0208 [label=call, style=bold];\n"));
+        assert!(dot.contains("    b0202 -> b0206 [label=skip, style=dashed];\n"));
+        assert!(dot.contains("    b0202 -> b0204;\n"));
+        assert!(dot.ends_with("}\n"));
+    }
+
+    #[test]
+    fn blocks_end_at_control_flow() {
+        assert!(ends_block(&Instruction::decode(0x1200)));
+        assert!(ends_block(&Instruction::decode(0x00EE)));
+        assert!(ends_block(&Instruction::decode(0xE09E)));
+        assert!(!ends_block(&Instruction::decode(0x6005)));
+        assert!(!ends_block(&Instruction::decode(0xD125)));
+    }
+}
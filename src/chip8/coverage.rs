@@ -0,0 +1,346 @@
+use super::chip8_mods::memory::{Access, AccessKind, Memory};
+use super::instruction::Instruction;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ByteCoverage {
+    // fetched as the first byte of an instruction
+    pub executed: u32,
+    // read as data, by DXYN sprites and FX65 loads
+    pub read: u32,
+}
+
+/// Which bytes of memory ran as instructions and which were read as data.
+/// The counters are filled by a memory hook, so they are shared with it.
+pub struct Coverage {
+    bytes: Arc<Mutex<Vec<ByteCoverage>>>,
+    rom_name: String,
+    program_start: usize,
+    program_len: usize,
+}
+
+impl Coverage {
+    pub fn new(
+        memory_size: usize,
+        rom_name: &str,
+        program_start: usize,
+        program_len: usize,
+    ) -> Self {
+        Self {
+            bytes: Arc::new(Mutex::new(vec![ByteCoverage::default(); memory_size])),
+            rom_name: rom_name.to_string(),
+            program_start,
+            program_len,
+        }
+    }
+
+    /// A memory hook counting fetches and reads into this coverage.
+    pub fn hook(&self) -> impl FnMut(&mut Access) + Send + 'static {
+        let bytes = Arc::clone(&self.bytes);
+        // the fetch of an instruction's second byte shouldn't count as an instruction
+        let mut last_fetch: Option<usize> = None;
+        move |access: &mut Access| {
+            let mut bytes = bytes.lock().unwrap();
+            // the byte before, wrapping around like the addresses of instructions
+            let previous = (access.address + bytes.len() - 1) % bytes.len();
+            let byte = match bytes.get_mut(access.address) {
+                Some(byte) => byte,
+                None => return,
+            };
+            match access.kind {
+                AccessKind::Fetch => {
+                    if last_fetch.take() != Some(previous) {
+                        byte.executed += 1;
+                        last_fetch = Some(access.address);
+                    }
+                }
+                AccessKind::Read => byte.read += 1,
+                AccessKind::Write => (),
+            }
+        }
+    }
+
+    pub fn byte(&self, address: usize) -> ByteCoverage {
+        self.bytes
+            .lock()
+            .unwrap()
+            .get(address)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub fn is_code(&self, address: usize) -> bool {
+        self.byte(address).executed > 0
+    }
+
+    pub fn is_data(&self, address: usize) -> bool {
+        self.byte(address).read > 0
+    }
+
+    /// Splits the program into annotated lines: executed instructions, data
+    /// bytes and runs of bytes that were never touched.
+    pub fn lines(&self, memory: &Memory) -> Vec<CoverageLine> {
+        let end = (self.program_start + self.program_len).min(memory.size());
+        let bytes = self.bytes.lock().unwrap();
+        let mut lines = Vec::new();
+        let mut address = self.program_start;
+        while address < end {
+            let byte = bytes[address];
+            if byte.executed > 0 && address + 1 < end {
+                let opcode = ((memory.peek(address) as u16) << 8) | memory.peek(address + 1) as u16;
+                lines.push(CoverageLine::Instruction {
+                    address,
+                    opcode,
+                    executed: byte.executed,
+                    // code that is also read, e.g. sprites sharing bytes with instructions
+                    read: byte.read + bytes[address + 1].read,
+                });
+                address += 2;
+            } else if byte.read > 0 {
+                lines.push(CoverageLine::Data {
+                    address,
+                    value: memory.peek(address),
+                    read: byte.read,
+                });
+                address += 1;
+            } else {
+                let start = address;
+                while address < end && bytes[address].executed == 0 && bytes[address].read == 0 {
+                    address += 1;
+                }
+                lines.push(CoverageLine::Untouched {
+                    start,
+                    len: address - start,
+                });
+            }
+        }
+        lines
+    }
+
+    // Writes `<prefix>.lst`, an annotated disassembly, and `<prefix>.info`, an
+    // lcov tracefile whose line numbers refer to the disassembly.
+    pub fn write_reports(&self, prefix: &str, memory: &Memory) -> io::Result<()> {
+        let listing_path = format!("{}.lst", prefix);
+        let lines = self.lines(memory);
+
+        let mut listing = BufWriter::new(File::create(&listing_path)?);
+        writeln!(listing, "; coverage of {}", self.rom_name)?;
+        for line in lines.iter() {
+            writeln!(listing, "{}", line)?;
+        }
+        listing.flush()?;
+
+        // instructions count as covered lines, untouched bytes as uncovered ones
+        // and data isn't code at all
+        let mut lcov = BufWriter::new(File::create(format!("{}.info", prefix))?);
+        writeln!(lcov, "TN:")?;
+        writeln!(lcov, "SF:{}", listing_path)?;
+        let mut found = 0;
+        let mut hit = 0;
+        for (index, line) in lines.iter().enumerate() {
+            // the header is the first line of the listing
+            let number = index + 2;
+            match line {
+                CoverageLine::Instruction { executed, .. } => {
+                    writeln!(lcov, "DA:{},{}", number, executed)?;
+                    found += 1;
+                    hit += 1;
+                }
+                CoverageLine::Untouched { .. } => {
+                    writeln!(lcov, "DA:{},0", number)?;
+                    found += 1;
+                }
+                CoverageLine::Data { .. } => (),
+            }
+        }
+        writeln!(lcov, "LF:{}", found)?;
+        writeln!(lcov, "LH:{}", hit)?;
+        writeln!(lcov, "end_of_record")?;
+        lcov.flush()
+    }
+
+    pub fn print_summary(&self) {
+        let bytes = self.bytes.lock().unwrap();
+        let end = (self.program_start + self.program_len).min(bytes.len());
+        let mut code = vec![false; end - self.program_start];
+        for (offset, byte) in bytes[self.program_start..end].iter().enumerate() {
+            if byte.executed > 0 {
+                code[offset] = true;
+                if let Some(second) = code.get_mut(offset + 1) {
+                    *second = true;
+                }
+            }
+        }
+        let data = bytes[self.program_start..end]
+            .iter()
+            .zip(code.iter())
+            .filter(|(byte, is_code)| byte.read > 0 && !**is_code)
+            .count();
+        println!(
+            "Coverage of {}: {} of {} bytes executed, {} read as data",
+            self.rom_name,
+            code.iter().filter(|is_code| **is_code).count(),
+            code.len(),
+            data
+        );
+    }
+}
+
+pub enum CoverageLine {
+    Instruction {
+        address: usize,
+        opcode: u16,
+        executed: u32,
+        read: u32,
+    },
+    Data {
+        address: usize,
+        value: u8,
+        read: u32,
+    },
+    Untouched {
+        start: usize,
+        len: usize,
+    },
+}
+
+impl std::fmt::Display for CoverageLine {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CoverageLine::Instruction {
+                address,
+                opcode,
+                executed,
+                read,
+            } => {
+                let instruction = Instruction::decode(*opcode).to_string();
+                write!(
+                    f,
+                    "0x{:04X}  {:04X}  {:<18} ; executed {}",
+                    address, opcode, instruction, executed
+                )?;
+                if *read > 0 {
+                    write!(f, ", read {}", read)?;
+                }
+                Ok(())
+            }
+            CoverageLine::Data {
+                address,
+                value,
+                read,
+            } => write!(
+                f,
+                "0x{:04X}  {:02X}    {:<18} ; read {}",
+                address,
+                value,
+                format!("DB 0x{:02X}", value),
+                read
+            ),
+            CoverageLine::Untouched { start, len } => write!(
+                f,
+                "0x{:04X}  ..    {:<18} ; untouched, {} bytes",
+                start, "", len
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::chip8_mods::memory::Layout;
+    use crate::chip8::rom::Rom;
+    use crate::chip8::Chip8;
+
+    // 0x200 A206  LD I, 0x206
+    // 0x202 D011  DRW V0, V1, 1
+    // 0x204 1204  JP 0x204
+    // 0x206 80    sprite row
+    // 0x207 00    never used
+    const PROGRAM: [u8; 8] = [0xA2, 0x06, 0xD0, 0x11, 0x12, 0x04, 0x80, 0x00];
+
+    fn covered(program: &[u8], steps: usize) -> Chip8 {
+        let rom = Rom::from_bytes("test.ch8", program);
+        let mut chip8 = Chip8::from_rom(&rom, Layout::Chip8).unwrap();
+        chip8.start_coverage(&rom, "unused");
+        for _ in 0..steps {
+            chip8.step().unwrap();
+        }
+        chip8
+    }
+
+    fn coverage(chip8: &Chip8) -> &Coverage {
+        &chip8.coverage.as_ref().unwrap().0
+    }
+
+    #[test]
+    fn counts_instructions_and_data() {
+        let chip8 = covered(&PROGRAM, 5);
+        let coverage = coverage(&chip8);
+        let executed: Vec<u32> = (0x200..0x208)
+            .map(|address| coverage.byte(address).executed)
+            .collect();
+        assert_eq!(executed, [1, 0, 1, 0, 3, 0, 0, 0]);
+        let read: Vec<u32> = (0x200..0x208)
+            .map(|address| coverage.byte(address).read)
+            .collect();
+        assert_eq!(read, [0, 0, 0, 0, 0, 0, 1, 0]);
+        assert!(coverage.is_code(0x204) && !coverage.is_code(0x205));
+        assert!(coverage.is_data(0x206) && !coverage.is_data(0x207));
+    }
+
+    #[test]
+    fn counts_a_self_jump_every_time() {
+        // 0x200 1200  JP 0x200
+        let chip8 = covered(&[0x12, 0x00], 4);
+        assert_eq!(coverage(&chip8).byte(0x200).executed, 4);
+        assert_eq!(coverage(&chip8).byte(0x201).executed, 0);
+    }
+
+    #[test]
+    fn instructions_wrap_around_memory() {
+        // 0x200 1FFF  JP 0xFFF, where 61 and the F0 of the font at 0x000 are LD V1, 0xF0
+        let mut chip8 = covered(&[0x1F, 0xFF], 0);
+        chip8.memory.poke(0xFFF, 0x61);
+        for _ in 0..3 {
+            chip8.step().unwrap();
+        }
+        let coverage = coverage(&chip8);
+        assert_eq!(coverage.byte(0xFFF).executed, 1);
+        assert_eq!(coverage.byte(0x000).executed, 0);
+        // the next instruction starts at 0x001
+        assert_eq!(coverage.byte(0x001).executed, 1);
+    }
+
+    #[test]
+    fn writes_listings_and_lcov() {
+        let chip8 = covered(&PROGRAM, 5);
+        let prefix = std::env::temp_dir().join(format!("rs_chip8-{}-coverage", std::process::id()));
+        let prefix = prefix.to_str().unwrap();
+        coverage(&chip8)
+            .write_reports(prefix, &chip8.memory)
+            .unwrap();
+
+        let listing = std::fs::read_to_string(format!("{}.lst", prefix)).unwrap();
+        let listing: Vec<&str> = listing.lines().collect();
+        assert_eq!(listing.len(), 6);
+        assert_eq!(listing[0], "; coverage of test.ch8");
+        assert_eq!(listing[3], "0x0204  1204  JP 0x204           ; executed 3");
+        assert_eq!(listing[4], "0x0206  80    DB 0x80            ; read 1");
+        assert_eq!(
+            listing[5],
+            "0x0207  ..                       ; untouched, 1 bytes"
+        );
+
+        let lcov = std::fs::read_to_string(format!("{}.info", prefix)).unwrap();
+        let expected = format!(
+            "TN:\nSF:{}.lst\nDA:2,1\nDA:3,1\nDA:4,3\nDA:6,0\nLF:4\nLH:3\nend_of_record\n",
+            prefix
+        );
+        assert_eq!(lcov, expected);
+        std::fs::remove_file(format!("{}.lst", prefix)).unwrap();
+        std::fs::remove_file(format!("{}.info", prefix)).unwrap();
+    }
+}
@@ -0,0 +1,230 @@
+use super::chip8_mods::keypad::Keymap;
+use super::palette;
+use super::quirks::{QuirkOverrides, Quirks};
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+
+// ROMs known without a database file, in the format of the community chip-8-database.
+const BUNDLED_PROGRAMS: &str = include_str!("../../database/programs.json");
+
+/// What the database knows about one ROM.
+#[derive(Debug, Clone)]
+pub struct RomInfo {
+    pub title: String,
+    pub authors: Vec<String>,
+    // chip-8-database platform id, e.g. `originalChip8`
+    pub platform: Option<String>,
+    pub quirks: Option<Quirks>,
+    // instructions per frame
+    pub tickrate: Option<u32>,
+    pub colors: [Option<[u8; 3]>; 4],
+    pub keymap: Keymap,
+}
+
+/// ROM metadata keyed by the SHA-1 of the ROM, read from the `programs.json`
+/// of the chip-8-database (https://github.com/chip-8/chip-8-database).
+pub struct Database {
+    roms: HashMap<String, RomInfo>,
+}
+
+impl Database {
+    pub fn new() -> Self {
+        Self {
+            roms: HashMap::new(),
+        }
+    }
+
+    pub fn bundled() -> Self {
+        Self::from_json(BUNDLED_PROGRAMS).expect("The bundled ROM database is invalid.")
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, serde_json::Error> {
+        let programs: Vec<serde_json::Value> = serde_json::from_str(json)?;
+        let mut database = Self::new();
+        for program in programs.iter() {
+            let title = program
+                .get("title")
+                .and_then(|title| title.as_str())
+                .unwrap_or_default();
+            let authors: Vec<String> = program
+                .get("authors")
+                .and_then(|authors| authors.as_array())
+                .map(|authors| {
+                    authors
+                        .iter()
+                        .filter_map(|author| author.as_str())
+                        .map(|author| author.to_string())
+                        .collect()
+                })
+                .unwrap_or_default();
+            let roms = match program.get("roms").and_then(|roms| roms.as_object()) {
+                Some(roms) => roms,
+                None => continue,
+            };
+            for (hash, rom) in roms.iter() {
+                database.roms.insert(
+                    hash.to_ascii_lowercase(),
+                    parse_rom(title, &authors, rom),
+                );
+            }
+        }
+        Ok(database)
+    }
+
+    // Entries of `other` win over entries with the same hash.
+    pub fn merge(&mut self, other: Database) {
+        self.roms.extend(other.roms);
+    }
+
+    pub fn lookup(&self, rom: &[u8]) -> Option<&RomInfo> {
+        self.roms.get(&sha1_hex(rom))
+    }
+}
+
+impl Default for Database {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub fn sha1_hex(bytes: &[u8]) -> String {
+    Sha1::digest(bytes)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn parse_rom(title: &str, authors: &[String], rom: &serde_json::Value) -> RomInfo {
+    // the first platform is the one the ROM was written for
+    let platform = rom
+        .get("platforms")
+        .and_then(|platforms| platforms.get(0))
+        .and_then(|platform| platform.as_str())
+        .map(|platform| platform.to_string());
+
+    let mut quirks = platform.as_deref().and_then(Quirks::for_platform);
+    if let (Some(platform), Some(quirks)) = (platform.as_deref(), quirks.as_mut()) {
+        if let Some(overrides) = rom.get("quirkyPlatforms").and_then(|quirky| quirky.get(platform)) {
+            QuirkOverrides::from_json(overrides).apply(quirks);
+        }
+    }
+
+    let mut colors = [None; 4];
+    if let Some(pixels) = rom
+        .get("colors")
+        .and_then(|colors| colors.get("pixels"))
+        .and_then(|pixels| pixels.as_array())
+    {
+        for (color, pixel) in colors.iter_mut().zip(pixels.iter()) {
+            *color = pixel.as_str().and_then(palette::parse_hex_color);
+        }
+    }
+
+    RomInfo {
+        title: title.to_string(),
+        authors: authors.to_vec(),
+        platform,
+        quirks,
+        tickrate: rom
+            .get("tickrate")
+            .and_then(|tickrate| tickrate.as_u64())
+            .map(|tickrate| tickrate as u32),
+        colors,
+        keymap: rom
+            .get("keys")
+            .map(Keymap::from_json)
+            .unwrap_or_default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROM: &[u8] = &[0x12, 0x00];
+
+    fn database(rom: &str) -> Database {
+        let json = format!(
+            r#"[{{"title": "Jumper", "authors": ["A", "B"], "roms": {{"{}": {}}}}}]"#,
+            sha1_hex(ROM).to_uppercase(),
+            rom
+        );
+        Database::from_json(&json).unwrap()
+    }
+
+    #[test]
+    fn hashes_with_sha1() {
+        assert_eq!(sha1_hex(b"abc"), "a9993e364706816aba3e25717850c26c9cd0d89d");
+    }
+
+    #[test]
+    fn looks_roms_up_by_hash() {
+        let database = database(
+            r##"{
+                "platforms": ["superchip", "xochip"],
+                "tickrate": 30,
+                "colors": {"pixels": ["#000000", "#FF8000"]},
+                "keys": {"up": 5, "a": 16}
+            }"##,
+        );
+        assert!(database.lookup(&[0x00, 0xE0]).is_none());
+        let info = database.lookup(ROM).unwrap();
+        assert_eq!(info.title, "Jumper");
+        assert_eq!(info.authors, ["A", "B"]);
+        assert_eq!(info.platform.as_deref(), Some("superchip"));
+        assert_eq!(info.quirks, Quirks::preset("schip"));
+        assert_eq!(info.tickrate, Some(30));
+        assert_eq!(
+            info.colors,
+            [Some([0, 0, 0]), Some([0xFF, 0x80, 0]), None, None]
+        );
+        assert_eq!(info.keymap.up, Some(5));
+        // not a key
+        assert_eq!(info.keymap.a, None);
+    }
+
+    #[test]
+    fn applies_quirky_platforms() {
+        let database = database(
+            r#"{
+                "platforms": ["originalChip8"],
+                "quirkyPlatforms": {"originalChip8": {"shift": true, "vblank": false}}
+            }"#,
+        );
+        let quirks = database.lookup(ROM).unwrap().quirks.unwrap();
+        assert_eq!(
+            quirks,
+            Quirks {
+                shift: true,
+                vblank: false,
+                ..Quirks::preset("vip").unwrap()
+            }
+        );
+    }
+
+    #[test]
+    fn unknown_platforms_have_no_quirks() {
+        let megachip = database(r#"{"platforms": ["megachip8"]}"#);
+        assert_eq!(megachip.lookup(ROM).unwrap().quirks, None);
+        let unknown = database("{}");
+        assert_eq!(unknown.lookup(ROM).unwrap().platform, None);
+    }
+
+    #[test]
+    fn merged_entries_win() {
+        let mut database = database(r#"{"tickrate": 10}"#);
+        database.merge(self::database(r#"{"tickrate": 20}"#));
+        assert_eq!(database.lookup(ROM).unwrap().tickrate, Some(20));
+    }
+
+    #[test]
+    fn reads_the_bundled_database() {
+        let database = Database::bundled();
+        let ibm = database
+            .roms
+            .get("1ba58656810b67fd131eb9af3e3987863bf26c90")
+            .unwrap();
+        assert_eq!(ibm.title, "IBM Logo");
+        assert!(Database::from_json("{}").is_err());
+    }
+}
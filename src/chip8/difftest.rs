@@ -0,0 +1,312 @@
+use super::instruction::Instruction;
+use super::trace::TraceLine;
+use super::Chip8;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+
+// Matching instructions shown before the one that diverged.
+const CONTEXT_LINES: usize = 5;
+
+/// One line of a reference trace in the format written by `--trace`.
+/// Fields other emulators can't provide may be left out, they aren't compared.
+#[derive(Debug, Default)]
+struct ReferenceLine {
+    number: usize,
+    text: String,
+    pc: Option<u16>,
+    opcode: Option<u16>,
+    registers: Option<[u8; 16]>,
+    i: Option<u16>,
+    sp: Option<usize>,
+    delay_timer: Option<u8>,
+    sound_timer: Option<u8>,
+    framebuffer: Option<u64>,
+}
+
+impl ReferenceLine {
+    // `<cycle> 0x<pc> <opcode> <disassembly> V=<16 bytes> I=.. SP=.. DT=.. ST=.. FB=..`
+    fn parse(number: usize, text: &str) -> Option<Self> {
+        let mut line = Self {
+            number,
+            text: text.to_string(),
+            ..Self::default()
+        };
+        let mut tokens = text.split_whitespace();
+        let _cycle = tokens.next()?;
+        line.pc = Some(parse_hex(tokens.next()?)? as u16);
+        line.opcode = Some(parse_hex(tokens.next()?)? as u16);
+
+        while let Some(token) = tokens.next() {
+            let (key, value) = match token.split_once('=') {
+                Some(pair) => pair,
+                // part of the disassembly
+                None => continue,
+            };
+            match key {
+                "V" => {
+                    let mut registers = [0; 16];
+                    registers[0] = parse_hex(value)? as u8;
+                    for register in registers.iter_mut().skip(1) {
+                        *register = parse_hex(tokens.next()?)? as u8;
+                    }
+                    line.registers = Some(registers);
+                }
+                "I" => line.i = Some(parse_hex(value)? as u16),
+                "SP" => line.sp = Some(value.parse().ok()?),
+                "DT" => line.delay_timer = Some(parse_hex(value)? as u8),
+                "ST" => line.sound_timer = Some(parse_hex(value)? as u8),
+                "FB" => line.framebuffer = Some(parse_hex(value)?),
+                _ => (),
+            }
+        }
+        Some(line)
+    }
+
+    // Every compared field as (name, reference, ours), formatted for the report.
+    fn fields(&self, ours: &TraceLine) -> Vec<(String, Option<String>, String)> {
+        let mut fields = vec![
+            (
+                "PC".to_string(),
+                self.pc.map(|pc| format!("0x{:04X}", pc)),
+                format!("0x{:04X}", ours.pc),
+            ),
+            (
+                "opcode".to_string(),
+                self.opcode
+                    .map(|opcode| format!("{:04X} {}", opcode, Instruction::decode(opcode))),
+                format!("{:04X} {}", ours.opcode, Instruction::decode(ours.opcode)),
+            ),
+        ];
+        for (index, register) in ours.registers.iter().enumerate() {
+            fields.push((
+                format!("V{:X}", index),
+                self.registers.map(|registers| format!("{:02X}", registers[index])),
+                format!("{:02X}", register),
+            ));
+        }
+        fields.push((
+            "I".to_string(),
+            self.i.map(|i| format!("0x{:04X}", i)),
+            format!("0x{:04X}", ours.i),
+        ));
+        fields.push((
+            "SP".to_string(),
+            self.sp.map(|sp| format!("{:02}", sp)),
+            format!("{:02}", ours.sp),
+        ));
+        fields.push((
+            "DT".to_string(),
+            self.delay_timer.map(|timer| format!("{:02X}", timer)),
+            format!("{:02X}", ours.delay_timer),
+        ));
+        fields.push((
+            "ST".to_string(),
+            self.sound_timer.map(|timer| format!("{:02X}", timer)),
+            format!("{:02X}", ours.sound_timer),
+        ));
+        fields.push((
+            "FB".to_string(),
+            self.framebuffer.map(|hash| format!("{:016X}", hash)),
+            format!("{:016X}", ours.framebuffer),
+        ));
+        fields
+    }
+
+    fn matches(&self, ours: &TraceLine) -> bool {
+        self.fields(ours)
+            .iter()
+            .all(|(_, reference, ours)| reference.as_ref().is_none_or(|value| value == ours))
+    }
+}
+
+fn parse_hex(text: &str) -> Option<u64> {
+    u64::from_str_radix(text.trim_start_matches("0x"), 16).ok()
+}
+
+/// Runs the emulator in lockstep with a reference trace and stops at the first
+/// instruction whose state differs. Returns whether the whole trace matched.
+pub fn run(mut chip8: Chip8, reference_path: &str) -> io::Result<bool> {
+    let reader = BufReader::new(File::open(reference_path)?);
+    let mut reference = Vec::new();
+    for (index, text) in reader.lines().enumerate() {
+        let text = text?;
+        if text.trim().is_empty() || text.starts_with('#') {
+            continue;
+        }
+        match ReferenceLine::parse(index + 1, &text) {
+            Some(line) => reference.push(line),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}:{} is not a trace line", reference_path, index + 1),
+                ))
+            }
+        }
+    }
+
+    let mut lines = reference.iter();
+    let mut context: VecDeque<&ReferenceLine> = VecDeque::with_capacity(CONTEXT_LINES);
+    let mut divergence: Option<(&ReferenceLine, TraceLine)> = None;
+    let mut finished = false;
+    while !finished && divergence.is_none() {
+        chip8.run_frame_checked(|chip8| {
+            let expected = match lines.next() {
+                Some(expected) => expected,
+                None => {
+                    finished = true;
+                    return false;
+                }
+            };
+            let ours = chip8.trace_line();
+            if !expected.matches(&ours) {
+                divergence = Some((expected, ours));
+                return false;
+            }
+            if context.len() == CONTEXT_LINES {
+                context.pop_front();
+            }
+            context.push_back(expected);
+            true
+        });
+        if let Some(fault) = chip8.fault() {
+            println!("The emulator stopped: {}", fault);
+            break;
+        }
+    }
+    chip8.shut_down();
+
+    match divergence {
+        // the reference went on after our fault
+        None if !finished => Ok(false),
+        None => {
+            println!("Matched all {} instructions of {}", reference.len(), reference_path);
+            Ok(true)
+        }
+        Some((expected, ours)) => {
+            print_report(reference_path, &context, expected, &ours);
+            Ok(false)
+        }
+    }
+}
+
+fn print_report(
+    reference_path: &str,
+    context: &VecDeque<&ReferenceLine>,
+    expected: &ReferenceLine,
+    ours: &TraceLine,
+) {
+    println!(
+        "Diverged from {} at instruction {} (line {})",
+        reference_path, ours.cycle, expected.number
+    );
+    println!();
+    for line in context.iter() {
+        println!("  {}", line.text);
+    }
+    println!("> {}", expected.text);
+    println!();
+    println!("{:<8} {:<26} {:<26}", "", "reference", "emulator");
+    for (name, reference, ours) in expected.fields(ours) {
+        let (reference, marker) = match reference {
+            Some(reference) if reference != ours => (reference, "<<"),
+            Some(reference) => (reference, ""),
+            None => ("-".to_string(), ""),
+        };
+        println!("{:<8} {:<26} {:<26} {}", name, reference, ours, marker);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::chip8_mods::memory::Layout;
+    use crate::chip8::rom::Rom;
+    use crate::chip8::trace::TraceFilter;
+    use std::fs;
+    use std::path::PathBuf;
+
+    // 6005 LD V0, 05, 7001 ADD V0, 01, 1202 JP 202
+    const PROGRAM: [u8; 6] = [0x60, 0x05, 0x70, 0x01, 0x12, 0x02];
+
+    fn chip8() -> Chip8 {
+        Chip8::from_rom(&Rom::from_bytes("test.ch8", &PROGRAM), Layout::Chip8).unwrap()
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("rs_chip8-{}-{}", std::process::id(), name))
+    }
+
+    // A trace of the first frame of PROGRAM, as written by --trace.
+    fn reference(name: &str) -> PathBuf {
+        let path = temp_path(name);
+        let mut chip8 = chip8();
+        chip8.start_tracing(path.to_str().unwrap(), TraceFilter::default());
+        chip8.run_frame().unwrap();
+        chip8.shut_down();
+        path
+    }
+
+    #[test]
+    fn parses_trace_lines() {
+        let text = "0000000002 0x0204 1202 JP 0x202           V=06 00 00 00 00 00 00 00 00 00 00 00 00 00 00 0A I=0x0000 SP=00 DT=00 ST=3C FB=0123456789ABCDEF";
+        let line = ReferenceLine::parse(3, text).unwrap();
+        assert_eq!(line.number, 3);
+        assert_eq!(line.pc, Some(0x204));
+        assert_eq!(line.opcode, Some(0x1202));
+        let registers = line.registers.unwrap();
+        assert_eq!((registers[0], registers[15]), (0x06, 0x0A));
+        assert_eq!(line.i, Some(0));
+        assert_eq!(line.sp, Some(0));
+        assert_eq!(line.sound_timer, Some(0x3C));
+        assert_eq!(line.framebuffer, Some(0x0123456789ABCDEF));
+    }
+
+    #[test]
+    fn leaves_out_missing_fields() {
+        let line = ReferenceLine::parse(1, "7 0x0200 6005 I=0x0010").unwrap();
+        assert_eq!(line.pc, Some(0x200));
+        assert_eq!(line.i, Some(0x10));
+        assert_eq!(line.registers, None);
+        assert_eq!(line.framebuffer, None);
+
+        assert!(ReferenceLine::parse(1, "7 0x0200").is_none());
+        assert!(ReferenceLine::parse(1, "7 pc 6005").is_none());
+        // too few registers
+        assert!(ReferenceLine::parse(1, "7 0x0200 6005 V=00 01").is_none());
+    }
+
+    #[test]
+    fn compares_only_given_fields() {
+        let mut chip8 = chip8();
+        chip8.step().unwrap();
+        let ours = chip8.trace_line();
+        let line = |text| ReferenceLine::parse(1, text).unwrap();
+        assert!(line("0 0x0200 6005").matches(&ours));
+        assert!(!line("0 0x0202 6005").matches(&ours));
+        assert!(!line("0 0x0200 6005 I=0x0001").matches(&ours));
+    }
+
+    #[test]
+    fn matches_its_own_trace() {
+        let path = reference("matching.trace");
+        assert!(run(chip8(), path.to_str().unwrap()).unwrap());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn stops_where_the_trace_diverges() {
+        let path = reference("diverging.trace");
+        // pretend the reference added 2 to V0 on its third instruction
+        let trace = fs::read_to_string(&path).unwrap();
+        let mut lines: Vec<String> = trace.lines().map(|line| line.to_string()).collect();
+        lines[3] = lines[3].replace("V=06", "V=07");
+        fs::write(&path, lines.join("\n")).unwrap();
+        assert!(!run(chip8(), path.to_str().unwrap()).unwrap());
+
+        fs::write(&path, "# comment\n\nnot a trace line\n").unwrap();
+        let err = run(chip8(), path.to_str().unwrap()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,31 @@
+use super::chip8_mods::memory::MemoryFault;
+use super::chip8_mods::stack::StackError;
+use std::fmt;
+
+/// Why the machine stopped. A faulted machine stays at the instruction that
+/// faulted and runs no further until a save state is loaded.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Fault {
+    // the instruction that faulted
+    pub address: u16,
+    pub kind: FaultKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FaultKind {
+    Stack(StackError),
+    // a write into a write-protected region
+    Memory(MemoryFault),
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            FaultKind::Stack(err) => write!(f, "{}", err)?,
+            FaultKind::Memory(err) => write!(f, "{}", err)?,
+        }
+        write!(f, " (at {:03X})", self.address)
+    }
+}
+
+impl std::error::Error for Fault {}
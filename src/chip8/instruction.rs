@@ -0,0 +1,518 @@
+use std::fmt;
+
+/// A decoded CHIP-8 instruction. Decoding doesn't depend on quirks or machine
+/// state, so it can be used for disassembly and analysis as well as execution.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    // 00E0
+    Clear,
+    // 00EE
+    Return,
+    // 0NNN, machine code routines of the original interpreter
+    System(u16),
+    // 1NNN
+    Jump(u16),
+    // 2NNN
+    Call(u16),
+    // 3XNN
+    SkipIfEqual(u8, u8),
+    // 4XNN
+    SkipIfNotEqual(u8, u8),
+    // 5XY0
+    SkipIfRegistersEqual(u8, u8),
+    // 6XNN
+    Set(u8, u8),
+    // 7XNN
+    Add(u8, u8),
+    // 8XY0
+    Copy(u8, u8),
+    // 8XY1
+    Or(u8, u8),
+    // 8XY2
+    And(u8, u8),
+    // 8XY3
+    Xor(u8, u8),
+    // 8XY4
+    AddRegisters(u8, u8),
+    // 8XY5
+    Subtract(u8, u8),
+    // 8XY6
+    ShiftRight(u8, u8),
+    // 8XY7
+    SubtractReversed(u8, u8),
+    // 8XYE
+    ShiftLeft(u8, u8),
+    // 9XY0
+    SkipIfRegistersNotEqual(u8, u8),
+    // ANNN
+    SetIndex(u16),
+    // BNNN
+    JumpWithOffset(u16),
+    // CXNN
+    Random(u8, u8),
+    // DXYN
+    Draw(u8, u8, u8),
+    // EX9E
+    SkipIfKey(u8),
+    // EXA1
+    SkipIfNotKey(u8),
+    // FX07
+    GetDelayTimer(u8),
+    // FX0A
+    WaitForKey(u8),
+    // FX15
+    SetDelayTimer(u8),
+    // FX18
+    SetSoundTimer(u8),
+    // FX1E
+    AddToIndex(u8),
+    // FX29
+    FontCharacter(u8),
+    // FX33
+    BinaryCodedDecimal(u8),
+    // FX55
+    Store(u8),
+    // FX65
+    Load(u8),
+    Unknown(u16),
+}
+
+/// Groups of instructions, e.g. to filter traces.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OpcodeClass {
+    // jumps, calls and returns
+    Flow,
+    // conditional skips on registers
+    Skip,
+    // 6XNN, 7XNN and 8XY0
+    Register,
+    // arithmetic and logic 8XYN
+    Alu,
+    // ANNN, FX1E and FX29
+    Index,
+    Random,
+    // 00E0 and DXYN
+    Display,
+    // EX9E, EXA1 and FX0A
+    Key,
+    // FX07, FX15 and FX18
+    Timer,
+    // FX33, FX55 and FX65
+    Memory,
+    // 0NNN and opcodes no interpreter knows
+    Other,
+}
+
+pub const CLASS_NAMES: [&str; 11] = [
+    "flow", "skip", "register", "alu", "index", "random", "display", "key", "timer", "memory",
+    "other",
+];
+
+impl OpcodeClass {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "flow" => Some(Self::Flow),
+            "skip" => Some(Self::Skip),
+            "register" => Some(Self::Register),
+            "alu" => Some(Self::Alu),
+            "index" => Some(Self::Index),
+            "random" => Some(Self::Random),
+            "display" => Some(Self::Display),
+            "key" => Some(Self::Key),
+            "timer" => Some(Self::Timer),
+            "memory" => Some(Self::Memory),
+            "other" => Some(Self::Other),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Flow => "flow",
+            Self::Skip => "skip",
+            Self::Register => "register",
+            Self::Alu => "alu",
+            Self::Index => "index",
+            Self::Random => "random",
+            Self::Display => "display",
+            Self::Key => "key",
+            Self::Timer => "timer",
+            Self::Memory => "memory",
+            Self::Other => "other",
+        }
+    }
+}
+
+impl Instruction {
+    pub fn decode(opcode: u16) -> Self {
+        let x = ((opcode & 0x0F00) >> 8) as u8;
+        let y = ((opcode & 0x00F0) >> 4) as u8;
+        let n = (opcode & 0x000F) as u8;
+        let nn = (opcode & 0x00FF) as u8;
+        let nnn = opcode & 0x0FFF;
+        match opcode & 0xF000 {
+            0x0000 => match opcode {
+                0x00E0 => Self::Clear,
+                0x00EE => Self::Return,
+                _ => Self::System(nnn),
+            },
+            0x1000 => Self::Jump(nnn),
+            0x2000 => Self::Call(nnn),
+            0x3000 => Self::SkipIfEqual(x, nn),
+            0x4000 => Self::SkipIfNotEqual(x, nn),
+            0x5000 if n == 0 => Self::SkipIfRegistersEqual(x, y),
+            0x6000 => Self::Set(x, nn),
+            0x7000 => Self::Add(x, nn),
+            0x8000 => match n {
+                0x0 => Self::Copy(x, y),
+                0x1 => Self::Or(x, y),
+                0x2 => Self::And(x, y),
+                0x3 => Self::Xor(x, y),
+                0x4 => Self::AddRegisters(x, y),
+                0x5 => Self::Subtract(x, y),
+                0x6 => Self::ShiftRight(x, y),
+                0x7 => Self::SubtractReversed(x, y),
+                0xE => Self::ShiftLeft(x, y),
+                _ => Self::Unknown(opcode),
+            },
+            0x9000 if n == 0 => Self::SkipIfRegistersNotEqual(x, y),
+            0xA000 => Self::SetIndex(nnn),
+            0xB000 => Self::JumpWithOffset(nnn),
+            0xC000 => Self::Random(x, nn),
+            0xD000 => Self::Draw(x, y, n),
+            0xE000 => match nn {
+                0x9E => Self::SkipIfKey(x),
+                0xA1 => Self::SkipIfNotKey(x),
+                _ => Self::Unknown(opcode),
+            },
+            0xF000 => match nn {
+                0x07 => Self::GetDelayTimer(x),
+                0x0A => Self::WaitForKey(x),
+                0x15 => Self::SetDelayTimer(x),
+                0x18 => Self::SetSoundTimer(x),
+                0x1E => Self::AddToIndex(x),
+                0x29 => Self::FontCharacter(x),
+                0x33 => Self::BinaryCodedDecimal(x),
+                0x55 => Self::Store(x),
+                0x65 => Self::Load(x),
+                _ => Self::Unknown(opcode),
+            },
+            _ => Self::Unknown(opcode),
+        }
+    }
+
+    pub fn class(&self) -> OpcodeClass {
+        match self {
+            Self::Return | Self::Jump(_) | Self::Call(_) | Self::JumpWithOffset(_) => {
+                OpcodeClass::Flow
+            }
+            Self::SkipIfEqual(..)
+            | Self::SkipIfNotEqual(..)
+            | Self::SkipIfRegistersEqual(..)
+            | Self::SkipIfRegistersNotEqual(..) => OpcodeClass::Skip,
+            Self::Set(..) | Self::Add(..) | Self::Copy(..) => OpcodeClass::Register,
+            Self::Or(..)
+            | Self::And(..)
+            | Self::Xor(..)
+            | Self::AddRegisters(..)
+            | Self::Subtract(..)
+            | Self::ShiftRight(..)
+            | Self::SubtractReversed(..)
+            | Self::ShiftLeft(..) => OpcodeClass::Alu,
+            Self::SetIndex(_) | Self::AddToIndex(_) | Self::FontCharacter(_) => OpcodeClass::Index,
+            Self::Random(..) => OpcodeClass::Random,
+            Self::Clear | Self::Draw(..) => OpcodeClass::Display,
+            Self::SkipIfKey(_) | Self::SkipIfNotKey(_) | Self::WaitForKey(_) => OpcodeClass::Key,
+            Self::GetDelayTimer(_) | Self::SetDelayTimer(_) | Self::SetSoundTimer(_) => {
+                OpcodeClass::Timer
+            }
+            Self::BinaryCodedDecimal(_) | Self::Store(_) | Self::Load(_) => OpcodeClass::Memory,
+            Self::System(_) | Self::Unknown(_) => OpcodeClass::Other,
+        }
+    }
+}
+
+// Disassembles into the mnemonics of Cowgod's CHIP-8 reference, which most
+// other disassemblers and tracing emulators use as well.
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Self::Clear => write!(f, "CLS"),
+            Self::Return => write!(f, "RET"),
+            Self::System(nnn) => write!(f, "SYS 0x{:03X}", nnn),
+            Self::Jump(nnn) => write!(f, "JP 0x{:03X}", nnn),
+            Self::Call(nnn) => write!(f, "CALL 0x{:03X}", nnn),
+            Self::SkipIfEqual(x, nn) => write!(f, "SE V{:X}, 0x{:02X}", x, nn),
+            Self::SkipIfNotEqual(x, nn) => write!(f, "SNE V{:X}, 0x{:02X}", x, nn),
+            Self::SkipIfRegistersEqual(x, y) => write!(f, "SE V{:X}, V{:X}", x, y),
+            Self::Set(x, nn) => write!(f, "LD V{:X}, 0x{:02X}", x, nn),
+            Self::Add(x, nn) => write!(f, "ADD V{:X}, 0x{:02X}", x, nn),
+            Self::Copy(x, y) => write!(f, "LD V{:X}, V{:X}", x, y),
+            Self::Or(x, y) => write!(f, "OR V{:X}, V{:X}", x, y),
+            Self::And(x, y) => write!(f, "AND V{:X}, V{:X}", x, y),
+            Self::Xor(x, y) => write!(f, "XOR V{:X}, V{:X}", x, y),
+            Self::AddRegisters(x, y) => write!(f, "ADD V{:X}, V{:X}", x, y),
+            Self::Subtract(x, y) => write!(f, "SUB V{:X}, V{:X}", x, y),
+            Self::ShiftRight(x, y) => write!(f, "SHR V{:X}, V{:X}", x, y),
+            Self::SubtractReversed(x, y) => write!(f, "SUBN V{:X}, V{:X}", x, y),
+            Self::ShiftLeft(x, y) => write!(f, "SHL V{:X}, V{:X}", x, y),
+            Self::SkipIfRegistersNotEqual(x, y) => write!(f, "SNE V{:X}, V{:X}", x, y),
+            Self::SetIndex(nnn) => write!(f, "LD I, 0x{:03X}", nnn),
+            Self::JumpWithOffset(nnn) => write!(f, "JP V0, 0x{:03X}", nnn),
+            Self::Random(x, nn) => write!(f, "RND V{:X}, 0x{:02X}", x, nn),
+            Self::Draw(x, y, n) => write!(f, "DRW V{:X}, V{:X}, {}", x, y, n),
+            Self::SkipIfKey(x) => write!(f, "SKP V{:X}", x),
+            Self::SkipIfNotKey(x) => write!(f, "SKNP V{:X}", x),
+            Self::GetDelayTimer(x) => write!(f, "LD V{:X}, DT", x),
+            Self::WaitForKey(x) => write!(f, "LD V{:X}, K", x),
+            Self::SetDelayTimer(x) => write!(f, "LD DT, V{:X}", x),
+            Self::SetSoundTimer(x) => write!(f, "LD ST, V{:X}", x),
+            Self::AddToIndex(x) => write!(f, "ADD I, V{:X}", x),
+            Self::FontCharacter(x) => write!(f, "LD F, V{:X}", x),
+            Self::BinaryCodedDecimal(x) => write!(f, "LD B, V{:X}", x),
+            Self::Store(x) => write!(f, "LD [I], V{:X}", x),
+            Self::Load(x) => write!(f, "LD V{:X}, [I]", x),
+            Self::Unknown(opcode) => write!(f, "DW 0x{:04X}", opcode),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // opcode, decoded instruction, disassembly, class
+    const TABLE: [(u16, Instruction, &str, OpcodeClass); 36] = [
+        (0x00E0, Instruction::Clear, "CLS", OpcodeClass::Display),
+        (0x00EE, Instruction::Return, "RET", OpcodeClass::Flow),
+        (
+            0x0123,
+            Instruction::System(0x123),
+            "SYS 0x123",
+            OpcodeClass::Other,
+        ),
+        (
+            0x1ABC,
+            Instruction::Jump(0xABC),
+            "JP 0xABC",
+            OpcodeClass::Flow,
+        ),
+        (
+            0x2ABC,
+            Instruction::Call(0xABC),
+            "CALL 0xABC",
+            OpcodeClass::Flow,
+        ),
+        (
+            0x3A12,
+            Instruction::SkipIfEqual(0xA, 0x12),
+            "SE VA, 0x12",
+            OpcodeClass::Skip,
+        ),
+        (
+            0x4A12,
+            Instruction::SkipIfNotEqual(0xA, 0x12),
+            "SNE VA, 0x12",
+            OpcodeClass::Skip,
+        ),
+        (
+            0x5AB0,
+            Instruction::SkipIfRegistersEqual(0xA, 0xB),
+            "SE VA, VB",
+            OpcodeClass::Skip,
+        ),
+        (
+            0x6A12,
+            Instruction::Set(0xA, 0x12),
+            "LD VA, 0x12",
+            OpcodeClass::Register,
+        ),
+        (
+            0x7A12,
+            Instruction::Add(0xA, 0x12),
+            "ADD VA, 0x12",
+            OpcodeClass::Register,
+        ),
+        (
+            0x8AB0,
+            Instruction::Copy(0xA, 0xB),
+            "LD VA, VB",
+            OpcodeClass::Register,
+        ),
+        (
+            0x8AB1,
+            Instruction::Or(0xA, 0xB),
+            "OR VA, VB",
+            OpcodeClass::Alu,
+        ),
+        (
+            0x8AB2,
+            Instruction::And(0xA, 0xB),
+            "AND VA, VB",
+            OpcodeClass::Alu,
+        ),
+        (
+            0x8AB3,
+            Instruction::Xor(0xA, 0xB),
+            "XOR VA, VB",
+            OpcodeClass::Alu,
+        ),
+        (
+            0x8AB4,
+            Instruction::AddRegisters(0xA, 0xB),
+            "ADD VA, VB",
+            OpcodeClass::Alu,
+        ),
+        (
+            0x8AB5,
+            Instruction::Subtract(0xA, 0xB),
+            "SUB VA, VB",
+            OpcodeClass::Alu,
+        ),
+        (
+            0x8AB6,
+            Instruction::ShiftRight(0xA, 0xB),
+            "SHR VA, VB",
+            OpcodeClass::Alu,
+        ),
+        (
+            0x8AB7,
+            Instruction::SubtractReversed(0xA, 0xB),
+            "SUBN VA, VB",
+            OpcodeClass::Alu,
+        ),
+        (
+            0x8ABE,
+            Instruction::ShiftLeft(0xA, 0xB),
+            "SHL VA, VB",
+            OpcodeClass::Alu,
+        ),
+        (
+            0x9AB0,
+            Instruction::SkipIfRegistersNotEqual(0xA, 0xB),
+            "SNE VA, VB",
+            OpcodeClass::Skip,
+        ),
+        (
+            0xAABC,
+            Instruction::SetIndex(0xABC),
+            "LD I, 0xABC",
+            OpcodeClass::Index,
+        ),
+        (
+            0xBABC,
+            Instruction::JumpWithOffset(0xABC),
+            "JP V0, 0xABC",
+            OpcodeClass::Flow,
+        ),
+        (
+            0xCA12,
+            Instruction::Random(0xA, 0x12),
+            "RND VA, 0x12",
+            OpcodeClass::Random,
+        ),
+        (
+            0xDAB5,
+            Instruction::Draw(0xA, 0xB, 5),
+            "DRW VA, VB, 5",
+            OpcodeClass::Display,
+        ),
+        (
+            0xEA9E,
+            Instruction::SkipIfKey(0xA),
+            "SKP VA",
+            OpcodeClass::Key,
+        ),
+        (
+            0xEAA1,
+            Instruction::SkipIfNotKey(0xA),
+            "SKNP VA",
+            OpcodeClass::Key,
+        ),
+        (
+            0xFA07,
+            Instruction::GetDelayTimer(0xA),
+            "LD VA, DT",
+            OpcodeClass::Timer,
+        ),
+        (
+            0xFA0A,
+            Instruction::WaitForKey(0xA),
+            "LD VA, K",
+            OpcodeClass::Key,
+        ),
+        (
+            0xFA15,
+            Instruction::SetDelayTimer(0xA),
+            "LD DT, VA",
+            OpcodeClass::Timer,
+        ),
+        (
+            0xFA18,
+            Instruction::SetSoundTimer(0xA),
+            "LD ST, VA",
+            OpcodeClass::Timer,
+        ),
+        (
+            0xFA1E,
+            Instruction::AddToIndex(0xA),
+            "ADD I, VA",
+            OpcodeClass::Index,
+        ),
+        (
+            0xFA29,
+            Instruction::FontCharacter(0xA),
+            "LD F, VA",
+            OpcodeClass::Index,
+        ),
+        (
+            0xFA33,
+            Instruction::BinaryCodedDecimal(0xA),
+            "LD B, VA",
+            OpcodeClass::Memory,
+        ),
+        (
+            0xFA55,
+            Instruction::Store(0xA),
+            "LD [I], VA",
+            OpcodeClass::Memory,
+        ),
+        (
+            0xFA65,
+            Instruction::Load(0xA),
+            "LD VA, [I]",
+            OpcodeClass::Memory,
+        ),
+        (
+            0x5AB1,
+            Instruction::Unknown(0x5AB1),
+            "DW 0x5AB1",
+            OpcodeClass::Other,
+        ),
+    ];
+
+    #[test]
+    fn decodes_every_family() {
+        for (opcode, instruction, text, class) in TABLE {
+            assert_eq!(Instruction::decode(opcode), instruction, "{:04X}", opcode);
+            assert_eq!(instruction.to_string(), text);
+            assert_eq!(instruction.class(), class, "{}", text);
+        }
+    }
+
+    #[test]
+    fn decodes_unknown_opcodes() {
+        for opcode in [0x9AB1, 0x8AB8, 0x8ABF, 0xEA00, 0xFA00, 0xFAFF] {
+            assert_eq!(Instruction::decode(opcode), Instruction::Unknown(opcode));
+        }
+    }
+
+    #[test]
+    fn names_classes() {
+        assert_eq!(CLASS_NAMES.len(), 11);
+        for name in CLASS_NAMES {
+            assert_eq!(OpcodeClass::from_name(name).unwrap().name(), name);
+        }
+        assert_eq!(OpcodeClass::from_name("alus"), None);
+    }
+}
@@ -0,0 +1,177 @@
+use super::phosphor::FilteredFrame;
+
+/// Colours used to draw the display. Index 0 is the background, 1 and 2 are the
+/// two XO-CHIP planes and 3 is used where both planes overlap.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Palette {
+    pub colors: [[u8; 3]; 4],
+}
+
+pub const PRESET_NAMES: [&str; 4] = ["default", "amber", "green", "lcd"];
+
+impl Palette {
+    pub fn new(colors: [[u8; 3]; 4]) -> Self {
+        Self { colors }
+    }
+
+    pub fn preset(name: &str) -> Option<Self> {
+        let colors = match name {
+            "default" => [[25, 51, 76], [255, 255, 255], [170, 170, 170], [85, 85, 85]],
+            "amber" => [[20, 12, 0], [255, 176, 0], [178, 110, 0], [255, 221, 128]],
+            "green" => [[0, 17, 0], [51, 255, 51], [25, 153, 25], [178, 255, 178]],
+            "lcd" => [[155, 188, 15], [15, 56, 15], [48, 98, 48], [139, 172, 15]],
+            _ => return None,
+        };
+        Some(Self::new(colors))
+    }
+
+    /// Parses a comma separated list of hex colours, e.g. `#000000,#ffb000`.
+    /// Missing plane colours are taken from the default palette.
+    pub fn from_hex_list(list: &str) -> Option<Self> {
+        let mut palette = Self::default();
+        for (i, color) in list.split(',').enumerate() {
+            if i >= palette.colors.len() {
+                return None;
+            }
+            palette.colors[i] = parse_hex_color(color)?;
+        }
+        Some(palette)
+    }
+
+    /// The opposite of `from_hex_list`.
+    pub fn to_hex_list(self) -> String {
+        self.colors
+            .iter()
+            .map(|[r, g, b]| format!("#{:02x}{:02x}{:02x}", r, g, b))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    pub fn background(&self) -> [u8; 3] {
+        self.colors[0]
+    }
+
+    pub fn foreground(&self) -> [u8; 3] {
+        self.colors[1]
+    }
+
+    pub fn color(&self, index: usize) -> [u8; 3] {
+        self.colors[index & 3]
+    }
+
+    /// Converts a frame into an RGBA buffer with one texel per pixel. A pixel gets the
+    /// colour of the planes it is lit on, `colors[index]`, blended by each plane's level.
+    pub fn render_rgba(&self, frame: &FilteredFrame) -> Vec<u8> {
+        let mut rgba = vec![0; frame.pixels() * 4];
+        self.render_rgba_rows(frame, 0..frame.rows, &mut rgba);
+        rgba
+    }
+
+    /// Like `render_rgba`, but only renders the given rows into an existing buffer.
+    pub fn render_rgba_rows(
+        &self,
+        frame: &FilteredFrame,
+        rows: impl Iterator<Item = usize>,
+        rgba: &mut [u8],
+    ) {
+        let indices = 1 << frame.planes;
+        for row in rows {
+            let texels = &mut rgba[row * frame.columns * 4..(row + 1) * frame.columns * 4];
+            for (column, texel) in texels.chunks_exact_mut(4).enumerate() {
+                let pixel = row * frame.columns + column;
+                let mut rgb = [0.0f32; 3];
+                for index in 0..indices {
+                    // how much of the pixel is lit on exactly the planes of `index`
+                    let weight = (0..frame.planes).fold(1.0, |weight, plane| {
+                        let level = frame.plane(plane)[pixel];
+                        weight
+                            * if (index >> plane) & 1 == 1 {
+                                level
+                            } else {
+                                1.0 - level
+                            }
+                    });
+                    if weight > 0.0 {
+                        let color = self.color(index);
+                        for channel in 0..3 {
+                            rgb[channel] += color[channel] as f32 * weight;
+                        }
+                    }
+                }
+                for channel in 0..3 {
+                    texel[channel] = rgb[channel].round() as u8;
+                }
+                texel[3] = 255;
+            }
+        }
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Self::preset("default").unwrap()
+    }
+}
+
+pub fn parse_hex_color(text: &str) -> Option<[u8; 3]> {
+    let hex = text.trim().trim_start_matches('#');
+    if hex.len() != 6 {
+        return None;
+    }
+    let value = u32::from_str_radix(hex, 16).ok()?;
+    Some([(value >> 16) as u8, (value >> 8) as u8, value as u8])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(planes: usize, levels: &[f32]) -> FilteredFrame {
+        FilteredFrame {
+            columns: levels.len() / planes,
+            rows: 1,
+            planes,
+            levels: levels.to_vec(),
+        }
+    }
+
+    fn texel(rgba: &[u8], pixel: usize) -> [u8; 3] {
+        [rgba[pixel * 4], rgba[pixel * 4 + 1], rgba[pixel * 4 + 2]]
+    }
+
+    #[test]
+    fn planes_pick_their_colour() {
+        let palette = Palette::new([[0, 0, 0], [255, 0, 0], [0, 255, 0], [0, 0, 255]]);
+        // pixels: off, plane 0, plane 1, both planes
+        let rgba = palette.render_rgba(&frame(2, &[0.0, 1.0, 0.0, 1.0, 0.0, 0.0, 1.0, 1.0]));
+        assert_eq!(texel(&rgba, 0), [0, 0, 0]);
+        assert_eq!(texel(&rgba, 1), [255, 0, 0]);
+        assert_eq!(texel(&rgba, 2), [0, 255, 0]);
+        assert_eq!(texel(&rgba, 3), [0, 0, 255]);
+        assert!(rgba.chunks(4).all(|texel| texel[3] == 255));
+    }
+
+    #[test]
+    fn levels_blend_towards_the_background() {
+        let palette = Palette::new([[0, 0, 0], [200, 100, 50], [0, 0, 0], [0, 0, 0]]);
+        let rgba = palette.render_rgba(&frame(1, &[0.5]));
+        assert_eq!(texel(&rgba, 0), [100, 50, 25]);
+    }
+
+    #[test]
+    fn hex_lists() {
+        let palette = Palette::from_hex_list("#000000, #ffb000").unwrap();
+        assert_eq!(palette.colors[0], [0, 0, 0]);
+        assert_eq!(palette.colors[1], [255, 176, 0]);
+        assert_eq!(palette.colors[2], Palette::default().colors[2]);
+        assert_eq!(
+            Palette::from_hex_list(&palette.to_hex_list()),
+            Some(palette)
+        );
+        assert_eq!(
+            Palette::from_hex_list("#000000,#111111,#222222,#333333,#444444"),
+            None
+        );
+        assert_eq!(Palette::from_hex_list("red"), None);
+    }
+}
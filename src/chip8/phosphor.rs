@@ -0,0 +1,209 @@
+use super::chip8_mods::display::{Damage, Display};
+use std::collections::VecDeque;
+
+/// How long pixels stay visible after the game turned them off.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Persistence {
+    Off,
+    // average of the last N frames
+    Blend(usize),
+    // turned off pixels fade out over N frames, like a CRT phosphor
+    Decay(usize),
+}
+
+impl Persistence {
+    /// Parses `off`, `blend:<frames>` or `decay:<frames>`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        if name == "off" {
+            return Some(Persistence::Off);
+        }
+        let (mode, frames) = name.split_once(':')?;
+        let frames: usize = frames.parse().ok().filter(|frames| *frames > 0)?;
+        match mode {
+            "blend" => Some(Persistence::Blend(frames)),
+            "decay" => Some(Persistence::Decay(frames)),
+            _ => None,
+        }
+    }
+}
+
+/// Brightness of every pixel on every plane between 0.0 (off) and 1.0 (lit), plane by
+/// plane and row by row.
+#[derive(Clone, PartialEq)]
+pub struct FilteredFrame {
+    pub columns: usize,
+    pub rows: usize,
+    pub planes: usize,
+    pub levels: Vec<f32>,
+}
+
+impl FilteredFrame {
+    /// Pixels per plane.
+    pub fn pixels(&self) -> usize {
+        self.columns * self.rows
+    }
+
+    /// Levels of one plane, row by row.
+    pub fn plane(&self, plane: usize) -> &[f32] {
+        let pixels = self.pixels();
+        &self.levels[plane * pixels..(plane + 1) * pixels]
+    }
+}
+
+/// Post-processes the display for presentation only, the emulated display is left alone.
+pub struct PhosphorFilter {
+    persistence: Persistence,
+    history: VecDeque<Vec<bool>>,
+    // display damage of the frames that still affect the filtered frame
+    recent_damage: VecDeque<Damage>,
+    damage: Damage,
+    frame: FilteredFrame,
+}
+
+impl PhosphorFilter {
+    pub fn new(persistence: Persistence) -> Self {
+        Self {
+            persistence,
+            history: VecDeque::new(),
+            recent_damage: VecDeque::new(),
+            damage: Damage::default(),
+            frame: FilteredFrame {
+                columns: 0,
+                rows: 0,
+                planes: 0,
+                levels: Vec::new(),
+            },
+        }
+    }
+
+    pub fn frame(&self) -> &FilteredFrame {
+        &self.frame
+    }
+
+    /// The part of the filtered frame the last `apply` changed.
+    pub fn damage(&self) -> Damage {
+        self.damage
+    }
+
+    // Has to be called once per emulated frame, so the effect does not depend on the refresh rate.
+    // `damage` is what changed on the display since the last call.
+    pub fn apply(&mut self, display: &Display, mut damage: Damage) {
+        let rows = display.height();
+        let columns = display.width();
+        let planes = display.planes();
+        if self.frame.columns != columns || self.frame.rows != rows || self.frame.planes != planes {
+            self.history.clear();
+            self.recent_damage.clear();
+            self.frame = FilteredFrame {
+                columns,
+                rows,
+                planes,
+                levels: vec![0.0; planes * rows * columns],
+            };
+            damage = Damage::all(columns, rows);
+        }
+
+        // a change keeps blending or fading for as many frames as the effect lasts,
+        // after that the filtered frame stays the same until the display changes again
+        let frames = match self.persistence {
+            Persistence::Off => 1,
+            Persistence::Blend(frames) | Persistence::Decay(frames) => frames,
+        };
+        self.recent_damage.push_back(damage);
+        while self.recent_damage.len() > frames {
+            self.recent_damage.pop_front();
+        }
+        self.damage = self
+            .recent_damage
+            .iter()
+            .fold(Damage::default(), |all, damage| all.union(*damage));
+        if self.damage.is_empty() {
+            return;
+        }
+
+        // every plane is filtered on its own, so XO-CHIP colours blend and fade separately
+        let mut current: Vec<bool> = Vec::with_capacity(planes * rows * columns);
+        for plane in 0..planes {
+            for lit in display.rows(plane) {
+                current.extend((0..columns).rev().map(|bit| (lit >> bit) & 1 == 1));
+            }
+        }
+        // only pixels in damaged rows can change their level
+        let pixels: Vec<usize> = (0..planes)
+            .flat_map(|plane| {
+                self.damage.rows().flat_map(move |y| {
+                    (y * columns..(y + 1) * columns).map(move |i| plane * rows * columns + i)
+                })
+            })
+            .collect();
+
+        match self.persistence {
+            Persistence::Off => {
+                for i in pixels {
+                    self.frame.levels[i] = if current[i] { 1.0 } else { 0.0 };
+                }
+            }
+            Persistence::Blend(frames) => {
+                self.history.push_back(current);
+                while self.history.len() > frames {
+                    self.history.pop_front();
+                }
+                let weight = 1.0 / self.history.len() as f32;
+                for i in pixels {
+                    let lit = self.history.iter().filter(|frame| frame[i]).count();
+                    self.frame.levels[i] = lit as f32 * weight;
+                }
+            }
+            Persistence::Decay(frames) => {
+                let step = 1.0 / frames as f32;
+                for i in pixels {
+                    let level = &mut self.frame.levels[i];
+                    *level = if current[i] {
+                        1.0
+                    } else {
+                        (*level - step).max(0.0)
+                    };
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn planes_fade_separately() {
+        let mut display = Display::with_size(64, 32, 2);
+        let mut filter = PhosphorFilter::new(Persistence::Decay(4));
+        display.draw_sprite(0, 0, 0, &[0x80], false);
+        display.draw_sprite(1, 0, 0, &[0x80], false);
+        apply(&mut filter, &mut display);
+        assert_eq!(filter.frame().plane(0)[0], 1.0);
+        assert_eq!(filter.frame().plane(1)[0], 1.0);
+
+        // turning the pixel off on plane 0 only fades plane 0
+        display.draw_sprite(0, 0, 0, &[0x80], false);
+        apply(&mut filter, &mut display);
+        assert_eq!(filter.frame().plane(0)[0], 0.75);
+        assert_eq!(filter.frame().plane(1)[0], 1.0);
+    }
+
+    #[test]
+    fn blend_averages_each_plane() {
+        let mut display = Display::with_size(64, 32, 2);
+        let mut filter = PhosphorFilter::new(Persistence::Blend(2));
+        display.draw_sprite(1, 0, 0, &[0x80], false);
+        apply(&mut filter, &mut display);
+        display.draw_sprite(1, 0, 0, &[0x80], false);
+        apply(&mut filter, &mut display);
+        assert_eq!(filter.frame().plane(0)[0], 0.0);
+        assert_eq!(filter.frame().plane(1)[0], 0.5);
+    }
+
+    fn apply(filter: &mut PhosphorFilter, display: &mut Display) {
+        let damage = display.take_damage();
+        filter.apply(display, damage);
+    }
+}
@@ -0,0 +1,185 @@
+use super::chip8_mods::keypad::Keymap;
+use super::palette::Palette;
+use super::quirks::QuirkOverrides;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+/// Settings the user chose for one ROM, `None` where the user kept what the
+/// database or the defaults say.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Profile {
+    pub quirks: QuirkOverrides,
+    pub instructions_per_frame: Option<u32>,
+    pub palette: Option<Palette>,
+    pub keymap: Option<Keymap>,
+}
+
+impl Profile {
+    pub fn from_json(json: &str) -> Result<Self, serde_json::Error> {
+        let value: serde_json::Value = serde_json::from_str(json)?;
+        Ok(Self {
+            quirks: value
+                .get("quirks")
+                .map(QuirkOverrides::from_json)
+                .unwrap_or_default(),
+            instructions_per_frame: value
+                .get("speed")
+                .and_then(|speed| speed.as_u64())
+                .map(|speed| speed as u32),
+            palette: value
+                .get("colors")
+                .and_then(|colors| colors.as_str())
+                .and_then(Palette::from_hex_list),
+            keymap: value.get("keys").map(Keymap::from_json),
+        })
+    }
+
+    pub fn to_json(&self) -> String {
+        let mut value = serde_json::Map::new();
+        if !self.quirks.is_empty() {
+            value.insert("quirks".to_string(), self.quirks.to_json());
+        }
+        if let Some(speed) = self.instructions_per_frame {
+            value.insert("speed".to_string(), speed.into());
+        }
+        if let Some(palette) = &self.palette {
+            value.insert("colors".to_string(), palette.to_hex_list().into());
+        }
+        if let Some(keymap) = &self.keymap {
+            value.insert("keys".to_string(), keymap.to_json());
+        }
+        serde_json::to_string_pretty(&serde_json::Value::Object(value)).unwrap()
+    }
+
+    // Settings of `other` win over the ones in this profile.
+    pub fn merge(&mut self, other: &Profile) {
+        self.quirks.merge(other.quirks);
+        self.instructions_per_frame = other.instructions_per_frame.or(self.instructions_per_frame);
+        self.palette = other.palette.or(self.palette);
+        self.keymap = other.keymap.or(self.keymap);
+    }
+}
+
+/// The profile of one ROM and the file it is stored in.
+/// Profiles are named after the SHA-1 of the ROM, so renaming or moving it keeps its settings.
+pub struct RomProfile {
+    path: PathBuf,
+    pub settings: Profile,
+}
+
+impl RomProfile {
+    /// Reads the profile of the ROM with the given hash from `directory`.
+    /// A missing profile is empty, a broken one is reported and ignored.
+    pub fn open(directory: PathBuf, hash: &str) -> Self {
+        let path = directory.join(format!("{}.json", hash));
+        let settings = match fs::read_to_string(&path) {
+            Ok(json) => Profile::from_json(&json).unwrap_or_else(|err| {
+                println!("Ignoring broken profile {}: {}", path.display(), err);
+                Profile::default()
+            }),
+            Err(_) => Profile::default(),
+        };
+        Self { path, settings }
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        if let Some(directory) = self.path.parent() {
+            fs::create_dir_all(directory)?;
+        }
+        fs::write(&self.path, self.settings.to_json())
+    }
+
+    pub fn path(&self) -> &std::path::Path {
+        &self.path
+    }
+}
+
+/// Where profiles live when `--profile-dir` isn't given:
+/// `$XDG_CONFIG_HOME/rs_chip8/profiles`, `~/.config/rs_chip8/profiles` or
+/// `%APPDATA%\rs_chip8\profiles`.
+pub fn default_directory() -> Option<PathBuf> {
+    let config = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .or_else(|| env::var_os("APPDATA").map(PathBuf::from))?;
+    Some(config.join("rs_chip8").join("profiles"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::quirks::Quirks;
+
+    #[test]
+    fn round_trips_through_json() {
+        let profile = Profile {
+            quirks: QuirkOverrides {
+                vblank: Some(false),
+                ..Default::default()
+            },
+            instructions_per_frame: Some(30),
+            palette: Palette::preset("amber"),
+            keymap: Some(Keymap {
+                up: Some(5),
+                ..Default::default()
+            }),
+        };
+        assert_eq!(Profile::from_json(&profile.to_json()).unwrap(), profile);
+        assert_eq!(Profile::from_json("{}").unwrap(), Profile::default());
+        assert_eq!(Profile::default().to_json(), "{}");
+        assert!(Profile::from_json("[1, 2").is_err());
+    }
+
+    #[test]
+    fn keeps_the_quirks_it_does_not_set() {
+        let profile = Profile::from_json(r#"{"quirks": {"logic": false}, "speed": 15}"#).unwrap();
+        assert_eq!(profile.instructions_per_frame, Some(15));
+        assert_eq!(profile.palette, None);
+        // the database says vip, the profile only changes one quirk
+        let mut quirks = Quirks::preset("vip").unwrap();
+        profile.quirks.apply(&mut quirks);
+        assert_eq!(
+            quirks,
+            Quirks {
+                logic: false,
+                ..Quirks::preset("vip").unwrap()
+            }
+        );
+    }
+
+    #[test]
+    fn merges_setting_by_setting() {
+        let mut profile = Profile::from_json(
+            r##"{"quirks": {"shift": true}, "speed": 15, "colors": "#000000"}"##,
+        )
+        .unwrap();
+        let command_line =
+            Profile::from_json(r#"{"quirks": {"jump": true}, "speed": 20}"#).unwrap();
+        profile.merge(&command_line);
+        assert_eq!(profile.quirks.shift, Some(true));
+        assert_eq!(profile.quirks.jump, Some(true));
+        assert_eq!(profile.instructions_per_frame, Some(20));
+        assert!(profile.palette.is_some());
+    }
+
+    #[test]
+    fn saves_and_opens() {
+        let directory =
+            std::env::temp_dir().join(format!("rs_chip8-{}-profiles", std::process::id()));
+        let mut profile = RomProfile::open(directory.clone(), "0123abcd");
+        assert_eq!(profile.settings, Profile::default());
+        profile.settings.instructions_per_frame = Some(12);
+        profile.save().unwrap();
+        assert_eq!(profile.path(), directory.join("0123abcd.json"));
+        let opened = RomProfile::open(directory.clone(), "0123abcd");
+        assert_eq!(opened.settings, profile.settings);
+
+        // a broken profile is ignored
+        fs::write(profile.path(), "not json").unwrap();
+        let broken = RomProfile::open(directory.clone(), "0123abcd");
+        assert_eq!(broken.settings, Profile::default());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,404 @@
+use super::instruction::{Instruction, OpcodeClass};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+// Rows shown in each table of the report.
+const REPORT_ROWS: usize = 20;
+
+// A frame that only runs this many different instructions, one of them reading
+// the delay timer, is treated as a busy loop polling the timer.
+const TIMER_LOOP_MAX_LENGTH: usize = 4;
+
+#[derive(Default)]
+struct SubroutineStats {
+    calls: u64,
+    // instructions run in the subroutine itself
+    self_cycles: u64,
+    // instructions run in the subroutine and everything it calls
+    total_cycles: u64,
+}
+
+// A call stack, with `parent` one call further out. The empty stack is its own parent.
+struct StackNode {
+    parent: usize,
+    entry: Option<u16>,
+    // the subroutines on the stack, each once, so recursive calls count once
+    subroutines: Vec<u16>,
+    instructions: u64,
+}
+
+/// The call stacks seen so far, interned so that counting an instruction doesn't allocate.
+struct CallStacks {
+    nodes: Vec<StackNode>,
+    children: HashMap<(usize, u16), usize>,
+    current: usize,
+}
+
+impl Default for CallStacks {
+    fn default() -> Self {
+        Self {
+            nodes: vec![StackNode {
+                parent: 0,
+                entry: None,
+                subroutines: Vec::new(),
+                instructions: 0,
+            }],
+            children: HashMap::new(),
+            current: 0,
+        }
+    }
+}
+
+impl CallStacks {
+    fn call(&mut self, entry: u16) {
+        let parent = self.current;
+        let nodes = &mut self.nodes;
+        self.current = *self.children.entry((parent, entry)).or_insert_with(|| {
+            let mut subroutines = nodes[parent].subroutines.clone();
+            if !subroutines.contains(&entry) {
+                subroutines.push(entry);
+            }
+            nodes.push(StackNode {
+                parent,
+                entry: Some(entry),
+                subroutines,
+                instructions: 0,
+            });
+            nodes.len() - 1
+        });
+    }
+
+    fn ret(&mut self) {
+        self.current = self.nodes[self.current].parent;
+    }
+
+    // Subroutine entries of a stack, outermost first.
+    fn entries(&self, mut node: usize) -> Vec<u16> {
+        let mut entries = Vec::new();
+        while let Some(entry) = self.nodes[node].entry {
+            entries.push(entry);
+            node = self.nodes[node].parent;
+        }
+        entries.reverse();
+        entries
+    }
+}
+
+/// Counts what a program spends its instructions and frames on.
+#[derive(Default)]
+pub struct Profiler {
+    // executions and the last opcode seen at every address
+    addresses: HashMap<u16, (u64, u16)>,
+    classes: HashMap<OpcodeClass, u64>,
+    subroutines: HashMap<u16, SubroutineStats>,
+    // instructions per call stack, for flame graphs
+    call_stacks: CallStacks,
+    cycles: u64,
+    frames: u64,
+    key_wait_frames: u64,
+    timer_poll_frames: u64,
+    frame: FrameStats,
+    // where `finish` writes the folded stacks
+    folded_path: Option<String>,
+}
+
+#[derive(Default)]
+struct FrameStats {
+    instructions: u64,
+    key_waits: u64,
+    reads_delay_timer: bool,
+    addresses: Vec<u16>,
+}
+
+impl Profiler {
+    pub fn new(folded_path: Option<&str>) -> Self {
+        Self {
+            folded_path: folded_path.map(|path| path.to_string()),
+            ..Self::default()
+        }
+    }
+
+    // Prints the report and writes the folded stacks.
+    pub fn finish(&self) {
+        self.print_report();
+        if let Some(path) = &self.folded_path {
+            match self.write_folded(path) {
+                Ok(()) => println!("Wrote folded stacks to {}", path),
+                Err(err) => println!("Could not write folded stacks to {}: {}", path, err),
+            }
+        }
+    }
+
+    // Counts an instruction before it executes.
+    pub fn record(&mut self, pc: u16, opcode: u16) {
+        let instruction = Instruction::decode(opcode);
+        self.cycles += 1;
+        let address = self.addresses.entry(pc).or_insert((0, opcode));
+        *address = (address.0 + 1, opcode);
+        *self.classes.entry(instruction.class()).or_insert(0) += 1;
+
+        let stack = &mut self.call_stacks.nodes[self.call_stacks.current];
+        stack.instructions += 1;
+        if let Some(current) = stack.entry {
+            self.subroutines.entry(current).or_default().self_cycles += 1;
+        }
+        for entry in stack.subroutines.iter() {
+            self.subroutines.entry(*entry).or_default().total_cycles += 1;
+        }
+
+        match instruction {
+            Instruction::Call(address) => {
+                self.subroutines.entry(address).or_default().calls += 1;
+                self.call_stacks.call(address);
+            }
+            Instruction::Return => self.call_stacks.ret(),
+            Instruction::WaitForKey(_) => self.frame.key_waits += 1,
+            Instruction::GetDelayTimer(_) => self.frame.reads_delay_timer = true,
+            _ => (),
+        }
+
+        self.frame.instructions += 1;
+        if self.frame.addresses.len() <= TIMER_LOOP_MAX_LENGTH
+            && !self.frame.addresses.contains(&pc)
+        {
+            self.frame.addresses.push(pc);
+        }
+    }
+
+    pub fn end_frame(&mut self) {
+        let frame = std::mem::take(&mut self.frame);
+        self.frames += 1;
+        if frame.instructions > 0 && frame.key_waits == frame.instructions {
+            self.key_wait_frames += 1;
+        } else if frame.reads_delay_timer && frame.addresses.len() <= TIMER_LOOP_MAX_LENGTH {
+            self.timer_poll_frames += 1;
+        }
+    }
+
+    pub fn print_report(&self) {
+        println!();
+        println!(
+            "Profile of {} instructions in {} frames",
+            self.cycles, self.frames
+        );
+        println!(
+            "  {} frames waiting for a key (FX0A), {} frames polling the delay timer",
+            self.key_wait_frames, self.timer_poll_frames
+        );
+
+        println!();
+        println!("Hot spots:");
+        println!(
+            "  {:<8} {:<6} {:<18} {:>12} {:>7}",
+            "address", "opcode", "", "executions", "share"
+        );
+        let mut addresses: Vec<_> = self.addresses.iter().collect();
+        addresses.sort_by(|a, b| b.1 .0.cmp(&a.1 .0).then(a.0.cmp(b.0)));
+        for (address, (count, opcode)) in addresses.iter().take(REPORT_ROWS) {
+            println!(
+                "  0x{:04X}   {:04X}   {:<18} {:>12} {:>6.2}%",
+                address,
+                opcode,
+                Instruction::decode(*opcode).to_string(),
+                count,
+                self.share(*count)
+            );
+        }
+
+        println!();
+        println!("Opcode classes:");
+        let mut classes: Vec<_> = self.classes.iter().collect();
+        classes.sort_by(|a, b| b.1.cmp(a.1).then(a.0.name().cmp(b.0.name())));
+        for (class, count) in classes {
+            println!(
+                "  {:<10} {:>12} {:>6.2}%",
+                class.name(),
+                count,
+                self.share(*count)
+            );
+        }
+
+        if !self.subroutines.is_empty() {
+            println!();
+            println!("Subroutines:");
+            println!(
+                "  {:<8} {:>8} {:>12} {:>12} {:>7}",
+                "entry", "calls", "self", "total", "share"
+            );
+            let mut subroutines: Vec<_> = self.subroutines.iter().collect();
+            subroutines.sort_by(|a, b| b.1.total_cycles.cmp(&a.1.total_cycles).then(a.0.cmp(b.0)));
+            for (entry, stats) in subroutines.iter().take(REPORT_ROWS) {
+                println!(
+                    "  0x{:04X}   {:>8} {:>12} {:>12} {:>6.2}%",
+                    entry,
+                    stats.calls,
+                    stats.self_cycles,
+                    stats.total_cycles,
+                    self.share(stats.total_cycles)
+                );
+            }
+        }
+    }
+
+    /// Writes one line per call stack in the folded format of flamegraph.pl and inferno:
+    /// `main;sub_0x0234;sub_0x0300 1234`.
+    pub fn write_folded(&self, path: &str) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        let mut stacks: Vec<_> = (0..self.call_stacks.nodes.len())
+            .filter(|node| self.call_stacks.nodes[*node].instructions > 0)
+            .map(|node| {
+                (
+                    self.call_stacks.entries(node),
+                    self.call_stacks.nodes[node].instructions,
+                )
+            })
+            .collect();
+        stacks.sort();
+        for (stack, count) in stacks {
+            write!(writer, "main")?;
+            for entry in stack.iter() {
+                write!(writer, ";sub_0x{:04X}", entry)?;
+            }
+            writeln!(writer, " {}", count)?;
+        }
+        writer.flush()
+    }
+
+    fn share(&self, count: u64) -> f64 {
+        if self.cycles == 0 {
+            return 0.0;
+        }
+        count as f64 * 100.0 / self.cycles as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::chip8_mods::memory::Layout;
+    use crate::chip8::rom::Rom;
+    use crate::chip8::Chip8;
+
+    fn profile(program: &[u8], steps: usize, frames: usize) -> Profiler {
+        let rom = Rom::from_bytes("test.ch8", program);
+        let mut chip8 = Chip8::from_rom(&rom, Layout::Chip8).unwrap();
+        chip8.start_profiling(None);
+        for _ in 0..steps {
+            chip8.step().unwrap();
+        }
+        for _ in 0..frames {
+            chip8.run_frame().unwrap();
+        }
+        chip8.profiler.take().unwrap()
+    }
+
+    fn folded(profiler: &Profiler, name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("rs_chip8-{}-{}", std::process::id(), name));
+        profiler.write_folded(path.to_str().unwrap()).unwrap();
+        let folded = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        folded
+    }
+
+    // 0x200 2206  CALL 0x206
+    // 0x202 220C  CALL 0x20C
+    // 0x204 1204  JP 0x204
+    // 0x206 220C  CALL 0x20C
+    // 0x208 6101  LD V1, 0x01
+    // 0x20A 00EE  RET
+    // 0x20C 6202  LD V2, 0x02
+    // 0x20E 00EE  RET
+    const CALLS: [u8; 16] = [
+        0x22, 0x06, 0x22, 0x0C, 0x12, 0x04, 0x22, 0x0C, 0x61, 0x01, 0x00, 0xEE, 0x62, 0x02, 0x00,
+        0xEE,
+    ];
+
+    #[test]
+    fn counts_subroutines() {
+        let profiler = profile(&CALLS, 10, 0);
+        assert_eq!(profiler.cycles, 10);
+        let outer = &profiler.subroutines[&0x206];
+        assert_eq!(
+            (outer.calls, outer.self_cycles, outer.total_cycles),
+            (1, 3, 5)
+        );
+        let inner = &profiler.subroutines[&0x20C];
+        assert_eq!(
+            (inner.calls, inner.self_cycles, inner.total_cycles),
+            (2, 4, 4)
+        );
+        assert_eq!(profiler.addresses[&0x20C], (2, 0x6202));
+        assert_eq!(profiler.addresses[&0x204], (1, 0x1204));
+        assert_eq!(profiler.classes[&OpcodeClass::Flow], 7);
+        assert_eq!(profiler.classes[&OpcodeClass::Register], 3);
+    }
+
+    #[test]
+    fn writes_folded_stacks() {
+        let profiler = profile(&CALLS, 10, 0);
+        assert_eq!(
+            folded(&profiler, "calls.folded"),
+            "main 3\n\
+             main;sub_0x0206 3\n\
+             main;sub_0x0206;sub_0x020C 2\n\
+             main;sub_0x020C 2\n"
+        );
+    }
+
+    #[test]
+    fn counts_recursion_once() {
+        // 0x200 2202  CALL 0x202, which calls itself
+        let profiler = profile(&[0x22, 0x02, 0x22, 0x02], 3, 0);
+        let stats = &profiler.subroutines[&0x202];
+        assert_eq!(
+            (stats.calls, stats.self_cycles, stats.total_cycles),
+            (3, 2, 2)
+        );
+        assert_eq!(
+            folded(&profiler, "recursion.folded"),
+            "main 1\nmain;sub_0x0202 1\nmain;sub_0x0202;sub_0x0202 1\n"
+        );
+    }
+
+    #[test]
+    fn returns_without_a_call_stay_in_main() {
+        let mut call_stacks = CallStacks::default();
+        call_stacks.ret();
+        assert_eq!(call_stacks.current, 0);
+        call_stacks.call(0x300);
+        call_stacks.call(0x400);
+        call_stacks.ret();
+        call_stacks.call(0x400);
+        // the same stack is only interned once
+        assert_eq!(call_stacks.nodes.len(), 3);
+        assert_eq!(call_stacks.entries(call_stacks.current), [0x300, 0x400]);
+    }
+
+    #[test]
+    fn finds_frames_waiting_for_a_key() {
+        // 0x200 F00A  LD V0, K
+        let profiler = profile(&[0xF0, 0x0A], 0, 3);
+        assert_eq!(profiler.frames, 3);
+        assert_eq!(profiler.key_wait_frames, 3);
+        assert_eq!(profiler.timer_poll_frames, 0);
+    }
+
+    #[test]
+    fn finds_frames_polling_the_timer() {
+        // 0x200 6005  LD V0, 0x05
+        // 0x202 F015  LD DT, V0
+        // 0x204 F107  LD V1, DT
+        // 0x206 3100  SE V1, 0x00
+        // 0x208 1204  JP 0x204
+        // 0x20A 120A  JP 0x20A
+        let program = [
+            0x60, 0x05, 0xF0, 0x15, 0xF1, 0x07, 0x31, 0x00, 0x12, 0x04, 0x12, 0x0A,
+        ];
+        let profiler = profile(&program, 2, 10);
+        assert_eq!(profiler.frames, 10);
+        assert_eq!(profiler.key_wait_frames, 0);
+        // the timer runs out after 5 frames, then the program spins at 0x20A
+        assert_eq!(profiler.timer_poll_frames, 5);
+    }
+}
@@ -0,0 +1,255 @@
+/// Behaviour that differs between CHIP-8 interpreters. The names follow the
+/// quirks of the community chip-8-database.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quirks {
+    // 8XY6/8XYE shift VX in place instead of shifting VY into VX
+    pub shift: bool,
+    // FX55/FX65 advance I by X instead of X + 1
+    pub memory_increment_by_x: bool,
+    // FX55/FX65 leave I unchanged
+    pub memory_leave_i_unchanged: bool,
+    // sprites wrap around the screen edges instead of being clipped
+    pub wrap: bool,
+    // BNNN jumps to XNN + VX instead of NNN + V0
+    pub jump: bool,
+    // DXYN waits for the next frame
+    pub vblank: bool,
+    // 8XY1/8XY2/8XY3 reset VF to 0
+    pub logic: bool,
+}
+
+pub const PRESET_NAMES: [&str; 6] = ["default", "vip", "modern", "chip48", "schip", "xochip"];
+
+impl Quirks {
+    pub fn preset(name: &str) -> Option<Self> {
+        let quirks = Self {
+            shift: false,
+            memory_increment_by_x: false,
+            memory_leave_i_unchanged: false,
+            wrap: false,
+            jump: false,
+            vblank: false,
+            logic: false,
+        };
+        match name {
+            // what this emulator always did
+            "default" => Some(Self {
+                shift: true,
+                memory_leave_i_unchanged: true,
+                ..quirks
+            }),
+            // original COSMAC VIP interpreter
+            "vip" => Some(Self {
+                vblank: true,
+                logic: true,
+                ..quirks
+            }),
+            "modern" => Some(quirks),
+            "chip48" => Some(Self {
+                shift: true,
+                memory_increment_by_x: true,
+                jump: true,
+                ..quirks
+            }),
+            "schip" => Some(Self {
+                shift: true,
+                memory_leave_i_unchanged: true,
+                jump: true,
+                ..quirks
+            }),
+            "xochip" => Some(Self {
+                wrap: true,
+                ..quirks
+            }),
+            _ => None,
+        }
+    }
+
+    /// Quirks of a platform id used by the chip-8-database, e.g. `superchip`.
+    pub fn for_platform(platform: &str) -> Option<Self> {
+        match platform {
+            "originalChip8" | "hybridVIP" => Self::preset("vip"),
+            "modernChip8" => Self::preset("modern"),
+            "chip48" => Self::preset("chip48"),
+            "superchip1" | "superchip" => Self::preset("schip"),
+            "xochip" => Self::preset("xochip"),
+            _ => None,
+        }
+    }
+
+    fn fields_mut(&mut self) -> [(&'static str, &mut bool); 7] {
+        [
+            ("shift", &mut self.shift),
+            ("memoryIncrementByX", &mut self.memory_increment_by_x),
+            ("memoryLeaveIUnchanged", &mut self.memory_leave_i_unchanged),
+            ("wrap", &mut self.wrap),
+            ("jump", &mut self.jump),
+            ("vblank", &mut self.vblank),
+            ("logic", &mut self.logic),
+        ]
+    }
+}
+
+impl Default for Quirks {
+    fn default() -> Self {
+        Self::preset("default").unwrap()
+    }
+}
+
+/// Quirks set by a profile, `None` where it keeps the ones of the database or the defaults.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct QuirkOverrides {
+    pub shift: Option<bool>,
+    pub memory_increment_by_x: Option<bool>,
+    pub memory_leave_i_unchanged: Option<bool>,
+    pub wrap: Option<bool>,
+    pub jump: Option<bool>,
+    pub vblank: Option<bool>,
+    pub logic: Option<bool>,
+}
+
+impl QuirkOverrides {
+    /// Overrides every quirk, as choosing a preset does.
+    pub fn all(mut quirks: Quirks) -> Self {
+        let mut overrides = Self::default();
+        for ((_, field), (_, value)) in overrides.fields_mut().into_iter().zip(quirks.fields_mut())
+        {
+            *field = Some(*value);
+        }
+        overrides
+    }
+
+    /// The quirks named in a chip-8-database quirk object.
+    pub fn from_json(quirks: &serde_json::Value) -> Self {
+        let mut overrides = Self::default();
+        for (key, field) in overrides.fields_mut() {
+            *field = quirks.get(key).and_then(|value| value.as_bool());
+        }
+        overrides
+    }
+
+    /// The quirks that are set as a chip-8-database quirk object.
+    pub fn to_json(mut self) -> serde_json::Value {
+        let quirks = self
+            .fields_mut()
+            .into_iter()
+            .filter_map(|(key, field)| Some((key.to_string(), serde_json::Value::Bool((*field)?))))
+            .collect();
+        serde_json::Value::Object(quirks)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn apply(mut self, quirks: &mut Quirks) {
+        for ((_, field), (_, value)) in quirks.fields_mut().into_iter().zip(self.fields_mut()) {
+            if let Some(value) = value {
+                *field = *value;
+            }
+        }
+    }
+
+    // Quirks set in `other` win over the ones set here.
+    pub fn merge(&mut self, mut other: QuirkOverrides) {
+        for ((_, field), (_, value)) in self.fields_mut().into_iter().zip(other.fields_mut()) {
+            *field = value.or(*field);
+        }
+    }
+
+    fn fields_mut(&mut self) -> [(&'static str, &mut Option<bool>); 7] {
+        [
+            ("shift", &mut self.shift),
+            ("memoryIncrementByX", &mut self.memory_increment_by_x),
+            ("memoryLeaveIUnchanged", &mut self.memory_leave_i_unchanged),
+            ("wrap", &mut self.wrap),
+            ("jump", &mut self.jump),
+            ("vblank", &mut self.vblank),
+            ("logic", &mut self.logic),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn presets() {
+        for name in PRESET_NAMES {
+            assert!(Quirks::preset(name).is_some());
+        }
+        assert_eq!(Quirks::preset("pong"), None);
+        assert_eq!(Quirks::for_platform("superchip"), Quirks::preset("schip"));
+        assert_eq!(Quirks::for_platform("megachip8"), None);
+    }
+
+    #[test]
+    fn overrides_only_what_they_set() {
+        let overrides = QuirkOverrides::from_json(&serde_json::json!({
+            "shift": false,
+            "vblank": true,
+            "wrap": "yes",
+        }));
+        assert_eq!(overrides.shift, Some(false));
+        assert_eq!(overrides.vblank, Some(true));
+        // not a bool
+        assert_eq!(overrides.wrap, None);
+
+        let mut quirks = Quirks::preset("schip").unwrap();
+        overrides.apply(&mut quirks);
+        assert_eq!(
+            quirks,
+            Quirks {
+                shift: false,
+                vblank: true,
+                ..Quirks::preset("schip").unwrap()
+            }
+        );
+    }
+
+    #[test]
+    fn overrides_round_trip() {
+        let overrides = QuirkOverrides {
+            jump: Some(true),
+            logic: Some(false),
+            ..Default::default()
+        };
+        let json = overrides.to_json();
+        assert_eq!(json, serde_json::json!({"jump": true, "logic": false}));
+        assert_eq!(QuirkOverrides::from_json(&json), overrides);
+        assert!(QuirkOverrides::default().is_empty());
+        assert!(!overrides.is_empty());
+    }
+
+    #[test]
+    fn presets_override_everything() {
+        let vip = Quirks::preset("vip").unwrap();
+        let mut quirks = Quirks::preset("xochip").unwrap();
+        QuirkOverrides::all(vip).apply(&mut quirks);
+        assert_eq!(quirks, vip);
+    }
+
+    #[test]
+    fn later_overrides_win() {
+        let mut overrides = QuirkOverrides {
+            shift: Some(true),
+            wrap: Some(true),
+            ..Default::default()
+        };
+        overrides.merge(QuirkOverrides {
+            wrap: Some(false),
+            jump: Some(true),
+            ..Default::default()
+        });
+        assert_eq!(
+            overrides,
+            QuirkOverrides {
+                shift: Some(true),
+                wrap: Some(false),
+                jump: Some(true),
+                ..Default::default()
+            }
+        );
+    }
+}
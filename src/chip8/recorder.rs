@@ -0,0 +1,308 @@
+use super::palette::Palette;
+use super::phosphor::FilteredFrame;
+use std::fs::{self, File};
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+
+// Frames are captured once per emulated frame, so a tick is 1/60 s.
+const TICKS_PER_SECOND: u64 = 60;
+
+enum Format {
+    Gif,
+    PngSequence,
+}
+
+struct PendingFrame {
+    rgba: Vec<u8>,
+    start_tick: u64,
+}
+
+/// Records every captured frame into an animated GIF or a directory of numbered PNGs.
+/// Consecutive identical frames are merged, so idle screens only cost one frame.
+pub struct Recorder {
+    path: PathBuf,
+    format: Format,
+    scale: usize,
+    palette: Palette,
+    recording: bool,
+    gif: Option<gif::Encoder<BufWriter<File>>>,
+    pending: Option<PendingFrame>,
+    width: usize,
+    height: usize,
+    tick: u64,
+}
+
+impl Recorder {
+    pub fn new(path: &str, scale: usize, palette: Palette) -> Self {
+        let path = PathBuf::from(path);
+        let format = match path.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("gif") => Format::Gif,
+            _ => Format::PngSequence,
+        };
+        Self {
+            path,
+            format,
+            scale: scale.max(1),
+            palette,
+            recording: false,
+            gif: None,
+            pending: None,
+            width: 0,
+            height: 0,
+            tick: 0,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn start(&mut self) -> io::Result<()> {
+        if self.recording {
+            return Ok(());
+        }
+        if let Format::PngSequence = self.format {
+            fs::create_dir_all(&self.path)?;
+        }
+        self.gif = None;
+        self.pending = None;
+        self.tick = 0;
+        self.recording = true;
+        println!("Recording frames to {}", self.path.display());
+        Ok(())
+    }
+
+    // `changed` is false if the frame is known to be the same as the last one.
+    pub fn capture(&mut self, frame: &FilteredFrame, changed: bool) -> io::Result<()> {
+        if !self.recording {
+            return Ok(());
+        }
+        if self.pending.is_some() && !changed {
+            self.tick += 1;
+            return Ok(());
+        }
+        let rgba = self.frame_to_rgba(frame);
+        let is_duplicate = match &self.pending {
+            Some(pending) => pending.rgba == rgba,
+            None => false,
+        };
+        if !is_duplicate {
+            self.flush_pending()?;
+            self.pending = Some(PendingFrame {
+                rgba,
+                start_tick: self.tick,
+            });
+        }
+        self.tick += 1;
+        Ok(())
+    }
+
+    pub fn stop(&mut self) -> io::Result<()> {
+        if !self.recording {
+            return Ok(());
+        }
+        self.flush_pending()?;
+        // dropping the encoder writes the GIF trailer
+        self.gif = None;
+        self.recording = false;
+        println!("Recorded {} frames to {}", self.tick, self.path.display());
+        Ok(())
+    }
+
+    fn frame_to_rgba(&mut self, frame: &FilteredFrame) -> Vec<u8> {
+        let columns = frame.columns;
+        self.height = frame.rows * self.scale;
+        self.width = columns * self.scale;
+
+        let rgba = self.palette.render_rgba(frame);
+        if self.scale == 1 {
+            return rgba;
+        }
+        let mut scaled = Vec::with_capacity(self.width * self.height * 4);
+        for row in rgba.chunks_exact(columns * 4) {
+            let mut line = Vec::with_capacity(self.width * 4);
+            for texel in row.chunks_exact(4) {
+                for _ in 0..self.scale {
+                    line.extend_from_slice(texel);
+                }
+            }
+            for _ in 0..self.scale {
+                scaled.extend_from_slice(&line);
+            }
+        }
+        scaled
+    }
+
+    fn flush_pending(&mut self) -> io::Result<()> {
+        let pending = match self.pending.take() {
+            Some(pending) => pending,
+            None => return Ok(()),
+        };
+        match self.format {
+            Format::Gif => self.write_gif_frame(&pending),
+            Format::PngSequence => self.write_png_frame(&pending),
+        }
+    }
+
+    fn write_gif_frame(&mut self, pending: &PendingFrame) -> io::Result<()> {
+        if self.gif.is_none() {
+            let file = BufWriter::new(File::create(&self.path)?);
+            let mut encoder = gif::Encoder::new(file, self.width as u16, self.height as u16, &[])
+                .map_err(to_io_error)?;
+            encoder
+                .set_repeat(gif::Repeat::Infinite)
+                .map_err(to_io_error)?;
+            self.gif = Some(encoder);
+        }
+
+        let (palette, indices) = index_colors(&pending.rgba);
+        let mut frame = match palette {
+            Some(palette) => gif::Frame::from_palette_pixels(
+                self.width as u16,
+                self.height as u16,
+                indices,
+                palette,
+                None,
+            ),
+            None => {
+                let mut rgba = pending.rgba.clone();
+                gif::Frame::from_rgba_speed(self.width as u16, self.height as u16, &mut rgba, 10)
+            }
+        };
+        // GIF delays are in centiseconds, so 60 fps cannot be represented exactly.
+        // Rounding both ends of the frame keeps the accumulated timing exact.
+        frame.delay = (ticks_to_centiseconds(self.tick) - ticks_to_centiseconds(pending.start_tick))
+            .max(1) as u16;
+
+        self.gif
+            .as_mut()
+            .unwrap()
+            .write_frame(&frame)
+            .map_err(to_io_error)
+    }
+
+    fn write_png_frame(&mut self, pending: &PendingFrame) -> io::Result<()> {
+        // frames are named after the tick they first appeared on, so timing survives deduplication
+        let file_path = self
+            .path
+            .join(format!("frame_{:06}.png", pending.start_tick));
+        let file = BufWriter::new(File::create(file_path)?);
+        let mut encoder = png::Encoder::new(file, self.width as u32, self.height as u32);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().map_err(to_io_error)?;
+        writer.write_image_data(&pending.rgba).map_err(to_io_error)
+    }
+}
+
+fn ticks_to_centiseconds(ticks: u64) -> u64 {
+    (ticks * 100 + TICKS_PER_SECOND / 2) / TICKS_PER_SECOND
+}
+
+// Builds an exact palette for frames with at most 256 colours.
+fn index_colors(rgba: &[u8]) -> (Option<Vec<u8>>, Vec<u8>) {
+    let mut colors: Vec<[u8; 3]> = Vec::new();
+    let mut indices = Vec::with_capacity(rgba.len() / 4);
+    for pixel in rgba.chunks_exact(4) {
+        let color = [pixel[0], pixel[1], pixel[2]];
+        let index = match colors.iter().position(|c| *c == color) {
+            Some(index) => index,
+            None => {
+                if colors.len() == 256 {
+                    return (None, Vec::new());
+                }
+                colors.push(color);
+                colors.len() - 1
+            }
+        };
+        indices.push(index as u8);
+    }
+    (Some(colors.concat()), indices)
+}
+
+fn to_io_error<E: std::error::Error + Send + Sync + 'static>(err: E) -> io::Error {
+    io::Error::other(err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(lit: &[bool]) -> FilteredFrame {
+        FilteredFrame {
+            columns: 4,
+            rows: 2,
+            planes: 1,
+            levels: lit.iter().map(|&lit| if lit { 1.0 } else { 0.0 }).collect(),
+        }
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("rs_chip8-{}-{}", std::process::id(), name))
+    }
+
+    fn record(recorder: &mut Recorder) {
+        let first = frame(&[true, false, false, false, false, false, false, true]);
+        let second = frame(&[false; 8]);
+        recorder.start().unwrap();
+        recorder.capture(&first, true).unwrap();
+        recorder.capture(&first, false).unwrap();
+        // the same pixels again, even though the caller didn't know
+        recorder.capture(&first, true).unwrap();
+        recorder.capture(&second, true).unwrap();
+        recorder.stop().unwrap();
+    }
+
+    #[test]
+    fn rounds_delays_to_centiseconds() {
+        let delays: Vec<u64> = (0..=6).map(ticks_to_centiseconds).collect();
+        assert_eq!(delays, [0, 2, 3, 5, 7, 8, 10]);
+    }
+
+    #[test]
+    fn records_pngs_named_after_their_first_frame() {
+        let path = temp_path("frames");
+        let mut recorder = Recorder::new(path.to_str().unwrap(), 2, Palette::default());
+        record(&mut recorder);
+
+        let mut names: Vec<String> = fs::read_dir(&path)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["frame_000000.png", "frame_000003.png"]);
+
+        let decoder = png::Decoder::new(File::open(path.join("frame_000000.png")).unwrap());
+        let reader = decoder.read_info().unwrap();
+        assert_eq!((reader.info().width, reader.info().height), (8, 4));
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn records_gifs_with_merged_frames() {
+        let path = temp_path("recording.gif");
+        let mut recorder = Recorder::new(path.to_str().unwrap(), 1, Palette::default());
+        record(&mut recorder);
+
+        let mut decoder = gif::DecodeOptions::new()
+            .read_info(File::open(&path).unwrap())
+            .unwrap();
+        assert_eq!((decoder.width(), decoder.height()), (4, 2));
+        let mut delays = Vec::new();
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            delays.push(frame.delay);
+        }
+        // three ticks are 5 cs, the fourth ends at 7 cs
+        assert_eq!(delays, [5, 2]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn only_records_while_started() {
+        let path = temp_path("idle");
+        let mut recorder = Recorder::new(path.to_str().unwrap(), 1, Palette::default());
+        recorder.capture(&frame(&[false; 8]), true).unwrap();
+        recorder.stop().unwrap();
+        assert!(!path.exists());
+    }
+}
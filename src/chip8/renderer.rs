@@ -0,0 +1,158 @@
+use super::chip8_mods::display::Damage;
+use super::palette::Palette;
+use super::phosphor::FilteredFrame;
+use ggez::graphics::{self, Color, DrawParam, FilterMode, Image, MeshBuilder};
+use ggez::{Context, GameResult};
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Scaling {
+    // only whole multiples of the display size, so every pixel has the same size
+    Integer,
+    // as large as the window allows while keeping the aspect ratio
+    Fit,
+}
+
+impl Scaling {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "integer" => Some(Scaling::Integer),
+            "fit" => Some(Scaling::Fit),
+            _ => None,
+        }
+    }
+}
+
+// The cached texture and what it was built from.
+struct UploadedFrame {
+    image: Image,
+    rgba: Vec<u8>,
+    palette: Palette,
+}
+
+pub struct Renderer {
+    scaling: Scaling,
+    grid: bool,
+    uploaded: Option<UploadedFrame>,
+}
+
+impl Renderer {
+    pub fn new(scaling: Scaling, grid: bool) -> Self {
+        Self {
+            scaling,
+            grid,
+            uploaded: None,
+        }
+    }
+
+    pub fn toggle_grid(&mut self) {
+        self.grid = !self.grid;
+    }
+
+    pub fn toggle_scaling(&mut self) {
+        self.scaling = match self.scaling {
+            Scaling::Integer => Scaling::Fit,
+            Scaling::Fit => Scaling::Integer,
+        };
+    }
+
+    // `damage` is what changed in the frame since the last draw.
+    pub fn draw(
+        &mut self,
+        ctx: &mut Context,
+        frame: &FilteredFrame,
+        damage: Damage,
+        palette: &Palette,
+    ) -> GameResult {
+        let columns = frame.columns;
+        let rows = frame.rows;
+        if columns == 0 || rows == 0 {
+            // nothing was emulated yet
+            graphics::clear(ctx, to_color(palette.background()));
+            return Ok(());
+        }
+
+        graphics::clear(ctx, to_color(palette.background()));
+
+        let screen = graphics::screen_coordinates(ctx);
+        let mut pixel_size = (screen.w / columns as f32).min(screen.h / rows as f32);
+        if self.scaling == Scaling::Integer {
+            pixel_size = pixel_size.floor().max(1.0);
+        }
+        let origin_x = ((screen.w - pixel_size * columns as f32) / 2.0).floor();
+        let origin_y = ((screen.h - pixel_size * rows as f32) / 2.0).floor();
+
+        let uploaded = match self.uploaded.take() {
+            Some(uploaded)
+                if uploaded.palette == *palette
+                    && uploaded.rgba.len() == frame.pixels() * 4 =>
+            {
+                Some(uploaded)
+            }
+            _ => None,
+        };
+        self.uploaded = match uploaded {
+            Some(uploaded) if damage.is_empty() => Some(uploaded),
+            Some(mut uploaded) => {
+                // only the changed rows are rendered again
+                palette.render_rgba_rows(frame, damage.rows(), &mut uploaded.rgba);
+                uploaded.image = upload(ctx, columns, rows, &uploaded.rgba)?;
+                Some(uploaded)
+            }
+            None => {
+                let rgba = palette.render_rgba(frame);
+                Some(UploadedFrame {
+                    image: upload(ctx, columns, rows, &rgba)?,
+                    rgba,
+                    palette: *palette,
+                })
+            }
+        };
+
+        let image = &self.uploaded.as_ref().unwrap().image;
+        graphics::draw(
+            ctx,
+            image,
+            DrawParam::new()
+                .dest([origin_x, origin_y])
+                .scale([pixel_size, pixel_size]),
+        )?;
+
+        if self.grid && pixel_size >= 3.0 {
+            let grid_color = grid_color(palette);
+            let width = pixel_size * columns as f32;
+            let height = pixel_size * rows as f32;
+            let mut builder = MeshBuilder::new();
+            for x_i in 0..=columns {
+                let x = origin_x + x_i as f32 * pixel_size;
+                builder.line(&[[x, origin_y], [x, origin_y + height]], 1.0, grid_color)?;
+            }
+            for y_i in 0..=rows {
+                let y = origin_y + y_i as f32 * pixel_size;
+                builder.line(&[[origin_x, y], [origin_x + width, y]], 1.0, grid_color)?;
+            }
+            let mesh = builder.build(ctx)?;
+            graphics::draw(ctx, &mesh, DrawParam::default())?;
+        }
+        Ok(())
+    }
+}
+
+fn upload(ctx: &mut Context, columns: usize, rows: usize, rgba: &[u8]) -> GameResult<Image> {
+    let mut image = Image::from_rgba8(ctx, columns as u16, rows as u16, rgba)?;
+    image.set_filter(FilterMode::Nearest);
+    Ok(image)
+}
+
+fn to_color(rgb: [u8; 3]) -> Color {
+    Color::from_rgb(rgb[0], rgb[1], rgb[2])
+}
+
+// halfway between background and foreground, so the grid is visible on lit and unlit pixels
+fn grid_color(palette: &Palette) -> Color {
+    let background = palette.background();
+    let foreground = palette.foreground();
+    let rgb = [0, 1, 2].map(|i| ((background[i] as u16 + foreground[i] as u16) / 2) as u8);
+    let mut color = to_color(rgb);
+    color.a = 0.35;
+    color
+}
@@ -0,0 +1,552 @@
+use super::batch::Task;
+use super::Chip8;
+use std::fmt;
+
+#[derive(Debug)]
+pub enum SpecError {
+    Toml(toml::de::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for SpecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SpecError::Toml(err) => write!(f, "{}", err),
+            SpecError::Invalid(message) => write!(f, "Invalid reward spec: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for SpecError {}
+
+impl From<toml::de::Error> for SpecError {
+    fn from(err: toml::de::Error) -> Self {
+        SpecError::Toml(err)
+    }
+}
+
+/// Where a number is read from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Source {
+    Register(usize),
+    // `bytes` bytes starting at `address`, big-endian, or one decimal digit per byte
+    // (as written by FX33) if `bcd` is set
+    Memory {
+        address: usize,
+        bytes: usize,
+        bcd: bool,
+    },
+}
+
+/// A number read from the machine, optionally reduced to a part of it, e.g. one
+/// digit of a register that holds two scores.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Value {
+    pub source: Source,
+    pub divide: i64,
+    pub modulo: Option<i64>,
+}
+
+impl Value {
+    pub fn read(&self, chip8: &Chip8) -> i64 {
+        let raw = match self.source {
+            Source::Register(x) => chip8.register(x) as i64,
+            Source::Memory {
+                address,
+                bytes,
+                bcd,
+            } => (address..address + bytes).fold(0, |value, address| {
+                // outside of memory reads as 0, so a spec can't stop the emulator
+                let byte = if address < chip8.memory_size() {
+                    chip8.peek(address) as i64
+                } else {
+                    0
+                };
+                if bcd {
+                    value * 10 + byte
+                } else {
+                    (value << 8) | byte
+                }
+            }),
+        };
+        let value = raw / self.divide;
+        match self.modulo {
+            Some(modulo) => value % modulo,
+            None => value,
+        }
+    }
+}
+
+/// One part of the score: a value times a weight. Negative weights punish, e.g.
+/// points of the opponent or lost lives.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Term {
+    pub name: String,
+    pub value: Value,
+    pub weight: f32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Condition {
+    Equals(Value, i64),
+    AtMost(Value, i64),
+    AtLeast(Value, i64),
+    // the program jumps to itself, the usual way to stop
+    Halted,
+    // the program waits for a key with FX0A
+    WaitingForKey,
+}
+
+impl Condition {
+    pub fn holds(&self, chip8: &Chip8) -> bool {
+        let pc = chip8.pc() as usize;
+        let opcode = || {
+            if pc + 1 < chip8.memory_size() {
+                ((chip8.peek(pc) as u16) << 8) | chip8.peek(pc + 1) as u16
+            } else {
+                0
+            }
+        };
+        match self {
+            Condition::Equals(value, limit) => value.read(chip8) == *limit,
+            Condition::AtMost(value, limit) => value.read(chip8) <= *limit,
+            Condition::AtLeast(value, limit) => value.read(chip8) >= *limit,
+            Condition::Halted => opcode() == 0x1000 | pc as u16,
+            Condition::WaitingForKey => opcode() & 0xF0FF == 0xF00A,
+        }
+    }
+}
+
+/// Reads the score and the end of an episode from a game's memory and registers,
+/// as described by a TOML spec:
+///
+/// ```toml
+/// title = "Example"
+/// verified = false
+/// # reward per frame survived
+/// per_frame = 0.0
+///
+/// [[reward]]
+/// name = "score"
+/// register = 0x5
+/// weight = 1.0
+///
+/// [[done]]
+/// register = 0xE
+/// at_most = 0
+/// ```
+///
+/// A value is read from a `register`, or from `address` (`bytes` long, big-endian,
+/// or one digit per byte with `bcd = true`), and can be reduced with `divide` and
+/// `modulo`. Episodes end when any `done` condition holds: `equals`, `at_most` or
+/// `at_least` on a value, `halted = true` or `waiting_for_key = true`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RewardSpec {
+    pub title: String,
+    // whether the addresses were checked against the ROM
+    pub verified: bool,
+    pub per_frame: f32,
+    pub terms: Vec<Term>,
+    pub done: Vec<Condition>,
+}
+
+impl RewardSpec {
+    pub fn from_toml(text: &str) -> Result<Self, SpecError> {
+        let spec: toml::Value = toml::from_str(text)?;
+        let title = spec
+            .get("title")
+            .and_then(|title| title.as_str())
+            .unwrap_or_default()
+            .to_string();
+        let verified = spec
+            .get("verified")
+            .and_then(|verified| verified.as_bool())
+            .unwrap_or(false);
+        let per_frame = match spec.get("per_frame") {
+            Some(per_frame) => number(per_frame, "per_frame")? as f32,
+            None => 0.0,
+        };
+
+        let mut terms = Vec::new();
+        for term in tables(&spec, "reward")? {
+            terms.push(Term {
+                name: term
+                    .get("name")
+                    .and_then(|name| name.as_str())
+                    .unwrap_or("reward")
+                    .to_string(),
+                value: parse_value(term)?,
+                weight: match term.get("weight") {
+                    Some(weight) => number(weight, "weight")? as f32,
+                    None => 1.0,
+                },
+            });
+        }
+
+        let mut done = Vec::new();
+        for condition in tables(&spec, "done")? {
+            let flag = |name: &str| condition.get(name).and_then(|flag| flag.as_bool());
+            let limit = |name: &str| condition.get(name).and_then(|limit| limit.as_integer());
+            done.push(if flag("halted") == Some(true) {
+                Condition::Halted
+            } else if flag("waiting_for_key") == Some(true) {
+                Condition::WaitingForKey
+            } else if let Some(limit) = limit("equals") {
+                Condition::Equals(parse_value(condition)?, limit)
+            } else if let Some(limit) = limit("at_most") {
+                Condition::AtMost(parse_value(condition)?, limit)
+            } else if let Some(limit) = limit("at_least") {
+                Condition::AtLeast(parse_value(condition)?, limit)
+            } else {
+                return Err(SpecError::Invalid(
+                    "a done condition needs equals, at_most, at_least, halted or waiting_for_key"
+                        .to_string(),
+                ));
+            });
+        }
+
+        Ok(Self {
+            title,
+            verified,
+            per_frame,
+            terms,
+            done,
+        })
+    }
+
+    pub fn is_done(&self, chip8: &Chip8) -> bool {
+        self.done.iter().any(|condition| condition.holds(chip8))
+    }
+}
+
+impl Task for RewardSpec {
+    fn score(&self, chip8: &Chip8, frames: u64) -> f32 {
+        self.terms
+            .iter()
+            .map(|term| term.value.read(chip8) as f32 * term.weight)
+            .sum::<f32>()
+            + self.per_frame * frames as f32
+    }
+
+    fn is_done(&self, chip8: &Chip8) -> bool {
+        RewardSpec::is_done(self, chip8)
+    }
+}
+
+/// Runs up to `frames` frames and prints every change of the score and where the
+/// episode ends, to check a spec against a ROM.
+pub fn watch(mut chip8: Chip8, spec: &RewardSpec, frames: u32) {
+    if !spec.verified {
+        println!("The reward spec for {} isn't verified yet.", spec.title);
+    }
+    let read = |chip8: &Chip8| -> Vec<i64> {
+        spec.terms
+            .iter()
+            .map(|term| term.value.read(chip8))
+            .collect()
+    };
+    let mut values = read(&chip8);
+    for frame in 1..=frames as u64 {
+        if let Err(fault) = chip8.run_frame() {
+            println!("The emulator stopped at frame {}: {}", frame, fault);
+            break;
+        }
+        let current = read(&chip8);
+        if current != values {
+            let terms: Vec<String> = spec
+                .terms
+                .iter()
+                .zip(current.iter())
+                .map(|(term, value)| format!("{}={}", term.name, value))
+                .collect();
+            println!(
+                "Frame {}: score {} ({})",
+                frame,
+                spec.score(&chip8, frame),
+                terms.join(", ")
+            );
+            values = current;
+        }
+        if spec.is_done(&chip8) {
+            println!(
+                "Episode ended at frame {} with score {}",
+                frame,
+                spec.score(&chip8, frame)
+            );
+            break;
+        }
+    }
+    chip8.shut_down();
+}
+
+fn tables<'a>(spec: &'a toml::Value, key: &str) -> Result<Vec<&'a toml::Value>, SpecError> {
+    match spec.get(key) {
+        None => Ok(Vec::new()),
+        Some(toml::Value::Array(tables)) if tables.iter().all(|table| table.is_table()) => {
+            Ok(tables.iter().collect())
+        }
+        Some(_) => Err(SpecError::Invalid(format!(
+            "{} has to be an array of tables, e.g. [[{}]]",
+            key, key
+        ))),
+    }
+}
+
+fn number(value: &toml::Value, name: &str) -> Result<f64, SpecError> {
+    value
+        .as_float()
+        .or_else(|| value.as_integer().map(|value| value as f64))
+        .ok_or_else(|| SpecError::Invalid(format!("{} has to be a number", name)))
+}
+
+fn parse_value(table: &toml::Value) -> Result<Value, SpecError> {
+    let integer = |name: &str| table.get(name).and_then(|value| value.as_integer());
+    let source = match (integer("register"), integer("address")) {
+        (Some(x), None) if (0..16).contains(&x) => Source::Register(x as usize),
+        (None, Some(address)) if (0..0x10000).contains(&address) => Source::Memory {
+            address: address as usize,
+            bytes: match integer("bytes") {
+                Some(bytes) if (1..=4).contains(&bytes) => bytes as usize,
+                Some(bytes) => {
+                    return Err(SpecError::Invalid(format!(
+                        "bytes has to be between 1 and 4, not {}",
+                        bytes
+                    )))
+                }
+                None => 1,
+            },
+            bcd: table
+                .get("bcd")
+                .and_then(|bcd| bcd.as_bool())
+                .unwrap_or(false),
+        },
+        _ => {
+            return Err(SpecError::Invalid(
+                "a value needs either a register (0-15) or a memory address".to_string(),
+            ))
+        }
+    };
+    let divide = integer("divide").unwrap_or(1);
+    let modulo = integer("modulo");
+    if divide <= 0 || modulo.is_some_and(|modulo| modulo <= 0) {
+        return Err(SpecError::Invalid(
+            "divide and modulo have to be positive".to_string(),
+        ));
+    }
+    Ok(Value {
+        source,
+        divide,
+        modulo,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::chip8_mods::memory::Layout;
+    use crate::chip8::rom::Rom;
+
+    const SPEC: &str = r#"
+        title = "Test"
+        verified = true
+        per_frame = 0.5
+
+        [[reward]]
+        name = "score"
+        address = 0x300
+        bytes = 3
+        bcd = true
+
+        [[reward]]
+        name = "lives"
+        register = 0xE
+        weight = -2
+
+        [[reward]]
+        register = 0x1
+        divide = 16
+        modulo = 4
+
+        [[done]]
+        register = 0xE
+        at_most = 0
+
+        [[done]]
+        halted = true
+    "#;
+
+    fn chip8(program: &[u8]) -> Chip8 {
+        Chip8::from_rom(&Rom::from_bytes("test.ch8", program), Layout::Chip8).unwrap()
+    }
+
+    #[test]
+    fn reads_specs() {
+        let spec = RewardSpec::from_toml(SPEC).unwrap();
+        assert_eq!(spec.title, "Test");
+        assert!(spec.verified);
+        assert_eq!(spec.per_frame, 0.5);
+        assert_eq!(spec.terms.len(), 3);
+        assert_eq!(
+            spec.terms[0].value.source,
+            Source::Memory {
+                address: 0x300,
+                bytes: 3,
+                bcd: true,
+            }
+        );
+        assert_eq!(spec.terms[1].weight, -2.0);
+        assert_eq!(spec.terms[2].name, "reward");
+        assert_eq!(spec.terms[2].weight, 1.0);
+        assert_eq!(
+            spec.terms[2].value,
+            Value {
+                source: Source::Register(1),
+                divide: 16,
+                modulo: Some(4),
+            }
+        );
+        assert_eq!(spec.done.len(), 2);
+        assert_eq!(spec.done[1], Condition::Halted);
+
+        let empty = RewardSpec::from_toml("").unwrap();
+        assert_eq!(empty.title, "");
+        assert!(!empty.verified);
+        assert!(empty.terms.is_empty() && empty.done.is_empty());
+    }
+
+    #[test]
+    fn rejects_invalid_specs() {
+        let invalid = [
+            "title = ",
+            "reward = 1",
+            "[[reward]]\nweight = 1.0",
+            "[[reward]]\nregister = 16",
+            "[[reward]]\nregister = 1\naddress = 0x300",
+            "[[reward]]\naddress = 0x300\nbytes = 5",
+            "[[reward]]\nregister = 1\ndivide = 0",
+            "[[reward]]\nregister = 1\nmodulo = -3",
+            "[[reward]]\nregister = 1\nweight = \"high\"",
+            "[[done]]\nregister = 1",
+            "[[done]]\nat_most = 0",
+        ];
+        for text in invalid {
+            assert!(RewardSpec::from_toml(text).is_err(), "{}", text);
+        }
+        assert!(matches!(
+            RewardSpec::from_toml("title = "),
+            Err(SpecError::Toml(_))
+        ));
+    }
+
+    #[test]
+    fn scores_the_machine() {
+        // 6E03 LD VE, 03, 61B7 LD V1, B7, 1204 JP 204
+        let mut chip8 = chip8(&[0x6E, 0x03, 0x61, 0xB7, 0x12, 0x04]);
+        for (offset, digit) in [1, 2, 5].iter().enumerate() {
+            chip8.memory.poke(0x300 + offset, *digit);
+        }
+        let spec = RewardSpec::from_toml(SPEC).unwrap();
+        // VE starts at 0
+        assert!(spec.is_done(&chip8));
+        chip8.step().unwrap();
+        assert!(!spec.is_done(&chip8));
+        chip8.step().unwrap();
+        let values: Vec<i64> = spec
+            .terms
+            .iter()
+            .map(|term| term.value.read(&chip8))
+            .collect();
+        // 0xB7 / 16 = 11, 11 % 4 = 3
+        assert_eq!(values, [125, 3, 3]);
+        assert_eq!(spec.score(&chip8, 10), 125.0 - 6.0 + 3.0 + 5.0);
+        // now at JP 204
+        assert!(spec.is_done(&chip8));
+    }
+
+    #[test]
+    fn reads_memory_big_endian() {
+        let mut chip8 = chip8(&[0x12, 0x00]);
+        chip8.memory.poke(0xFFE, 0x12);
+        chip8.memory.poke(0xFFF, 0x34);
+        let value = |address, bytes| Value {
+            source: Source::Memory {
+                address,
+                bytes,
+                bcd: false,
+            },
+            divide: 1,
+            modulo: None,
+        };
+        assert_eq!(value(0xFFE, 2).read(&chip8), 0x1234);
+        // past the end of memory reads as 0
+        assert_eq!(value(0xFFE, 4).read(&chip8), 0x12340000);
+    }
+
+    #[test]
+    fn ends_while_waiting_for_a_key() {
+        // F30A LD V3, K
+        let mut chip8 = chip8(&[0xF3, 0x0A]);
+        let condition = Condition::WaitingForKey;
+        assert!(condition.holds(&chip8));
+        chip8.run_frame().unwrap();
+        assert!(condition.holds(&chip8));
+        assert!(!Condition::Halted.holds(&chip8));
+    }
+
+    fn shipped(text: &str) -> RewardSpec {
+        let spec = RewardSpec::from_toml(text).unwrap();
+        assert!(!spec.done.is_empty(), "{}", spec.title);
+        spec
+    }
+
+    // Steps through `program` and returns the score and whether the episode is over
+    // after every instruction.
+    fn play(spec: &RewardSpec, program: &[u8]) -> Vec<(f32, bool)> {
+        let mut chip8 = chip8(program);
+        (0..program.len() / 2)
+            .map(|frame| {
+                chip8.step().unwrap();
+                (spec.score(&chip8, frame as u64), spec.is_done(&chip8))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn scores_pong() {
+        let spec = shipped(include_str!("../../specs/pong.toml"));
+        // 6E0A LD VE, 10, 7E01 ADD VE, 1, 7E28 ADD VE, 40, 7E08 ADD VE, 8
+        let program = [0x6E, 0x0A, 0x7E, 0x01, 0x7E, 0x28, 0x7E, 0x08];
+        assert_eq!(
+            play(&spec, &program),
+            [(1.0, false), (0.0, false), (4.0, false), (-4.0, true)]
+        );
+    }
+
+    #[test]
+    fn scores_breakout() {
+        let spec = shipped(include_str!("../../specs/breakout.toml"));
+        // 6E02 LD VE, 02, 7503 ADD V5, 03, 7EFF ADD VE, FF, 7501 ADD V5, 01, 7EFF ADD VE, FF
+        let program = [0x6E, 0x02, 0x75, 0x03, 0x7E, 0xFF, 0x75, 0x01, 0x7E, 0xFF];
+        assert_eq!(
+            play(&spec, &program),
+            [
+                (0.0, false),
+                (3.0, false),
+                (3.0, false),
+                (4.0, false),
+                (4.0, true)
+            ]
+        );
+    }
+
+    #[test]
+    fn scores_tetris() {
+        let spec = shipped(include_str!("../../specs/tetris.toml"));
+        // 6001 LD V0, 01, 6002 LD V0, 02, 1204 JP 204
+        let program = [0x60, 0x01, 0x60, 0x02, 0x12, 0x04];
+        assert_eq!(
+            play(&spec, &program),
+            [(0.0, false), (0.01, true), (0.02, true)]
+        );
+    }
+}
@@ -0,0 +1,413 @@
+use super::chip8_mods::memory::Layout;
+use super::palette::{self, Palette};
+use super::quirks::Quirks;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::Path;
+
+// Extensions used for CHIP-8 programs, used to pick a ROM out of a zip archive.
+const ROM_EXTENSIONS: [&str; 5] = ["ch8", "c8", "sc8", "xo8", "rom"];
+
+#[derive(Debug)]
+pub enum RomError {
+    Io(io::Error),
+    Zip(String),
+    Cartridge(String),
+    TooLarge { size: usize, capacity: usize },
+}
+
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RomError::Io(err) => write!(f, "{}", err),
+            RomError::Zip(message) => write!(f, "Invalid zip archive: {}", message),
+            RomError::Cartridge(message) => write!(f, "Invalid Octo cartridge: {}", message),
+            RomError::TooLarge { size, capacity } => write!(
+                f,
+                "Program is {} bytes, but only {} bytes of memory are available.",
+                size, capacity
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RomError {}
+
+impl From<io::Error> for RomError {
+    fn from(err: io::Error) -> Self {
+        RomError::Io(err)
+    }
+}
+
+pub struct Rom {
+    pub name: String,
+    pub bytes: Vec<u8>,
+}
+
+impl Rom {
+    pub fn from_bytes(name: &str, bytes: &[u8]) -> Self {
+        Self {
+            name: name.to_string(),
+            bytes: bytes.to_vec(),
+        }
+    }
+
+    pub fn from_reader(name: &str, mut reader: impl Read) -> Result<Self, RomError> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        Ok(Self {
+            name: name.to_string(),
+            bytes,
+        })
+    }
+
+    pub fn from_stdin() -> Result<Self, RomError> {
+        Self::from_reader("stdin", io::stdin().lock())
+    }
+
+    pub fn from_file(path: &str) -> Result<Self, RomError> {
+        Ok(Self {
+            name: path.to_string(),
+            bytes: fs::read(path)?,
+        })
+    }
+
+    /// Reads `entry` from a zip archive, or the first file with a ROM extension if it is `None`.
+    pub fn from_zip(path: &str, entry: Option<&str>) -> Result<Self, RomError> {
+        let mut archive = zip::ZipArchive::new(File::open(path)?)
+            .map_err(|err| RomError::Zip(err.to_string()))?;
+        let name = match entry {
+            Some(entry) => entry.to_string(),
+            // in archive order, file_names() goes through a HashMap
+            None => (0..archive.len())
+                .find_map(|index| {
+                    let name = archive.by_index_raw(index).ok()?.name().to_string();
+                    has_rom_extension(&name).then_some(name)
+                })
+                .ok_or_else(|| RomError::Zip(format!("{} contains no CHIP-8 program", path)))?,
+        };
+        let file = archive
+            .by_name(&name)
+            .map_err(|err| RomError::Zip(format!("{}: {}", name, err)))?;
+        Self::from_reader(&format!("{}:{}", path, name), file)
+    }
+
+    /// Loads a ROM from a file path, `-` for stdin, `archive.zip` or `archive.zip:entry`.
+    pub fn load(source: &str) -> Result<Self, RomError> {
+        if source == "-" {
+            return Self::from_stdin();
+        }
+        if let Some(index) = source.to_ascii_lowercase().find(".zip") {
+            let (archive, rest) = source.split_at(index + 4);
+            match rest.strip_prefix(':') {
+                Some(entry) => return Self::from_zip(archive, Some(entry)),
+                None if rest.is_empty() => return Self::from_zip(archive, None),
+                None => (),
+            }
+        }
+        Self::from_file(source)
+    }
+
+    pub fn check_fits(&self, layout: Layout) -> Result<(), RomError> {
+        let capacity = layout.size() - layout.program_start();
+        if self.bytes.len() > capacity {
+            return Err(RomError::TooLarge {
+                size: self.bytes.len(),
+                capacity,
+            });
+        }
+        Ok(())
+    }
+}
+
+fn has_extension(name: &str, extension: &str) -> bool {
+    Path::new(name)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| ext.eq_ignore_ascii_case(extension))
+}
+
+fn has_rom_extension(name: &str) -> bool {
+    ROM_EXTENSIONS
+        .iter()
+        .any(|extension| has_extension(name, extension))
+}
+
+/// Settings stored in an Octo cartridge, `None` where the cartridge does not set them.
+#[derive(Debug, Clone, Default)]
+pub struct OctoOptions {
+    // instructions per frame
+    pub tickrate: Option<u32>,
+    pub colors: [Option<[u8; 3]>; 4],
+    pub shift: Option<bool>,
+    pub load_store: Option<bool>,
+    pub jump: Option<bool>,
+    pub logic: Option<bool>,
+    pub clip: Option<bool>,
+    pub vblank: Option<bool>,
+}
+
+impl OctoOptions {
+    pub fn from_json(options: &serde_json::Value) -> Self {
+        let flag = |key: &str| options.get(key).and_then(|value| value.as_bool());
+        let color = |key: &str| {
+            options
+                .get(key)
+                .and_then(|value| value.as_str())
+                .and_then(palette::parse_hex_color)
+        };
+        Self {
+            tickrate: options
+                .get("tickrate")
+                .and_then(|value| value.as_u64())
+                .map(|tickrate| tickrate as u32),
+            colors: [
+                color("backgroundColor"),
+                color("fillColor"),
+                color("fillColor2"),
+                color("blendColor"),
+            ],
+            shift: flag("shiftQuirks"),
+            load_store: flag("loadStoreQuirks"),
+            jump: flag("jumpQuirks"),
+            logic: flag("logicQuirks"),
+            clip: flag("clipQuirks"),
+            vblank: flag("vBlankQuirks"),
+        }
+    }
+
+    pub fn apply_quirks(&self, quirks: &mut Quirks) {
+        if let Some(shift) = self.shift {
+            quirks.shift = shift;
+        }
+        if let Some(load_store) = self.load_store {
+            quirks.memory_leave_i_unchanged = load_store;
+            quirks.memory_increment_by_x = false;
+        }
+        if let Some(jump) = self.jump {
+            quirks.jump = jump;
+        }
+        if let Some(logic) = self.logic {
+            quirks.logic = logic;
+        }
+        if let Some(clip) = self.clip {
+            quirks.wrap = !clip;
+        }
+        if let Some(vblank) = self.vblank {
+            quirks.vblank = vblank;
+        }
+    }
+
+    pub fn apply_palette(&self, palette: &mut Palette) {
+        for (color, option) in palette.colors.iter_mut().zip(self.colors.iter()) {
+            if let Some(option) = option {
+                *color = *option;
+            }
+        }
+    }
+}
+
+/// An Octo "cartridge": a GIF whose pixels carry the program and its options.
+/// The program is Octo source, so only the options are of use to the emulator.
+pub struct OctoCartridge {
+    pub source: String,
+    pub options: OctoOptions,
+}
+
+impl OctoCartridge {
+    // Every pixel stores two bits of the payload in the low bits of its palette
+    // index. The payload is a big-endian 32-bit length followed by that many
+    // bytes of JSON: {"program": <Octo source>, "options": {...}}.
+    pub fn load(path: &str) -> Result<Self, RomError> {
+        let mut options = gif::DecodeOptions::new();
+        options.set_color_output(gif::ColorOutput::Indexed);
+        let mut decoder = options
+            .read_info(File::open(path)?)
+            .map_err(|err| RomError::Cartridge(err.to_string()))?;
+
+        let mut payload = Vec::new();
+        let mut current = 0u8;
+        let mut bits = 0;
+        while let Some(frame) = decoder
+            .read_next_frame()
+            .map_err(|err| RomError::Cartridge(err.to_string()))?
+        {
+            for index in frame.buffer.iter() {
+                current = (current << 2) | (index & 3);
+                bits += 2;
+                if bits == 8 {
+                    payload.push(current);
+                    current = 0;
+                    bits = 0;
+                }
+            }
+        }
+
+        if payload.len() < 4 {
+            return Err(RomError::Cartridge("no payload".to_string()));
+        }
+        let size = u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]) as usize;
+        let json = payload
+            .get(4..4 + size)
+            .ok_or_else(|| RomError::Cartridge("payload is truncated".to_string()))?;
+        let value: serde_json::Value =
+            serde_json::from_slice(json).map_err(|err| RomError::Cartridge(err.to_string()))?;
+
+        Ok(Self {
+            source: value
+                .get("program")
+                .and_then(|program| program.as_str())
+                .unwrap_or_default()
+                .to_string(),
+            options: value
+                .get("options")
+                .map(OctoOptions::from_json)
+                .unwrap_or_default(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("rs_chip8-{}-{}", std::process::id(), name))
+    }
+
+    fn write_zip(name: &str, files: &[(&str, &[u8])]) -> String {
+        let path = temp_path(name);
+        let mut zip = zip::ZipWriter::new(File::create(&path).unwrap());
+        for (name, bytes) in files {
+            zip.start_file(*name, zip::write::FileOptions::default())
+                .unwrap();
+            zip.write_all(bytes).unwrap();
+        }
+        zip.finish().unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn loads_files() {
+        let path = temp_path("plain.ch8");
+        fs::write(&path, [0x12, 0x00]).unwrap();
+        let rom = Rom::load(path.to_str().unwrap()).unwrap();
+        assert_eq!(rom.bytes, [0x12, 0x00]);
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(
+            Rom::load(path.to_str().unwrap()),
+            Err(RomError::Io(_))
+        ));
+    }
+
+    #[test]
+    fn loads_from_zip_archives() {
+        let archive = write_zip(
+            "games.zip",
+            &[
+                ("README.txt", b"not a program"),
+                ("games/pong.CH8", &[0x00, 0xE0]),
+                ("games/brix.ch8", &[0x12, 0x00]),
+            ],
+        );
+        // the first file with a ROM extension
+        let rom = Rom::load(&archive).unwrap();
+        assert_eq!(rom.bytes, [0x00, 0xE0]);
+        assert_eq!(rom.name, format!("{}:games/pong.CH8", archive));
+
+        let rom = Rom::load(&format!("{}:games/brix.ch8", archive)).unwrap();
+        assert_eq!(rom.bytes, [0x12, 0x00]);
+        assert!(matches!(
+            Rom::load(&format!("{}:games/missing.ch8", archive)),
+            Err(RomError::Zip(_))
+        ));
+        fs::remove_file(&archive).unwrap();
+    }
+
+    #[test]
+    fn rejects_archives_without_programs() {
+        let archive = write_zip("empty.zip", &[("README.txt", b"nothing to run")]);
+        assert!(matches!(Rom::load(&archive), Err(RomError::Zip(_))));
+        fs::remove_file(&archive).unwrap();
+
+        let path = temp_path("broken.zip");
+        fs::write(&path, b"not a zip archive").unwrap();
+        assert!(matches!(
+            Rom::load(path.to_str().unwrap()),
+            Err(RomError::Zip(_))
+        ));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn checks_the_size() {
+        let capacity = 0x1000 - 0x200;
+        assert!(Rom::from_bytes("fits", &vec![0; capacity])
+            .check_fits(Layout::Chip8)
+            .is_ok());
+        assert!(matches!(
+            Rom::from_bytes("too large", &vec![0; capacity + 1]).check_fits(Layout::Chip8),
+            Err(RomError::TooLarge { size, capacity: 0xE00 }) if size == capacity + 1
+        ));
+        // the ETI 660 loads programs at 0x600
+        assert!(Rom::from_bytes("eti", &vec![0; capacity])
+            .check_fits(Layout::Eti660)
+            .is_err());
+        assert!(Rom::from_bytes("large", &vec![0; 0x8000])
+            .check_fits(Layout::Extended)
+            .is_ok());
+    }
+
+    #[test]
+    fn reads_cartridges() {
+        let json = br##"{"program": ": main jump main", "options": {"tickrate": 20, "fillColor": "#FF0000", "shiftQuirks": true, "clipQuirks": true}}"##;
+        let mut payload = (json.len() as u32).to_be_bytes().to_vec();
+        payload.extend_from_slice(json);
+        // two bits a pixel, high bits first, on top of an arbitrary palette index
+        let pixels: Vec<u8> = payload
+            .iter()
+            .flat_map(|byte| [6, 4, 2, 0].map(|shift| 4 | ((byte >> shift) & 3)))
+            .collect();
+        let width = 64;
+        let height = pixels.len().div_ceil(width) as u16;
+        let mut buffer = pixels.clone();
+        buffer.resize(width * height as usize, 4);
+
+        let path = temp_path("cartridge.gif");
+        let palette: Vec<u8> = (0..8).flat_map(|index| [index * 30; 3]).collect();
+        let mut encoder =
+            gif::Encoder::new(File::create(&path).unwrap(), width as u16, height, &palette)
+                .unwrap();
+        let frame = gif::Frame {
+            width: width as u16,
+            height,
+            buffer: buffer.into(),
+            ..Default::default()
+        };
+        encoder.write_frame(&frame).unwrap();
+        drop(encoder);
+
+        let cartridge = OctoCartridge::load(path.to_str().unwrap()).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(cartridge.source, ": main jump main");
+        let options = cartridge.options;
+        assert_eq!(options.tickrate, Some(20));
+        assert_eq!(options.colors[1], Some([0xFF, 0, 0]));
+        assert_eq!(options.colors[0], None);
+        assert_eq!(options.jump, None);
+
+        let mut quirks = Quirks {
+            shift: false,
+            wrap: true,
+            ..Default::default()
+        };
+        let jump = quirks.jump;
+        options.apply_quirks(&mut quirks);
+        assert!(quirks.shift);
+        assert!(!quirks.wrap);
+        assert_eq!(quirks.jump, jump);
+    }
+}
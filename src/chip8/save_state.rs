@@ -0,0 +1,386 @@
+use super::Chip8;
+use rand::SeedableRng;
+use rand_chacha::ChaCha12Rng;
+use std::fmt;
+
+const MAGIC: &[u8; 4] = b"C8SS";
+const VERSION: u8 = 1;
+
+// `released` when FX0A hasn't seen a key released
+const NO_KEY: u8 = 0xFF;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StateError {
+    NotASaveState,
+    UnsupportedVersion(u8),
+    Truncated,
+    Corrupt,
+    // the state was saved with a different memory size, stack depth or display size
+    Mismatch(&'static str),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::NotASaveState => write!(f, "Not a save state."),
+            StateError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported save state version {}.", version)
+            }
+            StateError::Truncated => write!(f, "Save state is truncated."),
+            StateError::Corrupt => write!(f, "Save state is corrupt."),
+            StateError::Mismatch(what) => {
+                write!(f, "Save state was made with a different {}.", what)
+            }
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        if self.bytes.len() < len {
+            return Err(StateError::Truncated);
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, StateError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, StateError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, StateError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn u128(&mut self) -> Result<u128, StateError> {
+        Ok(u128::from_le_bytes(self.take(16)?.try_into().unwrap()))
+    }
+}
+
+// A save state holds the machine, not the configuration: quirks, speed, memory
+// layout and stack depth have to match between saving and loading. All numbers
+// are little-endian.
+impl Chip8 {
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = Vec::with_capacity(self.memory.size() + 512);
+        state.extend_from_slice(MAGIC);
+        state.push(VERSION);
+        state.extend_from_slice(&self.cycle.to_le_bytes());
+        state.extend_from_slice(&self.pc.get_point_value().to_le_bytes());
+        state.extend_from_slice(&self.i.get().to_le_bytes());
+        for register in self.variable_registers.iter() {
+            state.push(register.get());
+        }
+        state.push(self.delay_timer.get_value());
+        state.push(self.sound_timer.get_value());
+
+        // CXNN continues with the same random numbers after loading
+        state.extend_from_slice(&self.rng.get_seed());
+        state.extend_from_slice(&self.rng.get_stream().to_le_bytes());
+        state.extend_from_slice(&self.rng.get_word_pos().to_le_bytes());
+        let (waiting_for_release, released) = self.keypad.wait_state();
+        state.push(waiting_for_release as u8);
+        state.push(released.unwrap_or(NO_KEY));
+
+        let entries = self.stack.entries();
+        // `--stack-depth` keeps the depth below 0x10000
+        state.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+        state.extend_from_slice(&(self.stack.get_pointer() as u16).to_le_bytes());
+        for entry in entries.iter() {
+            state.extend_from_slice(&entry.to_le_bytes());
+        }
+
+        // one bit per pixel, plane by plane and row by row, leftmost pixel in the highest bit
+        let bytes_per_row = self.display.width() / 8;
+        state.push(bytes_per_row as u8);
+        state.push(self.display.height() as u8);
+        state.push(self.display.planes() as u8);
+        for plane in 0..self.display.planes() {
+            for row in self.display.rows(plane).iter() {
+                state.extend_from_slice(&row.to_be_bytes()[16 - bytes_per_row..]);
+            }
+        }
+
+        state.extend_from_slice(&(self.memory.size() as u32).to_le_bytes());
+        for address in 0..self.memory.size() {
+            state.push(self.memory.peek(address));
+        }
+        state
+    }
+
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), StateError> {
+        let mut reader = Reader { bytes: state };
+        if reader.take(4).map_err(|_| StateError::NotASaveState)? != MAGIC {
+            return Err(StateError::NotASaveState);
+        }
+        let version = reader.u8()?;
+        if version != VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+        let cycle = reader.u64()?;
+        let pc = reader.u32()?;
+        let i = reader.u16()?;
+        let registers = reader.take(16)?;
+        let delay_timer = reader.u8()?;
+        let sound_timer = reader.u8()?;
+
+        let seed: [u8; 32] = reader.take(32)?.try_into().unwrap();
+        let stream = reader.u64()?;
+        let word_pos = reader.u128()?;
+        let waiting_for_release = match reader.u8()? {
+            0 => false,
+            1 => true,
+            _ => return Err(StateError::Corrupt),
+        };
+        let released = match reader.u8()? {
+            NO_KEY => None,
+            key if key < 16 => Some(key),
+            _ => return Err(StateError::Corrupt),
+        };
+
+        let depth = reader.u16()? as usize;
+        let pointer = reader.u16()? as usize;
+        if depth != self.stack.entries().len() || pointer > depth {
+            return Err(StateError::Mismatch("stack depth"));
+        }
+        let mut entries = Vec::with_capacity(depth);
+        for _ in 0..depth {
+            entries.push(reader.u16()?);
+        }
+
+        let bytes_per_row = reader.u8()? as usize;
+        let height = reader.u8()? as usize;
+        let planes = reader.u8()? as usize;
+        if bytes_per_row * 8 != self.display.width()
+            || height != self.display.height()
+            || planes != self.display.planes()
+        {
+            return Err(StateError::Mismatch("display size"));
+        }
+        let mut display = Vec::with_capacity(planes * height);
+        for row in reader
+            .take(planes * height * bytes_per_row)?
+            .chunks_exact(bytes_per_row)
+        {
+            let mut bytes = [0; 16];
+            bytes[16 - bytes_per_row..].copy_from_slice(row);
+            display.push(u128::from_be_bytes(bytes));
+        }
+
+        let memory_size = reader.u32()? as usize;
+        if memory_size != self.memory.size() {
+            return Err(StateError::Mismatch("memory size"));
+        }
+        let memory = reader.take(memory_size)?;
+        if pc as usize >= memory_size {
+            return Err(StateError::Corrupt);
+        }
+
+        // everything is read, so nothing below can fail halfway
+        self.cycle = cycle;
+        self.pc.set_point_value(pc);
+        self.i.set(i);
+        for (register, value) in self.variable_registers.iter_mut().zip(registers.iter()) {
+            register.set(*value);
+        }
+        self.delay_timer.set_value(delay_timer);
+        self.sound_timer.set_value(sound_timer);
+        self.rng = ChaCha12Rng::from_seed(seed);
+        self.rng.set_stream(stream);
+        self.rng.set_word_pos(word_pos);
+        self.keypad
+            .restore_wait_state(waiting_for_release, released);
+        self.stack.restore(&entries, pointer);
+        for (plane, rows) in display.chunks_exact(height).enumerate() {
+            self.display.restore(plane, rows);
+        }
+        for (address, value) in memory.iter().enumerate() {
+            self.memory.poke(address, *value);
+        }
+        if let Some(block_cache) = &mut self.block_cache {
+            *block_cache = super::block_cache::BlockCache::new(self.memory.size());
+        }
+        self.waiting_for_vblank = false;
+        self.fault = None;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::chip8_mods::memory::Layout;
+    use crate::chip8::chip8_mods::stack::Stack;
+    use crate::chip8::rom::Rom;
+
+    // 0x200 2206  CALL 206
+    // 0x202 C0FF  RND V0, FF
+    // 0x204 1202  JP 202
+    // 0x206 A000  LD I, 000
+    // 0x208 D015  DRW V0, V1, 5
+    // 0x20A F20A  LD V2, K
+    // 0x20C 00EE  RET
+    const PROGRAM: [u8; 14] = [
+        0x22, 0x06, 0xC0, 0xFF, 0x12, 0x02, 0xA0, 0x00, 0xD0, 0x15, 0xF2, 0x0A, 0x00, 0xEE,
+    ];
+
+    fn chip8(layout: Layout) -> Chip8 {
+        let mut chip8 = Chip8::from_rom(&Rom::from_bytes("test.ch8", &PROGRAM), layout).unwrap();
+        chip8.seed_random(3);
+        chip8
+    }
+
+    // Runs into FX0A and taps a key, so the state is taken before FX0A sees the release.
+    fn waiting() -> Chip8 {
+        let mut chip8 = chip8(Layout::Chip8);
+        chip8.run_frame().unwrap();
+        chip8.set_keys(1 << 7);
+        chip8.set_keys(0);
+        chip8
+    }
+
+    fn random_numbers(chip8: &mut Chip8) -> Vec<u8> {
+        (0..8)
+            .map(|_| {
+                chip8.step().unwrap();
+                chip8.step().unwrap();
+                chip8.register(0)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_trips() {
+        let mut chip8 = waiting();
+        let state = chip8.save_state();
+        let mut loaded = self::chip8(Layout::Chip8);
+        loaded.load_state(&state).unwrap();
+        assert_eq!(loaded.save_state(), state);
+        assert_eq!(loaded.pc(), chip8.pc());
+        assert_eq!(loaded.index(), chip8.index());
+        assert_eq!(loaded.display().hash(), chip8.display().hash());
+
+        // FX0A finishes with the key released before saving
+        for machine in [&mut chip8, &mut loaded] {
+            machine.run_frame().unwrap();
+            assert_eq!(machine.register(2), 7);
+        }
+        assert_eq!(loaded.pc(), 0x202);
+        // and the random numbers go on the same
+        assert_eq!(random_numbers(&mut loaded), random_numbers(&mut chip8));
+    }
+
+    #[test]
+    fn restores_the_random_numbers() {
+        let mut chip8 = chip8(Layout::Chip8);
+        chip8.step().unwrap();
+        let state = chip8.save_state();
+        let first = random_numbers(&mut chip8);
+        // a differently seeded machine takes over the saved generator
+        let mut loaded = self::chip8(Layout::Chip8);
+        loaded.seed_random(99);
+        loaded.load_state(&state).unwrap();
+        assert_eq!(random_numbers(&mut loaded), first);
+    }
+
+    #[test]
+    fn rejects_truncated_states() {
+        let state = waiting().save_state();
+        let mut chip8 = chip8(Layout::Chip8);
+        for length in 0..state.len() {
+            let err = chip8.load_state(&state[..length]).unwrap_err();
+            let expected = if length < 4 {
+                StateError::NotASaveState
+            } else {
+                StateError::Truncated
+            };
+            assert_eq!(err, expected, "{} bytes", length);
+        }
+        // nothing was loaded halfway
+        assert_eq!(chip8.pc(), 0x200);
+    }
+
+    #[test]
+    fn rejects_other_formats() {
+        let mut chip8 = chip8(Layout::Chip8);
+        let mut state = chip8.save_state();
+        state[4] = 2;
+        assert_eq!(
+            chip8.load_state(&state),
+            Err(StateError::UnsupportedVersion(2))
+        );
+        state[0] = b'X';
+        assert_eq!(chip8.load_state(&state), Err(StateError::NotASaveState));
+    }
+
+    #[test]
+    fn rejects_corrupt_states() {
+        let mut chip8 = chip8(Layout::Chip8);
+        let state = chip8.save_state();
+        // the released key follows the magic, version, cycle, PC, I, V0-VF, the timers,
+        // the generator and whether FX0A waits
+        let released = 4 + 1 + 8 + 4 + 2 + 16 + 2 + 32 + 8 + 16 + 1;
+        assert_eq!(state[released], NO_KEY);
+        let mut corrupt = state.clone();
+        corrupt[released] = 0x10;
+        assert_eq!(chip8.load_state(&corrupt), Err(StateError::Corrupt));
+        let mut corrupt = state;
+        corrupt[released - 1] = 2;
+        assert_eq!(chip8.load_state(&corrupt), Err(StateError::Corrupt));
+    }
+
+    #[test]
+    fn rejects_other_machines() {
+        let state = waiting().save_state();
+        let mut large = chip8(Layout::Extended);
+        assert_eq!(
+            large.load_state(&state),
+            Err(StateError::Mismatch("memory size"))
+        );
+        let mut deep = chip8(Layout::Chip8);
+        deep.set_stack(Stack::new(32));
+        assert_eq!(
+            deep.load_state(&state),
+            Err(StateError::Mismatch("stack depth"))
+        );
+    }
+
+    #[test]
+    fn round_trips_deep_stacks() {
+        // 0x200 2200  CALL 200, which calls itself 300 times
+        let deep = || {
+            let mut chip8 =
+                Chip8::from_rom(&Rom::from_bytes("test.ch8", &[0x22, 0x00]), Layout::Chip8)
+                    .unwrap();
+            chip8.set_stack(Stack::new(400));
+            chip8
+        };
+        let mut chip8 = deep();
+        for _ in 0..300 {
+            chip8.step().unwrap();
+        }
+        let state = chip8.save_state();
+        let mut loaded = deep();
+        loaded.load_state(&state).unwrap();
+        assert_eq!(loaded.save_state(), state);
+        for _ in 0..100 {
+            loaded.step().unwrap();
+        }
+        assert!(loaded.step().is_err());
+    }
+}
@@ -0,0 +1,178 @@
+use super::instruction::{Instruction, OpcodeClass};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+/// Which instructions end up in the trace.
+#[derive(Debug, Clone, Default)]
+pub struct TraceFilter {
+    // inclusive range of instruction addresses
+    pub pc_range: Option<(u16, u16)>,
+    pub classes: Option<Vec<OpcodeClass>>,
+}
+
+impl TraceFilter {
+    /// Parses an inclusive hex range, e.g. `200-2ff`.
+    pub fn parse_pc_range(range: &str) -> Option<(u16, u16)> {
+        let (start, end) = range.split_once('-')?;
+        let parse = |address: &str| {
+            u16::from_str_radix(address.trim().trim_start_matches("0x"), 16).ok()
+        };
+        Some((parse(start)?, parse(end)?))
+    }
+
+    /// Parses a comma separated list of opcode class names, e.g. `flow,display`.
+    pub fn parse_classes(list: &str) -> Option<Vec<OpcodeClass>> {
+        list.split(',')
+            .map(|name| OpcodeClass::from_name(name.trim()))
+            .collect()
+    }
+
+    pub fn matches(&self, pc: u16, instruction: &Instruction) -> bool {
+        if let Some((start, end)) = self.pc_range {
+            if pc < start || pc > end {
+                return false;
+            }
+        }
+        match &self.classes {
+            Some(classes) => classes.contains(&instruction.class()),
+            None => true,
+        }
+    }
+}
+
+/// The machine state before an instruction executes.
+pub struct TraceLine {
+    // instructions executed before this one
+    pub cycle: u64,
+    pub pc: u16,
+    pub opcode: u16,
+    pub registers: [u8; 16],
+    pub i: u16,
+    pub sp: usize,
+    pub delay_timer: u8,
+    pub sound_timer: u8,
+    // `Display::hash` of the screen
+    pub framebuffer: u64,
+}
+
+// One line per instruction, with fixed width columns so traces can be diffed:
+// 0000000042 0x0212 6A02 LD VA, 0x02       V=00 01 .. 0F I=0x0000 SP=00 DT=00 ST=00 FB=...
+impl TraceLine {
+    fn write_to(&self, writer: &mut impl Write, instruction: &Instruction) -> io::Result<()> {
+        write!(
+            writer,
+            "{:010} 0x{:04X} {:04X} {:<18} V=",
+            self.cycle,
+            self.pc,
+            self.opcode,
+            instruction.to_string()
+        )?;
+        for (index, register) in self.registers.iter().enumerate() {
+            let separator = if index == 0 { "" } else { " " };
+            write!(writer, "{}{:02X}", separator, register)?;
+        }
+        writeln!(
+            writer,
+            " I=0x{:04X} SP={:02} DT={:02X} ST={:02X} FB={:016X}",
+            self.i, self.sp, self.delay_timer, self.sound_timer, self.framebuffer
+        )
+    }
+}
+
+/// Writes an execution trace to a file, or to stdout for `-`.
+pub struct Tracer {
+    writer: BufWriter<Box<dyn Write + Send>>,
+    pub filter: TraceFilter,
+}
+
+impl Tracer {
+    pub fn new(path: &str, filter: TraceFilter) -> io::Result<Self> {
+        let output: Box<dyn Write + Send> = match path {
+            "-" => Box::new(io::stdout()),
+            _ => Box::new(File::create(path)?),
+        };
+        Ok(Self {
+            writer: BufWriter::new(output),
+            filter,
+        })
+    }
+
+    pub fn record(&mut self, line: &TraceLine, instruction: &Instruction) -> io::Result<()> {
+        line.write_to(&mut self.writer, instruction)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_pc_ranges() {
+        assert_eq!(TraceFilter::parse_pc_range("200-2ff"), Some((0x200, 0x2FF)));
+        assert_eq!(
+            TraceFilter::parse_pc_range("0x300 - 0x3A0"),
+            Some((0x300, 0x3A0))
+        );
+        assert_eq!(TraceFilter::parse_pc_range("200"), None);
+        assert_eq!(TraceFilter::parse_pc_range("200-xyz"), None);
+    }
+
+    #[test]
+    fn parses_classes() {
+        assert_eq!(
+            TraceFilter::parse_classes("flow, display"),
+            Some(vec![OpcodeClass::Flow, OpcodeClass::Display])
+        );
+        assert_eq!(TraceFilter::parse_classes("flow,sound"), None);
+    }
+
+    #[test]
+    fn filters_by_pc_and_class() {
+        let jump = Instruction::decode(0x1200);
+        let draw = Instruction::decode(0xD125);
+        assert!(TraceFilter::default().matches(0x000, &jump));
+
+        let filter = TraceFilter {
+            pc_range: Some((0x200, 0x2FF)),
+            classes: Some(vec![OpcodeClass::Display]),
+        };
+        assert!(filter.matches(0x2FF, &draw));
+        assert!(!filter.matches(0x300, &draw));
+        assert!(!filter.matches(0x1FE, &draw));
+        assert!(!filter.matches(0x200, &jump));
+    }
+
+    #[test]
+    fn writes_fixed_width_lines() {
+        let mut registers = [0; 16];
+        registers[1] = 0x01;
+        registers[15] = 0xFF;
+        let line = TraceLine {
+            cycle: 42,
+            pc: 0x212,
+            opcode: 0x6A02,
+            registers,
+            i: 0x2F0,
+            sp: 3,
+            delay_timer: 0x10,
+            sound_timer: 0,
+            framebuffer: 0xDEADBEEF,
+        };
+        let mut output = Vec::new();
+        line.write_to(&mut output, &Instruction::decode(line.opcode))
+            .unwrap();
+        let text = String::from_utf8(output).unwrap();
+        let expected_start = "0000000042 0x0212 6A02 ";
+        assert!(text.starts_with(expected_start));
+        assert!(text.ends_with(
+            " V=00 01 00 00 00 00 00 00 00 00 00 00 00 00 00 FF I=0x02F0 SP=03 DT=10 ST=00 FB=00000000DEADBEEF\n"
+        ));
+        // the disassembly is padded to 18 characters
+        let disassembly = &text[expected_start.len()..text.find(" V=").unwrap()];
+        assert_eq!(disassembly.len(), 18);
+    }
+}
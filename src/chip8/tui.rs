@@ -0,0 +1,246 @@
+use super::chip8_mods::display::{Damage, Display};
+use super::chip8_mods::keypad::Keypad;
+use super::palette::Palette;
+use super::Chip8;
+use crossterm::event::{
+    self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers, KeyboardEnhancementFlags,
+    PopKeyboardEnhancementFlags, PushKeyboardEnhancementFlags,
+};
+use crossterm::style::{Color, Print, SetBackgroundColor, SetForegroundColor};
+use crossterm::{cursor, execute, queue, style, terminal};
+use std::io::{self, Stdout, Write};
+use std::time::{Duration, Instant};
+
+const FRAME_DURATION: Duration = Duration::from_micros(16_667);
+
+// Most terminals only report presses (and auto-repeats), so a key counts as
+// released once no repeat arrived for this long.
+const RELEASE_DELAY: Duration = Duration::from_millis(150);
+
+#[derive(Clone, Copy)]
+pub enum Glyphs {
+    // one cell shows 1x2 pixels
+    HalfBlock,
+    // one cell shows 2x4 pixels
+    Braille,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+struct Cell {
+    symbol: char,
+    fg: Color,
+    bg: Color,
+}
+
+struct Screen {
+    glyphs: Glyphs,
+    palette: Palette,
+    cells: Vec<Cell>,
+}
+
+impl Screen {
+    fn new(glyphs: Glyphs, palette: Palette) -> Self {
+        Self {
+            glyphs,
+            palette,
+            cells: Vec::new(),
+        }
+    }
+
+    // forget what is on the terminal, so the next draw repaints every cell
+    fn invalidate(&mut self) {
+        self.cells.clear();
+    }
+
+    // `damage` is what changed on the display since the last draw.
+    fn draw(&mut self, stdout: &mut Stdout, display: &Display, damage: Damage) -> io::Result<()> {
+        // pixels per cell
+        let (cell_width, cell_height) = match self.glyphs {
+            Glyphs::HalfBlock => (1, 2),
+            Glyphs::Braille => (2, 4),
+        };
+        let columns = display.width() / cell_width;
+        let rows = display.height() / cell_height;
+        let repaint_all = self.cells.len() != columns * rows;
+        if repaint_all {
+            queue!(stdout, terminal::Clear(terminal::ClearType::All))?;
+            let blank = Cell {
+                symbol: ' ',
+                fg: Color::Reset,
+                bg: Color::Reset,
+            };
+            self.cells = vec![blank; columns * rows];
+        } else if damage.is_empty() {
+            return Ok(());
+        }
+
+        for row in 0..rows {
+            // only cells showing a changed row of pixels can change
+            let mut pixel_rows = row * cell_height..(row + 1) * cell_height;
+            if !repaint_all && !pixel_rows.any(|y| damage.contains_row(y)) {
+                continue;
+            }
+            for column in 0..columns {
+                let index = row * columns + column;
+                let cell = match self.glyphs {
+                    Glyphs::HalfBlock => half_block_cell(display, &self.palette, column, row),
+                    Glyphs::Braille => braille_cell(display, &self.palette, column, row),
+                };
+                if !repaint_all && self.cells[index] == cell {
+                    continue;
+                }
+                queue!(
+                    stdout,
+                    cursor::MoveTo(column as u16, row as u16),
+                    SetForegroundColor(cell.fg),
+                    SetBackgroundColor(cell.bg),
+                    Print(cell.symbol)
+                )?;
+                self.cells[index] = cell;
+            }
+        }
+        queue!(stdout, style::ResetColor)?;
+        stdout.flush()?;
+        Ok(())
+    }
+}
+
+fn to_color(rgb: [u8; 3]) -> Color {
+    Color::Rgb {
+        r: rgb[0],
+        g: rgb[1],
+        b: rgb[2],
+    }
+}
+
+fn half_block_cell(display: &Display, palette: &Palette, column: usize, row: usize) -> Cell {
+    Cell {
+        symbol: '▀',
+        fg: to_color(palette.color(display.pixel(column, row * 2) as usize)),
+        bg: to_color(palette.color(display.pixel(column, row * 2 + 1) as usize)),
+    }
+}
+
+fn braille_cell(display: &Display, palette: &Palette, column: usize, row: usize) -> Cell {
+    // dot bit for every pixel of a 2x4 braille cell, indexed [y][x]
+    const DOTS: [[u32; 2]; 4] = [[0x01, 0x08], [0x02, 0x10], [0x04, 0x20], [0x40, 0x80]];
+
+    let mut bits = 0;
+    for (dy, dot_row) in DOTS.iter().enumerate() {
+        for (dx, dot) in dot_row.iter().enumerate() {
+            if display.is_pixel_on(column * 2 + dx, row * 4 + dy) {
+                bits |= dot;
+            }
+        }
+    }
+    Cell {
+        symbol: char::from_u32(0x2800 + bits).unwrap(),
+        fg: to_color(palette.foreground()),
+        bg: to_color(palette.background()),
+    }
+}
+
+pub fn run(mut chip8: Chip8, glyphs: Glyphs) {
+    let mut stdout = io::stdout();
+    if let Err(err) = run_terminal(&mut chip8, glyphs, &mut stdout) {
+        println!("Terminal frontend failed: {}", err);
+    }
+    chip8.shut_down();
+}
+
+fn run_terminal(chip8: &mut Chip8, glyphs: Glyphs, stdout: &mut Stdout) -> io::Result<()> {
+    terminal::enable_raw_mode()?;
+    let reports_release = terminal::supports_keyboard_enhancement().unwrap_or(false);
+    execute!(stdout, terminal::EnterAlternateScreen, cursor::Hide)?;
+    if reports_release {
+        execute!(
+            stdout,
+            PushKeyboardEnhancementFlags(KeyboardEnhancementFlags::REPORT_EVENT_TYPES)
+        )?;
+    }
+
+    let result = main_loop(chip8, glyphs, stdout, reports_release);
+
+    if reports_release {
+        execute!(stdout, PopKeyboardEnhancementFlags)?;
+    }
+    execute!(
+        stdout,
+        style::ResetColor,
+        cursor::Show,
+        terminal::LeaveAlternateScreen
+    )?;
+    terminal::disable_raw_mode()?;
+    result
+}
+
+fn main_loop(
+    chip8: &mut Chip8,
+    glyphs: Glyphs,
+    stdout: &mut Stdout,
+    reports_release: bool,
+) -> io::Result<()> {
+    let mut screen = Screen::new(glyphs, chip8.palette);
+    let mut release_at: [Option<Instant>; 16] = [None; 16];
+    let mut next_frame = Instant::now();
+
+    loop {
+        while event::poll(next_frame.saturating_duration_since(Instant::now()))? {
+            match event::read()? {
+                Event::Key(key_event) => {
+                    if is_quit(&key_event) {
+                        return Ok(());
+                    }
+                    let key = match key_event.code {
+                        KeyCode::Char(' ') => chip8.keymap.a,
+                        KeyCode::Char(c) => Keypad::key_for_char(c),
+                        KeyCode::Up => chip8.keymap.up,
+                        KeyCode::Down => chip8.keymap.down,
+                        KeyCode::Left => chip8.keymap.left,
+                        KeyCode::Right => chip8.keymap.right,
+                        KeyCode::Enter => chip8.keymap.b,
+                        _ => None,
+                    };
+                    if let Some(key) = key {
+                        if key_event.kind == KeyEventKind::Release {
+                            chip8.keypad.release(key);
+                        } else {
+                            chip8.keypad.press(key);
+                            if !reports_release {
+                                release_at[key as usize] = Some(Instant::now() + RELEASE_DELAY);
+                            }
+                        }
+                    }
+                }
+                Event::Resize(_, _) => screen.invalidate(),
+                _ => (),
+            }
+        }
+
+        let now = Instant::now();
+        for (key, deadline) in release_at.iter_mut().enumerate() {
+            if matches!(deadline, Some(at) if *at <= now) {
+                chip8.keypad.release(key as u8);
+                *deadline = None;
+            }
+        }
+
+        if let Err(fault) = chip8.run_frame() {
+            return Err(io::Error::other(format!("the emulator stopped: {}", fault)));
+        }
+        let damage = chip8.take_damage();
+        screen.draw(stdout, &chip8.display, damage)?;
+
+        next_frame += FRAME_DURATION;
+        if next_frame < now {
+            // we fell behind, don't try to catch up
+            next_frame = now;
+        }
+    }
+}
+
+fn is_quit(key_event: &KeyEvent) -> bool {
+    key_event.code == KeyCode::Esc
+        || (key_event.code == KeyCode::Char('c')
+            && key_event.modifiers.contains(KeyModifiers::CONTROL))
+}
@@ -0,0 +1,156 @@
+//! The ggez window, built with the `window` feature.
+
+use super::chip8_mods::keypad;
+use super::{palette, profile, renderer, Chip8};
+use ggez::event::{self, KeyCode, KeyMods};
+use ggez::graphics;
+use ggez::timer;
+use ggez::{Context, GameResult};
+
+const DEFAULT_RECORDING_PATH: &str = "./recording.gif";
+
+impl Chip8 {
+    pub fn set_renderer(&mut self, renderer: renderer::Renderer) {
+        self.renderer = renderer;
+    }
+
+    pub fn run(self) {
+        let cb = ggez::ContextBuilder::new("CHIP8_rust", "FleeXo").window_mode(
+            ggez::conf::WindowMode::default()
+                .dimensions(800.0, 600.0)
+                .resizable(true),
+        );
+        let (ctx, event_loop) = cb.build().unwrap();
+        graphics::set_window_title(&ctx, "CHIP8 Emulator in Rust");
+        event::run(ctx, event_loop, self);
+    }
+
+    fn cycle_palette(&mut self) {
+        let current = palette::PRESET_NAMES
+            .iter()
+            .position(|name| palette::Palette::preset(name) == Some(self.palette));
+        let next = match current {
+            Some(i) => (i + 1) % palette::PRESET_NAMES.len(),
+            None => 0,
+        };
+        self.palette = palette::Palette::preset(palette::PRESET_NAMES[next]).unwrap();
+    }
+
+    fn change_speed(&mut self, delta: i32) {
+        let instructions_per_frame = (self.instructions_per_frame as i32 + delta).max(1) as u32;
+        self.set_instructions_per_frame(instructions_per_frame);
+        println!("{} instructions per frame", self.instructions_per_frame);
+    }
+
+    // Stores the current value of a setting the user just changed in the ROM's profile.
+    fn remember(&mut self, update: impl FnOnce(&Self, &mut profile::Profile)) {
+        let mut profile = match self.profile.take() {
+            Some(profile) => profile,
+            None => return,
+        };
+        update(self, &mut profile.settings);
+        if let Err(err) = profile.save() {
+            println!("Could not save profile {}: {}", profile.path().display(), err);
+        }
+        self.profile = Some(profile);
+    }
+}
+
+impl event::EventHandler<ggez::GameError> for Chip8 {
+    fn update(&mut self, _ctx: &mut Context) -> GameResult {
+        const DESIRED_FPS: u32 = 60;
+        while timer::check_update_time(_ctx, DESIRED_FPS) {
+            if let Err(fault) = self.run_frame() {
+                println!("The emulator stopped: {}", fault);
+                event::quit(_ctx);
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn key_down_event(&mut self, ctx: &mut Context, keycode: KeyCode, _keymods: KeyMods, repeat: bool) {
+        if let Some(key) = keycode_to_key(&self.keymap, keycode) {
+            self.keypad.press(key);
+        }
+        match keycode {
+            KeyCode::Escape => event::quit(ctx),
+            // F12 starts and stops recording
+            KeyCode::F12 if !repeat => {
+                if self.recorder.is_some() {
+                    self.stop_recording();
+                } else {
+                    self.start_recording(DEFAULT_RECORDING_PATH);
+                }
+            }
+            // F3 and F4 change the speed, F5 toggles the pixel grid, F6 cycles the palette presets,
+            // F7 switches the scaling. Speed and palette are saved in the ROM's profile.
+            KeyCode::F3 | KeyCode::F4 => {
+                self.change_speed(if keycode == KeyCode::F3 { -1 } else { 1 });
+                self.remember(|this, settings| {
+                    settings.instructions_per_frame = Some(this.instructions_per_frame)
+                });
+            }
+            KeyCode::F5 if !repeat => self.renderer.toggle_grid(),
+            KeyCode::F6 if !repeat => {
+                self.cycle_palette();
+                self.remember(|this, settings| settings.palette = Some(this.palette));
+            }
+            KeyCode::F7 if !repeat => self.renderer.toggle_scaling(),
+            _ => (),
+        }
+    }
+
+    fn key_up_event(&mut self, _ctx: &mut Context, keycode: KeyCode, _keymods: KeyMods) {
+        if let Some(key) = keycode_to_key(&self.keymap, keycode) {
+            self.keypad.release(key);
+        }
+    }
+
+    fn quit_event(&mut self, _ctx: &mut Context) -> bool {
+        self.shut_down();
+        false
+    }
+
+    fn resize_event(&mut self, ctx: &mut Context, width: f32, height: f32) {
+        // keep one screen unit per window pixel, the renderer does the scaling
+        let _ = graphics::set_screen_coordinates(ctx, graphics::Rect::new(0.0, 0.0, width, height));
+    }
+
+    fn draw(&mut self, ctx: &mut Context) -> GameResult {
+        let damage = self.take_damage();
+        self.renderer.draw(ctx, self.phosphor.frame(), damage, &self.palette)?;
+        graphics::present(ctx)?;
+        Ok(())
+    }
+}
+
+fn keycode_to_key(keymap: &keypad::Keymap, keycode: KeyCode) -> Option<u8> {
+    let c = match keycode {
+        // arrow keys, space and enter follow the ROM's keymap
+        KeyCode::Up => return keymap.up,
+        KeyCode::Down => return keymap.down,
+        KeyCode::Left => return keymap.left,
+        KeyCode::Right => return keymap.right,
+        KeyCode::Space => return keymap.a,
+        KeyCode::Return => return keymap.b,
+        KeyCode::Key1 => '1',
+        KeyCode::Key2 => '2',
+        KeyCode::Key3 => '3',
+        KeyCode::Key4 => '4',
+        KeyCode::Q => 'q',
+        KeyCode::W => 'w',
+        KeyCode::E => 'e',
+        KeyCode::R => 'r',
+        KeyCode::A => 'a',
+        KeyCode::S => 's',
+        KeyCode::D => 'd',
+        KeyCode::F => 'f',
+        KeyCode::Z => 'z',
+        KeyCode::X => 'x',
+        KeyCode::C => 'c',
+        KeyCode::V => 'v',
+        _ => return None,
+    };
+    keypad::Keypad::key_for_char(c)
+}
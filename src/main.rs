@@ -1,5 +1,305 @@
-mod chip8;
+use rs_chip8::chip8;
+use std::env;
 
 fn main() {
-    chip8::Chip8::run("./programs/bc_test.ch8");
+    let mut program = String::from("./programs/bc_test.ch8");
+    let mut record_path: Option<String> = None;
+    let mut trace_path: Option<String> = None;
+    let mut trace_filter = chip8::trace::TraceFilter::default();
+    let mut reference_trace: Option<String> = None;
+    let mut profile_instructions = false;
+    let mut folded_path: Option<String> = None;
+    let mut coverage_prefix: Option<String> = None;
+    let mut dot_path: Option<String> = None;
+    let mut engine = chip8::block_cache::Engine::Interpreter;
+    let mut headless_frames: Option<u32> = None;
+    let mut reward_spec: Option<chip8::reward::RewardSpec> = None;
+    let mut tui_glyphs: Option<chip8::tui::Glyphs> = None;
+    let mut scaling = chip8::renderer::Scaling::Fit;
+    let mut grid = false;
+    let mut persistence = chip8::phosphor::Persistence::Off;
+    let mut stack: Option<chip8::chip8_mods::stack::Stack> = None;
+    let mut layout = chip8::chip8_mods::memory::Layout::Chip8;
+    let mut protect_memory = false;
+    // speed, quirks, palette and keymap given on the command line
+    let mut settings = chip8::profile::Profile::default();
+    let mut cartridge: Option<String> = None;
+    let mut database = Some(chip8::database::Database::bundled());
+    let mut profile_dir = chip8::profile::default_directory();
+    let mut save_profile = false;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            // --record <file.gif | directory>
+            "--record" => record_path = args.next(),
+            // --trace <file | ->, writes every executed instruction
+            "--trace" => trace_path = args.next(),
+            // --trace-pc <start-end>, only trace instructions at these hex addresses
+            "--trace-pc" => {
+                trace_filter.pc_range = Some(
+                    args.next()
+                        .and_then(|range| chip8::trace::TraceFilter::parse_pc_range(&range))
+                        .expect("--trace-pc expects a hex address range, e.g. 200-2ff."),
+                );
+            }
+            // --trace-ops <class,...>, only trace these opcode classes
+            "--trace-ops" => {
+                trace_filter.classes = Some(
+                    args.next()
+                        .and_then(|list| chip8::trace::TraceFilter::parse_classes(&list))
+                        .unwrap_or_else(|| {
+                            panic!(
+                                "--trace-ops expects a list of {}.",
+                                chip8::instruction::CLASS_NAMES.join(", ")
+                            )
+                        }),
+                );
+            }
+            // --diff-trace <reference trace>, runs in lockstep and reports the first difference
+            "--diff-trace" => reference_trace = args.next(),
+            // print a hot-spot report when the emulator stops
+            "--profile" => profile_instructions = true,
+            // --profile-folded <file>, also writes folded stacks for flame graphs
+            "--profile-folded" => {
+                profile_instructions = true;
+                folded_path = Some(args.next().expect("--profile-folded expects a file."));
+            }
+            // --coverage <prefix>, writes <prefix>.lst and <prefix>.info when the emulator stops
+            "--coverage" => coverage_prefix = args.next(),
+            // --analyze <graph.dot>, writes the control-flow graph instead of running the ROM
+            "--analyze" => dot_path = args.next(),
+            // --engine <interpreter | cached>
+            "--engine" => {
+                engine = args
+                    .next()
+                    .and_then(|name| chip8::block_cache::Engine::from_name(&name))
+                    .expect("--engine expects interpreter or cached.");
+            }
+            // --headless <frames>
+            "--headless" => {
+                headless_frames = args.next().and_then(|frames| frames.parse().ok());
+                if headless_frames.is_none() {
+                    panic!("--headless expects a frame count.");
+                }
+            }
+            // --reward <spec.toml>, with --headless prints the score
+            "--reward" => {
+                let spec = args.next().expect("--reward expects a spec file.");
+                reward_spec = Some(
+                    std::fs::read_to_string(&spec)
+                        .map_err(|err| err.to_string())
+                        .and_then(|text| {
+                            chip8::reward::RewardSpec::from_toml(&text)
+                                .map_err(|err| err.to_string())
+                        })
+                        .unwrap_or_else(|err| panic!("Could not read {}: {}", spec, err)),
+                );
+            }
+            // render into the terminal instead of a window
+            "--tui" => tui_glyphs = Some(chip8::tui::Glyphs::HalfBlock),
+            "--tui-braille" => tui_glyphs = Some(chip8::tui::Glyphs::Braille),
+            // --palette <default | amber | green | lcd>
+            "--palette" => {
+                settings.palette = Some(
+                    args.next()
+                        .and_then(|name| chip8::palette::Palette::preset(&name))
+                        .expect("--palette expects one of default, amber, green, lcd."),
+                );
+            }
+            // --colors <background,foreground[,plane 2,both planes]>
+            "--colors" => {
+                settings.palette = Some(
+                    args.next()
+                        .and_then(|list| chip8::palette::Palette::from_hex_list(&list))
+                        .expect("--colors expects up to four hex colours, e.g. #000000,#ffb000."),
+                );
+            }
+            // --scale <integer | fit>
+            "--scale" => {
+                scaling = args
+                    .next()
+                    .and_then(|name| chip8::renderer::Scaling::from_name(&name))
+                    .expect("--scale expects integer or fit.");
+            }
+            "--grid" => grid = true,
+            // --persistence <off | blend:<frames> | decay:<frames>>
+            "--persistence" => {
+                persistence = args
+                    .next()
+                    .and_then(|name| chip8::phosphor::Persistence::from_name(&name))
+                    .expect("--persistence expects off, blend:<frames> or decay:<frames>.");
+            }
+            // --stack-depth <entries>
+            "--stack-depth" => {
+                let depth: u16 = args
+                    .next()
+                    .and_then(|depth| depth.parse().ok())
+                    .expect("--stack-depth expects a number of entries up to 65535.");
+                stack = Some(chip8::chip8_mods::stack::Stack::new(depth as usize));
+            }
+            // 12 entries, stored in RAM at 0xEA0 like the COSMAC VIP interpreter
+            "--vip-stack" => stack = Some(chip8::chip8_mods::stack::Stack::vip()),
+            // --memory <chip8 | 64k | eti660>
+            "--memory" => {
+                layout = args
+                    .next()
+                    .and_then(|name| chip8::chip8_mods::memory::Layout::from_name(&name))
+                    .expect("--memory expects chip8, 64k or eti660.");
+            }
+            // fault on writes into the interpreter area and the VIP reserved area
+            "--protect-memory" => protect_memory = true,
+            // --quirks <default | vip | modern | chip48 | schip | xochip>
+            "--quirks" => {
+                settings.quirks = chip8::quirks::QuirkOverrides::all(
+                    args.next()
+                        .and_then(|name| chip8::quirks::Quirks::preset(&name))
+                        .expect("--quirks expects one of default, vip, modern, chip48, schip, xochip."),
+                );
+            }
+            // --speed <instructions per frame>
+            "--speed" => {
+                settings.instructions_per_frame = Some(
+                    args.next()
+                        .and_then(|speed| speed.parse().ok())
+                        .expect("--speed expects a number of instructions per frame."),
+                );
+            }
+            // --keys <up=5,down=8,left=7,right=9,a=6,b=4>, keypad keys for arrows, space and enter
+            "--keys" => {
+                settings.keymap = Some(
+                    args.next()
+                        .and_then(|list| chip8::chip8_mods::keypad::Keymap::from_bindings(&list))
+                        .expect("--keys expects bindings like up=5,down=8,a=6."),
+                );
+            }
+            // --profile-dir <directory>, where per-ROM profiles are stored
+            "--profile-dir" => {
+                profile_dir = Some(
+                    args.next()
+                        .expect("--profile-dir expects a directory.")
+                        .into(),
+                );
+            }
+            // don't read or write per-ROM profiles
+            "--no-profile" => profile_dir = None,
+            // store the speed, quirks, palette and keymap given here in the ROM's profile
+            "--save-profile" => save_profile = true,
+            // --cartridge <octo cartridge.gif>, applies its quirks, speed and colours
+            "--cartridge" => cartridge = args.next(),
+            // --database <programs.json>, e.g. from the community chip-8-database
+            "--database" => {
+                let path = args.next().expect("--database expects a programs.json file.");
+                let json = std::fs::read_to_string(&path)
+                    .unwrap_or_else(|err| panic!("Could not read {}: {}", path, err));
+                let extra = chip8::database::Database::from_json(&json)
+                    .unwrap_or_else(|err| panic!("Could not parse {}: {}", path, err));
+                database
+                    .get_or_insert_with(chip8::database::Database::new)
+                    .merge(extra);
+            }
+            // don't configure the emulator from the ROM database
+            "--no-database" => database = None,
+            _ => program = arg,
+        }
+    }
+
+    let rom = chip8::rom::Rom::load(&program)
+        .unwrap_or_else(|err| panic!("Could not load {}: {}", program, err));
+    if let Some(dot_path) = &dot_path {
+        let graph = chip8::control_flow::ControlFlowGraph::build(
+            &rom.bytes,
+            layout.program_start() as u16,
+        );
+        graph.print_summary();
+        std::fs::write(dot_path, graph.to_dot())
+            .unwrap_or_else(|err| panic!("Could not write {}: {}", dot_path, err));
+        return;
+    }
+
+    println!("In program {}", rom.name);
+    let mut chip8 = chip8::Chip8::from_rom(&rom, layout)
+        .unwrap_or_else(|err| panic!("Could not load {}: {}", program, err));
+    if protect_memory {
+        chip8.protect_memory(chip8::chip8_mods::memory::RegionKind::Interpreter);
+        chip8.protect_memory(chip8::chip8_mods::memory::RegionKind::VipReserved);
+    }
+
+    // settings resolve in layers: command line over cartridge over profile over database over defaults
+    if let Some(info) = database.as_ref().and_then(|database| database.lookup(&rom.bytes)) {
+        println!("Identified {} ({})", info.title, info.platform.as_deref().unwrap_or("unknown platform"));
+        chip8.apply_rom_info(info);
+    }
+    if let Some(profile_dir) = profile_dir {
+        let mut profile =
+            chip8::profile::RomProfile::open(profile_dir, &chip8::database::sha1_hex(&rom.bytes));
+        chip8.apply_profile(&profile.settings);
+        if save_profile {
+            profile.settings.merge(&settings);
+            match profile.save() {
+                Ok(()) => println!("Saved profile {}", profile.path().display()),
+                Err(err) => println!("Could not save profile {}: {}", profile.path().display(), err),
+            }
+        }
+        chip8.set_profile(profile);
+    } else if save_profile {
+        panic!("--save-profile needs a profile directory.");
+    }
+    if let Some(cartridge) = &cartridge {
+        let cartridge = chip8::rom::OctoCartridge::load(cartridge)
+            .unwrap_or_else(|err| panic!("Could not read {}: {}", cartridge, err));
+        chip8.apply_octo_options(&cartridge.options);
+    }
+    chip8.apply_profile(&settings);
+    chip8.set_engine(engine);
+    chip8.set_persistence(persistence);
+    if let Some(stack) = stack {
+        chip8.set_stack(stack);
+    }
+    chip8.set_renderer(chip8::renderer::Renderer::new(scaling, grid));
+    if let Some(coverage_prefix) = &coverage_prefix {
+        chip8.start_coverage(&rom, coverage_prefix);
+    }
+    if profile_instructions {
+        chip8.start_profiling(folded_path.as_deref());
+    }
+    if let Some(trace_path) = &trace_path {
+        chip8.start_tracing(trace_path, trace_filter);
+    }
+    if let Some(record_path) = &record_path {
+        chip8.start_recording(record_path);
+    }
+
+    if let Some(reference_trace) = &reference_trace {
+        let matched = chip8::difftest::run(chip8, reference_trace)
+            .unwrap_or_else(|err| panic!("Could not read {}: {}", reference_trace, err));
+        std::process::exit(if matched { 0 } else { 1 });
+    }
+
+    match (headless_frames, tui_glyphs) {
+        (Some(frames), _) if reward_spec.is_some() => {
+            chip8::reward::watch(chip8, reward_spec.as_ref().unwrap(), frames)
+        }
+        (Some(frames), _) => {
+            let report = chip8.run_headless(frames);
+            if let Some(fault) = report.fault {
+                println!("The emulator stopped: {}", fault);
+            }
+            let seconds = report.elapsed.as_secs_f64();
+            println!(
+                "Ran {} instructions in {:.3} s, {:.0} instructions per second",
+                report.instructions,
+                seconds,
+                report.instructions as f64 / seconds.max(f64::EPSILON)
+            );
+            if let Some(counts) = report.block_cache {
+                println!(
+                    "Block cache: {} hits, {} misses, {} invalidations",
+                    counts.hits, counts.misses, counts.invalidations
+                );
+            }
+        }
+        (None, Some(glyphs)) => chip8::tui::run(chip8, glyphs),
+        (None, None) => chip8.run(),
+    }
 }
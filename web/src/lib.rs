@@ -0,0 +1,158 @@
+//! wasm-bindgen bindings for the browser frontend in `www/`. The page runs the
+//! frames, draws `framebuffer()` onto a canvas and turns the buzzer on and off.
+
+use rs_chip8::chip8::chip8_mods::display::Damage;
+use rs_chip8::chip8::chip8_mods::keypad::Keypad;
+use rs_chip8::chip8::chip8_mods::memory::Layout;
+use rs_chip8::chip8::database::Database;
+use rs_chip8::chip8::palette::{self, Palette};
+use rs_chip8::chip8::quirks::{self, Quirks};
+use rs_chip8::chip8::rom::Rom;
+use rs_chip8::chip8::Chip8;
+use wasm_bindgen::prelude::*;
+
+#[wasm_bindgen]
+pub struct Emulator {
+    chip8: Chip8,
+    // keys held down, bit k for key k
+    keys: u16,
+    // the filtered frame in RGBA, as a canvas ImageData wants it
+    rgba: Vec<u8>,
+    is_stale: bool,
+}
+
+#[wasm_bindgen]
+impl Emulator {
+    /// Loads a program at 0x200, or at 0x200 of 64 KB of memory if it needs more than 4 KB.
+    /// Quirks, speed, colours and keys come from the ROM database when it knows the ROM.
+    #[wasm_bindgen(constructor)]
+    pub fn new(rom: &[u8], name: &str) -> Result<Emulator, JsError> {
+        let rom = Rom::from_bytes(name, rom);
+        let layout = match rom.check_fits(Layout::Chip8) {
+            Ok(()) => Layout::Chip8,
+            Err(_) => Layout::Extended,
+        };
+        let mut chip8 =
+            Chip8::from_rom(&rom, layout).map_err(|err| JsError::new(&err.to_string()))?;
+        if let Some(info) = Database::bundled().lookup(&rom.bytes) {
+            chip8.apply_rom_info(info);
+        }
+        Ok(Self {
+            chip8,
+            keys: 0,
+            rgba: Vec::new(),
+            is_stale: true,
+        })
+    }
+
+    /// Runs one frame: the instructions of a frame, then one tick of both timers.
+    /// Throws once the program faulted, e.g. with a stack overflow.
+    pub fn run_frame(&mut self) -> Result<(), JsError> {
+        self.chip8
+            .run_frame()
+            .map_err(|fault| JsError::new(&fault.to_string()))
+    }
+
+    pub fn key_down(&mut self, key: u8) {
+        self.keys |= 1 << (key & 0xF);
+        self.chip8.set_keys(self.keys);
+    }
+
+    pub fn key_up(&mut self, key: u8) {
+        self.keys &= !(1 << (key & 0xF));
+        self.chip8.set_keys(self.keys);
+    }
+
+    /// The keypad key for a `KeyboardEvent.code`, like in the window: 1234/QWER/ASDF/ZXCV,
+    /// and the arrow keys, space and enter as the ROM's keymap says.
+    pub fn key_for_code(&self, code: &str) -> Option<u8> {
+        let keymap = self.chip8.keymap();
+        match code {
+            "ArrowUp" => keymap.up,
+            "ArrowDown" => keymap.down,
+            "ArrowLeft" => keymap.left,
+            "ArrowRight" => keymap.right,
+            "Space" => keymap.a,
+            "Enter" => keymap.b,
+            _ => code
+                .strip_prefix("Key")
+                .or_else(|| code.strip_prefix("Digit"))
+                .and_then(|key| key.chars().next())
+                .and_then(Keypad::key_for_char),
+        }
+    }
+
+    pub fn sound_active(&self) -> bool {
+        self.chip8.sound_active()
+    }
+
+    pub fn width(&self) -> usize {
+        self.chip8.display().width()
+    }
+
+    pub fn height(&self) -> usize {
+        self.chip8.display().height()
+    }
+
+    /// Renders what changed since the last call and returns whether anything did.
+    pub fn render(&mut self) -> bool {
+        let damage = self.chip8.take_damage();
+        let frame = self.chip8.filtered_frame();
+        let damage = if self.is_stale || self.rgba.len() != frame.pixels() * 4 {
+            self.rgba = vec![0; frame.pixels() * 4];
+            self.is_stale = false;
+            Damage::all(frame.columns, frame.rows)
+        } else {
+            damage
+        };
+        self.chip8
+            .palette()
+            .render_rgba_rows(frame, damage.rows(), &mut self.rgba);
+        !damage.is_empty()
+    }
+
+    /// The frame drawn by `render`, width x height RGBA pixels.
+    pub fn framebuffer(&self) -> Vec<u8> {
+        self.rgba.clone()
+    }
+
+    /// One of `default`, `vip`, `modern`, `chip48`, `schip` and `xochip`.
+    pub fn set_quirks(&mut self, preset: &str) -> Result<(), JsError> {
+        let quirks = Quirks::preset(preset).ok_or_else(|| {
+            JsError::new(&format!(
+                "The quirks preset has to be one of {}.",
+                quirks::PRESET_NAMES.join(", ")
+            ))
+        })?;
+        self.chip8.set_quirks(quirks);
+        Ok(())
+    }
+
+    pub fn set_speed(&mut self, instructions_per_frame: u32) {
+        self.chip8
+            .set_instructions_per_frame(instructions_per_frame);
+    }
+
+    /// One of `default`, `amber`, `green` and `lcd`.
+    pub fn set_palette(&mut self, preset: &str) -> Result<(), JsError> {
+        let palette = Palette::preset(preset).ok_or_else(|| {
+            JsError::new(&format!(
+                "The palette has to be one of {}.",
+                palette::PRESET_NAMES.join(", ")
+            ))
+        })?;
+        self.chip8.set_palette(palette);
+        self.is_stale = true;
+        Ok(())
+    }
+
+    pub fn save_state(&self) -> Vec<u8> {
+        self.chip8.save_state()
+    }
+
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), JsError> {
+        self.chip8
+            .load_state(state)
+            .map_err(|err| JsError::new(&err.to_string()))
+    }
+}
@@ -0,0 +1,183 @@
+// Runs the emulator at 60 frames per second on a canvas. A page can start a demo
+// with `?rom=<url>`.
+import init, { Emulator } from "./pkg/rs_chip8_web.js";
+
+const FRAMES_PER_SECOND = 60;
+// frames to catch up at most, e.g. after the tab was in the background
+const MAX_PENDING_FRAMES = 4;
+const BEEP_HZ = 440;
+const BEEP_VOLUME = 0.1;
+
+const canvas = document.getElementById("screen");
+const context = canvas.getContext("2d");
+const status = document.getElementById("status");
+const quirks = document.getElementById("quirks");
+const palette = document.getElementById("palette");
+
+let emulator = null;
+// the loaded ROM, to start again when a setting goes back to the database
+let rom = null;
+let pendingFrames = 0;
+let lastTime = null;
+
+// The buzzer: a square wave whose gain follows the sound timer. Browsers only
+// allow audio after a click or key press, so it starts with the first one.
+const beeper = {
+  context: null,
+  gain: null,
+
+  start() {
+    if (this.context) {
+      return;
+    }
+    this.context = new AudioContext();
+    const oscillator = this.context.createOscillator();
+    oscillator.type = "square";
+    oscillator.frequency.value = BEEP_HZ;
+    this.gain = this.context.createGain();
+    this.gain.gain.value = 0;
+    oscillator.connect(this.gain).connect(this.context.destination);
+    oscillator.start();
+  },
+
+  set(isActive) {
+    if (this.gain) {
+      // a short ramp instead of a click
+      this.gain.gain.setTargetAtTime(isActive ? BEEP_VOLUME : 0, this.context.currentTime, 0.005);
+    }
+  },
+};
+
+function load(bytes, name) {
+  rom = { bytes, name };
+  try {
+    emulator?.free();
+    emulator = new Emulator(bytes, name);
+    applySettings();
+    canvas.width = emulator.width();
+    canvas.height = emulator.height();
+    pendingFrames = 0;
+    status.textContent = name;
+  } catch (error) {
+    emulator = null;
+    status.textContent = `Could not load ${name}: ${error.message ?? error}`;
+  }
+}
+
+function applySettings() {
+  if (!emulator) {
+    return;
+  }
+  if (quirks.value) {
+    emulator.set_quirks(quirks.value);
+  }
+  if (palette.value) {
+    emulator.set_palette(palette.value);
+  }
+}
+
+function draw() {
+  const width = emulator.width();
+  const height = emulator.height();
+  // hires and lores switch the size, and resizing clears the canvas
+  const isResized = canvas.width !== width || canvas.height !== height;
+  if (isResized) {
+    canvas.width = width;
+    canvas.height = height;
+  }
+  if (!emulator.render() && !isResized) {
+    return;
+  }
+  const pixels = new Uint8ClampedArray(emulator.framebuffer());
+  context.putImageData(new ImageData(pixels, width, height), 0, 0);
+}
+
+function tick(time) {
+  if (emulator) {
+    if (lastTime !== null) {
+      pendingFrames += ((time - lastTime) / 1000) * FRAMES_PER_SECOND;
+      pendingFrames = Math.min(pendingFrames, MAX_PENDING_FRAMES);
+    }
+    try {
+      while (pendingFrames >= 1) {
+        emulator.run_frame();
+        pendingFrames -= 1;
+      }
+      draw();
+      beeper.set(emulator.sound_active());
+    } catch (error) {
+      // the program faulted, or a panic left the module in an unknown state
+      status.textContent = `The emulator stopped: ${error}`;
+      emulator = null;
+      beeper.set(false);
+    }
+  }
+  lastTime = time;
+  requestAnimationFrame(tick);
+}
+
+async function loadFile(file) {
+  load(new Uint8Array(await file.arrayBuffer()), file.name);
+}
+
+function onKey(event, isDown) {
+  const key = emulator?.key_for_code(event.code);
+  if (key === undefined) {
+    return;
+  }
+  event.preventDefault();
+  if (isDown) {
+    beeper.start();
+    emulator.key_down(key);
+  } else {
+    emulator.key_up(key);
+  }
+}
+
+document.addEventListener("keydown", (event) => onKey(event, true));
+document.addEventListener("keyup", (event) => onKey(event, false));
+document.addEventListener("click", () => beeper.start());
+
+canvas.addEventListener("dragover", (event) => {
+  event.preventDefault();
+  canvas.classList.add("dropping");
+});
+canvas.addEventListener("dragleave", () => canvas.classList.remove("dropping"));
+canvas.addEventListener("drop", (event) => {
+  event.preventDefault();
+  canvas.classList.remove("dropping");
+  beeper.start();
+  const file = event.dataTransfer.files[0];
+  if (file) {
+    loadFile(file);
+  }
+});
+document.getElementById("file").addEventListener("change", (event) => {
+  const file = event.target.files[0];
+  if (file) {
+    loadFile(file);
+  }
+});
+
+function onSettingChanged(event) {
+  if (event.target.value) {
+    applySettings();
+  } else if (rom) {
+    load(rom.bytes, rom.name);
+  }
+}
+
+quirks.addEventListener("change", onSettingChanged);
+palette.addEventListener("change", onSettingChanged);
+
+await init();
+const demo = new URLSearchParams(location.search).get("rom");
+if (demo) {
+  const response = await fetch(demo);
+  if (response.ok) {
+    load(new Uint8Array(await response.arrayBuffer()), demo);
+  } else {
+    status.textContent = `Could not load ${demo}: ${response.status} ${response.statusText}`;
+  }
+}
+requestAnimationFrame(tick);